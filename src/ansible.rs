@@ -0,0 +1,327 @@
+use crate::condition::{Condition, ConditionKey, Operator};
+use crate::document::{CodeBlock, Document, Section};
+use crate::vars::CorgVars;
+use crate::CORG_VERSION;
+use chrono::prelude::*;
+use serde_yaml::{Mapping, Value};
+
+/// Renders a document as an Ansible playbook with one play for all hosts.
+///
+/// - every step becomes a named block of `ansible.builtin.shell` tasks, deeper
+///   sections nested blocks.
+/// - `when` conditions become `when` clauses on facts, shell tests run as a
+///   check task first.
+/// - `creates` and `retry` become the matching task keywords, `file` blocks
+///   `ansible.builtin.copy` tasks.
+/// - `vars` are the variables of the play, see `placeholders`.
+///
+/// Prose isn't part of the playbook, `parallel` blocks run one after another and
+/// `needs` is left to the order of the document.
+pub fn push_playbook(s: &mut String, file_name: &str, document: &Document, vars: &CorgVars) {
+    s.push_str(&format!("# -- GENERATED BY CORG v{} --\n", CORG_VERSION));
+    s.push_str(&format!("# filename: {}\n", file_name));
+    s.push_str(&format!("# date: {}\n", Utc::now().to_rfc3339()));
+
+    // Serializing the value rather than writing YAML by hand keeps the output
+    // valid whatever the code blocks contain.
+    match serde_yaml::to_string(&playbook(document, vars)) {
+        Ok(yaml) => s.push_str(&yaml),
+        Err(err) => panic!("failed to serialize the playbook: {}", err),
+    }
+    s.push('\n');
+}
+
+/// Returns the playbook of the document, `vars` become the variables of the play.
+pub fn playbook(document: &Document, vars: &CorgVars) -> Value {
+    let mut builder = PlaybookBuilder { check_count: 0 };
+
+    let mut tasks = builder.content_tasks(&document.blocks);
+    for step in document.steps() {
+        tasks.extend(builder.section_tasks(step));
+    }
+
+    // A play can't be skipped, so the title's condition guards all its tasks.
+    if let Some(condition) = document.title.as_ref().and_then(|title| title.when.as_ref()) {
+        let name = document.title.as_ref().map(|title| title.text.as_str()).unwrap_or_default();
+        tasks = builder.guarded_block(name, condition, tasks);
+    }
+
+    let mut play = Mapping::new();
+    let name = match &document.title {
+        Some(title) => title.text.clone(),
+        None => String::from("corg"),
+    };
+    insert(&mut play, "name", Value::from(name));
+    insert(&mut play, "hosts", Value::from("all"));
+
+    let play_vars = play_vars(vars);
+    if !play_vars.is_empty() {
+        insert(&mut play, "vars", Value::Mapping(play_vars));
+    }
+
+    insert(&mut play, "tasks", Value::Sequence(tasks));
+
+    Value::Sequence(vec![Value::Mapping(play)])
+}
+
+/// Returns the variables to parse a document with for a playbook, placeholders
+/// of variables Ansible can template are kept (e.g. `{{ db.host }}`) so they
+/// resolve to the play's variables, the others are filled in.
+pub fn placeholders(vars: &CorgVars) -> CorgVars {
+    let mut placeholders = CorgVars::new();
+
+    for (key, value) in vars.entries() {
+        if is_jinja_name(key) {
+            placeholders.insert(key, &format!("{{{{ {} }}}}", key));
+        } else {
+            placeholders.insert(key, value);
+        }
+    }
+
+    placeholders
+}
+
+struct PlaybookBuilder {
+    /// Check tasks registered so far, their results are named after the count.
+    check_count: usize,
+}
+
+impl PlaybookBuilder {
+    /// Returns a block running the section, nested blocks for its children.
+    fn section_tasks(&mut self, section: &Section) -> Vec<Value> {
+        let mut tasks = self.content_tasks(&section.blocks);
+        for child in section.children.iter() {
+            tasks.extend(self.section_tasks(child));
+        }
+
+        match &section.heading.when {
+            Some(condition) => self.guarded_block(&section.heading.text, condition, tasks),
+            None => vec![Value::Mapping(block(&section.heading.text, tasks))],
+        }
+    }
+
+    fn content_tasks(&mut self, blocks: &[CodeBlock]) -> Vec<Value> {
+        let mut tasks = vec![];
+
+        for code_block in blocks.iter() {
+            tasks.extend(self.block_tasks(code_block));
+        }
+
+        tasks
+    }
+
+    /// Returns the shell task running the code block, after the check of its
+    /// condition when that's a shell test.
+    fn block_tasks(&mut self, code_block: &CodeBlock) -> Vec<Value> {
+        let mut tasks = vec![];
+        let mut task = Mapping::new();
+        insert(&mut task, "name", Value::from(code_block.name.as_str()));
+
+        if let Some(path) = &code_block.file {
+            let mut copy = Mapping::new();
+            insert(&mut copy, "dest", Value::from(path.as_str()));
+            insert(&mut copy, "content", Value::from(code_block.code.as_str()));
+            if let Some(mode) = &code_block.mode {
+                insert(&mut copy, "mode", Value::from(mode.as_str()));
+            }
+            insert(&mut task, "ansible.builtin.copy", Value::Mapping(copy));
+        } else {
+            insert(&mut task, "ansible.builtin.shell", Value::from(code_block.code.as_str()));
+        }
+
+        let mut args = Mapping::new();
+        if code_block.file.is_none() && (code_block.lang == "bash" || code_block.lang == "zsh") {
+            insert(&mut args, "executable", Value::from(format!("/bin/{}", code_block.lang)));
+        }
+        if let Some(path) = &code_block.creates {
+            insert(&mut args, "creates", Value::from(path.as_str()));
+        }
+        if !args.is_empty() {
+            insert(&mut task, "args", Value::Mapping(args));
+        }
+
+        // `{sudo}` is Ansible's own privilege escalation.
+        if let Some(user) = &code_block.sudo {
+            insert(&mut task, "become", Value::from(true));
+            insert(&mut task, "become_user", Value::from(user.as_str()));
+        }
+
+        if let Some(condition) = &code_block.when {
+            let (check, when) = self.when(condition);
+            tasks.extend(check);
+            insert(&mut task, "when", Value::from(when));
+        }
+
+        // Ansible only knows a fixed delay, backoff is dropped.
+        if let Some(retry) = &code_block.retry {
+            insert(&mut task, "register", Value::from("corg_result"));
+            insert(&mut task, "until", Value::from("corg_result is succeeded"));
+            insert(&mut task, "retries", Value::from(retry.attempts - 1));
+            insert(&mut task, "delay", Value::from(retry.delay.ceil() as u64));
+        }
+
+        tasks.push(Value::Mapping(task));
+        tasks
+    }
+
+    /// Returns a block of the tasks which only runs when the condition holds.
+    fn guarded_block(&mut self, name: &str, condition: &Condition, tasks: Vec<Value>) -> Vec<Value> {
+        let (check, when) = self.when(condition);
+        let mut guarded: Vec<Value> = check.into_iter().collect();
+
+        let mut block = block(name, tasks);
+        insert(&mut block, "when", Value::from(when));
+        guarded.push(Value::Mapping(block));
+
+        guarded
+    }
+
+    /// Returns the `when` clause of the condition, shell tests can't be written
+    /// as one and run in a check task whose result the clause looks at.
+    fn when(&mut self, condition: &Condition) -> (Option<Value>, String) {
+        match condition {
+            Condition::Compare { key, operator, value } => (None, compare_expression(*key, *operator, value)),
+            Condition::Shell(test) => {
+                self.check_count += 1;
+                let register = format!("corg_when_{}", self.check_count);
+
+                let mut check = Mapping::new();
+                insert(&mut check, "name", Value::from(format!("Check {}", test)));
+                insert(&mut check, "ansible.builtin.shell", Value::from(test.as_str()));
+                insert(&mut check, "register", Value::from(register.as_str()));
+                insert(&mut check, "changed_when", Value::from(false));
+                insert(&mut check, "failed_when", Value::from(false));
+
+                (Some(Value::Mapping(check)), format!("{}.rc == 0", register))
+            }
+        }
+    }
+}
+
+fn block(name: &str, tasks: Vec<Value>) -> Mapping {
+    let mut block = Mapping::new();
+    insert(&mut block, "name", Value::from(name));
+    insert(&mut block, "block", Value::Sequence(tasks));
+
+    block
+}
+
+/// Returns the Jinja expression of an `os` or `host` comparison, `os` is matched
+/// against the distribution fact which is the `ID` of `/etc/os-release` apart
+/// from its case.
+fn compare_expression(key: ConditionKey, operator: Operator, value: &str) -> String {
+    let fact = match key {
+        ConditionKey::Os => "ansible_facts['distribution'] | lower",
+        ConditionKey::Host => "ansible_facts['hostname']",
+    };
+    let value = jinja_string(value);
+
+    match operator {
+        Operator::Equal => format!("{} == {}", fact, value),
+        Operator::NotEqual => format!("{} != {}", fact, value),
+        Operator::Matches => format!("{} is regex({})", fact, value),
+        Operator::NotMatches => format!("{} is not regex({})", fact, value),
+    }
+}
+
+/// Returns the variables of the play, dotted names are nested again so
+/// `{{ db.host }}` finds them.
+fn play_vars(vars: &CorgVars) -> Mapping {
+    let mut play_vars = Mapping::new();
+
+    for (key, value) in vars.entries() {
+        if !is_jinja_name(key) {
+            continue;
+        }
+
+        let mut parts: Vec<&str> = key.split('.').collect();
+        let last = parts.pop().unwrap_or(key);
+        let mut mapping = &mut play_vars;
+
+        for part in parts {
+            let entry = mapping
+                .entry(Value::from(part))
+                .or_insert_with(|| Value::Mapping(Mapping::new()));
+            if !entry.is_mapping() {
+                *entry = Value::Mapping(Mapping::new());
+            }
+            mapping = match entry {
+                Value::Mapping(mapping) => mapping,
+                _ => unreachable!(),
+            };
+        }
+
+        insert(mapping, last, Value::from(value));
+    }
+
+    play_vars
+}
+
+/// Jinja reads `{{ my-var }}` as a subtraction, only names made of identifiers
+/// can be left to Ansible.
+fn is_jinja_name(name: &str) -> bool {
+    name.split('.').all(|part| {
+        let mut chars = part.chars();
+
+        match chars.next() {
+            Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+                chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            _ => false,
+        }
+    })
+}
+
+fn jinja_string(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn insert(mapping: &mut Mapping, key: &str, value: Value) {
+    mapping.insert(Value::from(key), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front_matter::FrontMatter;
+
+    const GOLDEN_SOURCE: &str = include_str!("../tests/fixtures/ansible.md");
+    const GOLDEN_PLAYBOOK: &str = include_str!("../tests/fixtures/ansible.yml");
+
+    #[test]
+    fn test_playbook_golden_file() {
+        let (front_matter, _) = FrontMatter::split(GOLDEN_SOURCE).unwrap();
+        let vars = CorgVars::from_front_matter(front_matter.as_ref(), None).unwrap();
+        let document = Document::parse(GOLDEN_SOURCE, &placeholders(&vars), None).unwrap();
+
+        let yaml = serde_yaml::to_string(&playbook(&document, &vars)).unwrap();
+
+        assert_eq!(yaml.trim_end(), GOLDEN_PLAYBOOK.trim_end(), "\n{}", yaml);
+    }
+
+    #[test]
+    fn test_push_playbook_is_valid_yaml() {
+        let source = "## Quote {when=\"host=~'\"}\n\n```shell\necho \"a: b\" # - [x]\n```\n";
+        let document = Document::parse(source, &CorgVars::new(), None).unwrap();
+        let mut output = String::new();
+
+        push_playbook(&mut output, "quote.yml", &document, &CorgVars::new());
+
+        let parsed: Value = serde_yaml::from_str(&output).unwrap();
+        let task = &parsed[0]["tasks"][0];
+        assert_eq!(task["when"], Value::from("ansible_facts['hostname'] is regex('\\'')"));
+        assert_eq!(task["block"][0]["ansible.builtin.shell"], Value::from("echo \"a: b\" # - [x]\n"));
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let mut vars = CorgVars::new();
+        vars.insert("db.host", "db1");
+        vars.insert("api-token-name", "ci");
+
+        let placeholders = placeholders(&vars);
+
+        assert_eq!(placeholders.get("db.host").unwrap(), "{{ db.host }}");
+        assert_eq!(placeholders.get("api-token-name").unwrap(), "ci");
+    }
+}
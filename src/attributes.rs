@@ -0,0 +1,366 @@
+use std::fmt;
+
+/// Attributes written in braces after a code fence language, e.g.
+///
+/// ```text
+/// ```shell {retry=5, backoff=exp, delay=2s}
+/// ```
+///
+/// Entries are separated by commas or whitespace, values can be quoted and
+/// entries without a value are flags.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorgAttributes {
+    entries: Vec<(String, Option<String>)>,
+}
+
+impl CorgAttributes {
+    /// Splits a code fence info string into its language and attributes.
+    pub fn from_info(info: &str) -> Result<(String, CorgAttributes), String> {
+        let (lang, attributes) = split_trailing(info);
+        let lang = lang.split_whitespace().next().unwrap_or("").to_string();
+
+        match attributes {
+            Some(attributes) => Ok((lang, CorgAttributes::parse(attributes)?)),
+            None => Ok((lang, CorgAttributes::default())),
+        }
+    }
+
+    /// Parses the text between the braces.
+    pub fn parse(text: &str) -> Result<CorgAttributes, String> {
+        let mut entries = vec![];
+        let mut chars = text.chars().peekable();
+
+        loop {
+            // Skip separators
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() || *c == ',' {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            if chars.peek().is_none() {
+                break;
+            }
+
+            let mut key = String::new();
+            while let Some(c) = chars.peek() {
+                if c.is_alphanumeric() || *c == '-' || *c == '_' {
+                    key.push(*c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            if key.is_empty() {
+                let unexpected = chars.next().unwrap();
+                return Err(format!("unexpected '{}' in attributes", unexpected));
+            }
+
+            let value = if chars.peek() == Some(&'=') {
+                chars.next();
+                Some(parse_value(&mut chars, &key)?)
+            } else {
+                None
+            };
+
+            entries.push((key, value));
+        }
+
+        Ok(CorgAttributes { entries })
+    }
+
+    /// The keys and values in the order they were written, flags have no value.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_deref()))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(key, _)| key.as_str())
+    }
+
+    pub fn has(&self, key: &str) -> bool {
+        self.keys().any(|existing| existing == key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| existing == key)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Returns an error naming the first key which isn't in `known`.
+    pub fn check_keys(&self, known: &[&str]) -> Result<(), String> {
+        match self.keys().find(|key| !known.contains(key)) {
+            Some(key) => Err(format!(
+                "unknown attribute '{}', expected one of: {}",
+                key,
+                known.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for CorgAttributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}={:?}", key, value),
+                None => key.clone(),
+            })
+            .collect();
+
+        write!(f, "{{{}}}", entries.join(", "))
+    }
+}
+
+fn parse_value<I>(chars: &mut std::iter::Peekable<I>, key: &str) -> Result<String, String>
+where
+    I: Iterator<Item = char>,
+{
+    let mut value = String::new();
+
+    match chars.peek() {
+        Some(&quote) if quote == '"' || quote == '\'' => {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(c) => value.push(c),
+                        None => break,
+                    },
+                    Some(c) if c == quote => return Ok(value),
+                    Some(c) => value.push(c),
+                    None => break,
+                }
+            }
+
+            Err(format!("unterminated quote in the value of '{}'", key))
+        }
+        _ => {
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() || *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+
+            if value.is_empty() {
+                Err(format!("missing value for '{}'", key))
+            } else {
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// Splits trailing `{...}` attributes off a code fence info string or heading,
+/// returns the text before them and the text between the braces.
+pub fn split_trailing(text: &str) -> (&str, Option<&str>) {
+    let trimmed = text.trim_end();
+
+    if !trimmed.ends_with('}') {
+        return (text, None);
+    }
+
+    // Find the brace opening the last group, skipping over quoted values.
+    let mut quote: Option<char> = None;
+    let mut open: Option<usize> = None;
+    let mut inside = false;
+    for (index, c) in trimmed.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') if inside => quote = Some(c),
+            (None, '{') if !inside => {
+                open = Some(index);
+                inside = true;
+            }
+            (None, '}') => inside = false,
+            _ => (),
+        }
+    }
+
+    match open {
+        Some(open) => (&trimmed[..open], Some(&trimmed[open + 1..trimmed.len() - 1])),
+        None => (text, None),
+    }
+}
+
+/// Parses a duration like `2s`, `500ms`, `5m` or `1h` into seconds, plain numbers
+/// are seconds.
+pub fn parse_duration(text: &str) -> Result<f64, String> {
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", text))?;
+
+    let multiplier = match unit {
+        "" | "s" => 1.0,
+        "ms" => 0.001,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("invalid duration '{}', expected e.g. 500ms, 2s, 5m or 1h", text)),
+    };
+
+    Ok(number * multiplier)
+}
+
+/// How the delay between retries grows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Fixed,
+    Linear,
+    Exponential,
+}
+
+impl Backoff {
+    fn parse(text: &str) -> Result<Backoff, String> {
+        match text {
+            "fixed" | "none" => Ok(Backoff::Fixed),
+            "linear" => Ok(Backoff::Linear),
+            "exp" | "exponential" => Ok(Backoff::Exponential),
+            _ => Err(format!("invalid backoff '{}', expected fixed, linear or exp", text)),
+        }
+    }
+
+    /// Name understood by `corg_retry` in the logger script.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fixed => "fixed",
+            Self::Linear => "linear",
+            Self::Exponential => "exp",
+        }
+    }
+}
+
+/// `{retry=5, backoff=exp, delay=2s}` runs a code block up to 5 times, waiting
+/// 2, 4, 8 and 16 seconds between the attempts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Backoff,
+    /// Seconds to wait before the first retry.
+    pub delay: f64,
+}
+
+impl RetryPolicy {
+    pub fn from_attributes(attributes: &CorgAttributes) -> Result<Option<RetryPolicy>, String> {
+        let attempts = match attributes.get("retry") {
+            Some(attempts) => match attempts.parse::<u32>() {
+                Ok(attempts) if attempts > 0 => attempts,
+                _ => return Err(format!("retry must be a positive number of attempts, got '{}'", attempts)),
+            },
+            None if attributes.has("retry") => return Err(String::from("missing value for 'retry'")),
+            None => {
+                if attributes.has("backoff") || attributes.has("delay") {
+                    return Err(String::from("backoff and delay only apply to blocks with retry"));
+                }
+                return Ok(None);
+            }
+        };
+
+        let backoff = match attributes.get("backoff") {
+            Some(backoff) => Backoff::parse(backoff)?,
+            None => Backoff::Fixed,
+        };
+
+        let delay = match attributes.get("delay") {
+            Some(delay) => parse_duration(delay)?,
+            None => 1.0,
+        };
+
+        Ok(Some(RetryPolicy {
+            attempts,
+            backoff,
+            delay,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_info() {
+        let (lang, attributes) = CorgAttributes::from_info("shell {retry=5, backoff=exp delay=2s, sudo}").unwrap();
+
+        assert_eq!(lang, "shell");
+        assert_eq!(attributes.get("retry"), Some("5"));
+        assert_eq!(attributes.get("backoff"), Some("exp"));
+        assert_eq!(attributes.get("delay"), Some("2s"));
+        assert!(attributes.has("sudo"));
+        assert_eq!(attributes.get("sudo"), None);
+    }
+
+    #[test]
+    fn test_from_info_without_attributes() {
+        let (lang, attributes) = CorgAttributes::from_info("shell").unwrap();
+
+        assert_eq!(lang, "shell");
+        assert_eq!(attributes, CorgAttributes::default());
+    }
+
+    #[test]
+    fn test_parse_quoted() {
+        let attributes = CorgAttributes::parse(r#"when="host=~^db{1,2}", name='pull "all"'"#).unwrap();
+
+        assert_eq!(attributes.get("when"), Some("host=~^db{1,2}"));
+        assert_eq!(attributes.get("name"), Some("pull \"all\""));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(CorgAttributes::parse("retry=").is_err());
+        assert!(CorgAttributes::parse("when=\"os=debian").is_err());
+        assert!(CorgAttributes::parse("=5").is_err());
+    }
+
+    #[test]
+    fn test_split_trailing() {
+        assert_eq!(split_trailing("Install Nix {when=\"x}\"}"), ("Install Nix ", Some("when=\"x}\"")));
+        assert_eq!(split_trailing("Use {a} it's {sudo}"), ("Use {a} it's ", Some("sudo")));
+        assert_eq!(split_trailing("Install Nix"), ("Install Nix", None));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let attributes = CorgAttributes::parse("retry=5, backoff=exp, delay=2s").unwrap();
+        let expected = RetryPolicy {
+            attempts: 5,
+            backoff: Backoff::Exponential,
+            delay: 2.0,
+        };
+
+        assert_eq!(RetryPolicy::from_attributes(&attributes), Ok(Some(expected)));
+        assert_eq!(RetryPolicy::from_attributes(&CorgAttributes::default()), Ok(None));
+    }
+
+    #[test]
+    fn test_retry_policy_errors() {
+        for attributes in &["retry=0", "retry=many", "retry=3, backoff=random", "backoff=exp"] {
+            let attributes = CorgAttributes::parse(attributes).unwrap();
+            assert!(RetryPolicy::from_attributes(&attributes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2s"), Ok(2.0));
+        assert_eq!(parse_duration("500ms"), Ok(0.5));
+        assert_eq!(parse_duration("3"), Ok(3.0));
+        assert_eq!(parse_duration("1m"), Ok(60.0));
+        assert!(parse_duration("soon").is_err());
+    }
+}
@@ -84,7 +84,7 @@ impl<'a> Clog<'a> {
     }
 
     pub fn debug(&mut self, message: &str) {
-        self.write_log(ClogLevel::Announce, message);
+        self.write_log(ClogLevel::Debug, message);
     }
 
     pub fn info(&mut self, message: &str) {
@@ -111,8 +111,13 @@ impl<'a> Clog<'a> {
         self.write_log(ClogLevel::Announce, message);
     }
 
-    fn set_log_level_color(&mut self) {
-        let log_level_color = self.level.color();
+    /// Logs a message at the given level, e.g. one read from a running script.
+    pub fn log(&mut self, level: ClogLevel, message: &str) {
+        self.write_log(level, message);
+    }
+
+    fn set_log_level_color(&mut self, level: ClogLevel) {
+        let log_level_color = level.color();
 
         self.set_bold();
         self.set_color(log_level_color);
@@ -124,18 +129,18 @@ impl<'a> Clog<'a> {
 
     fn write_log(&mut self, level: ClogLevel, message: &str) {
         if self.level.should_log(level) {
-            self.write_log_level();
+            self.write_log_level(level);
             self.write(message);
             self.write_newline();
         }
     }
 
-    fn write_log_level(&mut self) {
-        let level_text = self.level.to_string();
+    fn write_log_level(&mut self, level: ClogLevel) {
+        let level_text = level.to_string();
         self.set_white();
         self.set_bold();
         self.write("[");
-        self.set_log_level_color();
+        self.set_log_level_color(level);
         self.write(&level_text);
         self.set_white();
         self.set_bold();
@@ -0,0 +1,170 @@
+use crate::document::{CodeBlock, Document, Section};
+use crate::CORG_VERSION;
+use chrono::prelude::*;
+use serde_yaml::{Mapping, Value};
+
+/// Where the script of the runbook is written on the machine.
+pub const CLOUD_INIT_SCRIPT_PATH: &str = "/var/lib/corg/runbook.sh";
+
+/// Separates the parts of multipart user-data.
+const BOUNDARY: &str = "==CORG-USER-DATA==";
+
+/// Renders a `#cloud-config` document bootstrapping a machine with the runbook:
+/// zsh is installed, the `{file=...}` blocks go under `write_files` next to the
+/// script, which `runcmd` runs once the machine booted.
+///
+/// `script` has to be standalone (see `CorgFile::standalone`), nothing else is
+/// written to the machine. Files which are only written under a condition (or
+/// with `creates`) are left to the script.
+pub fn push_cloud_config(s: &mut String, file_name: &str, document: &Document, script: &str) {
+    let mut config = cloud_config(document);
+
+    if let Some(Value::Sequence(write_files)) = config.get_mut(&Value::from("write_files")) {
+        write_files.push(write_file(CLOUD_INIT_SCRIPT_PATH, script, Some("0755")));
+    }
+
+    let command = vec![Value::from("zsh"), Value::from(CLOUD_INIT_SCRIPT_PATH)];
+    insert(&mut config, "runcmd", Value::Sequence(vec![Value::Sequence(command)]));
+
+    push_config(s, file_name, &config);
+}
+
+/// Renders MIME multipart user-data, a cloud-config part installing zsh and
+/// writing the files followed by the script as a part of its own.
+pub fn push_multipart(s: &mut String, file_name: &str, document: &Document, script: &str) {
+    let mut config = String::new();
+    push_config(&mut config, file_name, &cloud_config(document));
+
+    s.push_str(&format!("Content-Type: multipart/mixed; boundary=\"{}\"\n", BOUNDARY));
+    s.push_str("MIME-Version: 1.0\n");
+
+    push_part(s, "text/cloud-config", "cloud-config.txt", &config);
+    push_part(s, "text/x-shellscript", "runbook.sh", script);
+
+    s.push_str(&format!("\n--{}--\n", BOUNDARY));
+}
+
+/// The parts of the config both kinds of user-data share.
+fn cloud_config(document: &Document) -> Mapping {
+    let mut config = Mapping::new();
+
+    // Generated scripts run with zsh, which most images don't ship.
+    insert(&mut config, "packages", Value::Sequence(vec![Value::from("zsh")]));
+
+    let write_files = files(document)
+        .into_iter()
+        .filter_map(|block| {
+            let path = block.file.as_ref()?;
+            Some(write_file(path, &block.code, block.mode.as_deref()))
+        })
+        .collect();
+    insert(&mut config, "write_files", Value::Sequence(write_files));
+
+    config
+}
+
+fn push_config(s: &mut String, file_name: &str, config: &Mapping) {
+    s.push_str("#cloud-config\n");
+    s.push_str(&format!("# -- GENERATED BY CORG v{} --\n", CORG_VERSION));
+    s.push_str(&format!("# filename: {}\n", file_name));
+    s.push_str(&format!("# date: {}\n", Utc::now().to_rfc3339()));
+
+    match serde_yaml::to_string(config) {
+        Ok(yaml) => s.push_str(&yaml),
+        Err(err) => panic!("failed to serialize the cloud-config: {}", err),
+    }
+    s.push('\n');
+}
+
+fn push_part(s: &mut String, content_type: &str, file_name: &str, content: &str) {
+    s.push_str(&format!("\n--{}\n", BOUNDARY));
+    s.push_str(&format!("Content-Type: {}; charset=\"utf-8\"\n", content_type));
+    s.push_str("MIME-Version: 1.0\n");
+    s.push_str("Content-Transfer-Encoding: 8bit\n");
+    s.push_str(&format!("Content-Disposition: attachment; filename=\"{}\"\n\n", file_name));
+    s.push_str(content.trim_end_matches('\n'));
+    s.push('\n');
+}
+
+fn write_file(path: &str, content: &str, mode: Option<&str>) -> Value {
+    let mut file = Mapping::new();
+    insert(&mut file, "path", Value::from(path));
+    insert(&mut file, "content", Value::from(content));
+    if let Some(mode) = mode {
+        insert(&mut file, "permissions", Value::from(mode));
+    }
+
+    Value::Mapping(file)
+}
+
+/// Returns the `{file=...}` blocks which are always written, in document order.
+fn files(document: &Document) -> Vec<&CodeBlock> {
+    let mut files = vec![];
+
+    if document.title.as_ref().is_some_and(|title| title.when.is_some()) {
+        return files;
+    }
+
+    fn collect<'a>(blocks: &'a [CodeBlock], sections: &'a [Section], files: &mut Vec<&'a CodeBlock>) {
+        for block in blocks.iter() {
+            if block.file.is_some() && block.when.is_none() && block.creates.is_none() {
+                files.push(block);
+            }
+        }
+
+        for section in sections.iter().filter(|section| section.heading.when.is_none()) {
+            collect(&section.blocks, &section.children, files);
+        }
+    }
+
+    collect(&document.blocks, &document.sections, &mut files);
+
+    files
+}
+
+fn insert(mapping: &mut Mapping, key: &str, value: Value) {
+    mapping.insert(Value::from(key), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vars::CorgVars;
+
+    const SOURCE: &str = "# Faye\n\n## Nginx\n\n```nginx {file=/etc/nginx/nginx.conf, mode=0644}\nworker_processes 2;\n```\n\n\
+                          ### Debian {when=\"os=debian\"}\n\n```conf {file=/etc/apt/pin}\nPin: nginx\n```\n";
+
+    fn document() -> Document {
+        Document::parse(SOURCE, &CorgVars::new(), None).unwrap()
+    }
+
+    #[test]
+    fn test_push_cloud_config() {
+        let mut output = String::new();
+        push_cloud_config(&mut output, "faye.user-data.yml", &document(), "#!/bin/zsh\necho hi\n");
+
+        assert!(output.starts_with("#cloud-config\n"));
+
+        let config: Value = serde_yaml::from_str(&output).unwrap();
+        assert_eq!(config["packages"][0], Value::from("zsh"));
+        assert_eq!(config["write_files"][0]["path"], Value::from("/etc/nginx/nginx.conf"));
+        assert_eq!(config["write_files"][0]["content"], Value::from("worker_processes 2;\n"));
+        assert_eq!(config["write_files"][0]["permissions"], Value::from("0644"));
+        // The conditional file is left to the script.
+        assert_eq!(config["write_files"][1]["path"], Value::from(CLOUD_INIT_SCRIPT_PATH));
+        assert_eq!(config["write_files"][1]["content"], Value::from("#!/bin/zsh\necho hi\n"));
+        assert_eq!(config["runcmd"][0][1], Value::from(CLOUD_INIT_SCRIPT_PATH));
+    }
+
+    #[test]
+    fn test_push_multipart() {
+        let mut output = String::new();
+        push_multipart(&mut output, "faye.user-data", &document(), "#!/bin/zsh\necho hi\n");
+
+        assert!(output.starts_with("Content-Type: multipart/mixed; boundary=\"==CORG-USER-DATA==\"\n"));
+        assert!(output.contains("Content-Type: text/cloud-config; charset=\"utf-8\"\n"));
+        assert!(output.contains("filename=\"runbook.sh\"\n\n#!/bin/zsh\necho hi\n\n--==CORG-USER-DATA==--\n"));
+        assert!(!output.contains("runcmd"));
+        assert_eq!(output.matches("\n--==CORG-USER-DATA==\n").count(), 2);
+    }
+}
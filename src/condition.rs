@@ -0,0 +1,255 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::mem::MaybeUninit;
+
+/// Matches `key=value` style conditions, e.g. `os=debian` or `host=~^db`.
+static KEY_CONDITION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^([A-Za-z_][A-Za-z0-9_\-]*)\s*(!=|=~|!~|=)\s*(.*)$").unwrap()
+});
+
+/// What a `key=value` condition is checked against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConditionKey {
+    /// The `ID` of `/etc/os-release`, e.g. `debian` or `nixos`.
+    Os,
+    /// The hostname of the machine running the script.
+    Host,
+}
+
+impl ConditionKey {
+    fn parse(text: &str) -> Result<ConditionKey, String> {
+        match text {
+            "os" => Ok(ConditionKey::Os),
+            "host" => Ok(ConditionKey::Host),
+            _ => Err(format!(
+                "unknown condition '{}', expected os or host, or a shell test like `[ -f /etc/debian_version ]`",
+                text
+            )),
+        }
+    }
+
+    /// Name understood by `corg_when` in the logger script.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Os => "os",
+            Self::Host => "host",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Matches,
+    NotMatches,
+}
+
+impl Operator {
+    fn parse(text: &str) -> Operator {
+        match text {
+            "!=" => Operator::NotEqual,
+            "=~" => Operator::Matches,
+            "!~" => Operator::NotMatches,
+            _ => Operator::Equal,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Equal => "=",
+            Self::NotEqual => "!=",
+            Self::Matches => "=~",
+            Self::NotMatches => "!~",
+        }
+    }
+}
+
+/// The condition of a `{when="..."}` attribute on a heading or code fence, the
+/// section or block is skipped at runtime when it doesn't hold.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// `os=debian`, `os!=nixos`, `host=~^db` or `host!~^web`
+    Compare {
+        key: ConditionKey,
+        operator: Operator,
+        value: String,
+    },
+    /// A shell test written as is, e.g. `[ -d /etc/nixos ]` or `[[ $(uname) = Linux ]]`
+    Shell(String),
+}
+
+impl Condition {
+    /// Parses and validates a condition, so every generator (`shell.rs` and the
+    /// others) gets one which holds up at runtime. Conditions are parsed with the
+    /// document, a typo fails the conversion rather than the run.
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let text = text.trim();
+
+        if text.is_empty() {
+            return Err(String::from("when needs a condition, e.g. when=\"os=debian\""));
+        }
+
+        if is_shell_test(text) {
+            check_shell_test(text)?;
+            return Ok(Condition::Shell(String::from(text)));
+        }
+
+        let captures = match KEY_CONDITION.captures(text) {
+            Some(captures) => captures,
+            None => {
+                return Err(format!(
+                    "invalid condition '{}', expected e.g. os=debian, host=~^db or a shell test like `[ -f /etc/debian_version ]`",
+                    text
+                ))
+            }
+        };
+
+        let key = ConditionKey::parse(&captures[1])?;
+        let operator = Operator::parse(&captures[2]);
+        let value = captures[3].trim();
+
+        if value.is_empty() {
+            return Err(format!("missing value in condition '{}'", text));
+        }
+
+        if operator == Operator::Matches || operator == Operator::NotMatches {
+            check_pattern(value).map_err(|err| format!("invalid regex in condition '{}': {}", text, err))?;
+        }
+
+        Ok(Condition::Compare {
+            key,
+            operator,
+            value: String::from(value),
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compare { key, operator, value } => {
+                write!(f, "{}{}{}", key.as_str(), operator.as_str(), value)
+            }
+            Self::Shell(test) => write!(f, "{}", test),
+        }
+    }
+}
+
+/// Compiles a `=~` pattern the way zsh does at runtime, as a POSIX extended
+/// regular expression (`grep -E` in Dockerfiles uses the same dialect). Perl
+/// syntax like `(?i)` or `\d` isn't supported there.
+fn check_pattern(pattern: &str) -> Result<(), String> {
+    let pattern = CString::new(pattern).map_err(|_| String::from("pattern contains a NUL byte"))?;
+    let mut regex = MaybeUninit::<libc::regex_t>::uninit();
+
+    unsafe {
+        let code = libc::regcomp(regex.as_mut_ptr(), pattern.as_ptr(), libc::REG_EXTENDED | libc::REG_NOSUB);
+        if code != 0 {
+            let mut message = [0 as libc::c_char; 256];
+            libc::regerror(code, regex.as_ptr(), message.as_mut_ptr(), message.len());
+            return Err(CStr::from_ptr(message.as_ptr()).to_string_lossy().into_owned());
+        }
+        libc::regfree(regex.as_mut_ptr());
+    }
+
+    Ok(())
+}
+
+fn is_shell_test(text: &str) -> bool {
+    let text = text.trim_start_matches('!').trim_start();
+
+    text.starts_with('[') || text == "test" || text.starts_with("test ")
+}
+
+/// Catches the mistakes which would otherwise only show up as a syntax error
+/// when the script runs.
+fn check_shell_test(text: &str) -> Result<(), String> {
+    let test = text.trim_start_matches('!').trim_start();
+
+    if test.contains('\n') {
+        return Err(format!("shell test '{}' must be on a single line", text));
+    }
+
+    let closed = if test.starts_with("[[") {
+        test.len() > 4 && test.starts_with("[[ ") && test.ends_with(" ]]")
+    } else if test.starts_with('[') {
+        test.len() > 2 && test.starts_with("[ ") && test.ends_with(" ]")
+    } else {
+        true
+    };
+
+    if !closed {
+        return Err(format!("shell test '{}' isn't closed, expected e.g. `[ -f /etc/debian_version ]`", text));
+    }
+
+    let mut quote: Option<char> = None;
+    let mut chars = test.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => (),
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            _ => (),
+        }
+    }
+
+    match quote {
+        Some(_) => Err(format!("unterminated quote in shell test '{}'", text)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compare() {
+        let expected = Condition::Compare {
+            key: ConditionKey::Host,
+            operator: Operator::Matches,
+            value: String::from("^db[0-9]+"),
+        };
+
+        assert_eq!(Condition::parse("host=~^db[0-9]+"), Ok(expected));
+        assert!(Condition::parse("host=~^(db|cache)[[:digit:]]{2}$").is_ok());
+        assert_eq!(
+            Condition::parse("os != nixos").map(|condition| condition.to_string()),
+            Ok(String::from("os!=nixos"))
+        );
+    }
+
+    #[test]
+    fn test_parse_shell_test() {
+        for test in &["[ -d /etc/nixos ]", "[[ $(uname) = Linux ]]", "! test -f /etc/debian_version"] {
+            assert_eq!(Condition::parse(test), Ok(Condition::Shell(String::from(*test))));
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let invalid = &[
+            "",
+            "distro=debian",
+            "os=",
+            "host=~^db(",
+            "host=~(?i)^db",
+            "host!~^web[",
+            "[ -d /etc/nixos",
+            "[[ -d /etc/nixos ]",
+            "[ \"$HOST = db ]",
+            "uname | grep Linux",
+        ];
+
+        for condition in invalid {
+            assert!(Condition::parse(condition).is_err(), "{}", condition);
+        }
+    }
+}
@@ -1,4 +1,3 @@
-use crate::corg_file::CorgFile;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::Error;
@@ -0,0 +1,69 @@
+use crate::location::Location;
+use crate::secrets::SecretFinding;
+use std::fmt;
+use std::io;
+
+/// Everything that can stop corg from turning a document into a script.
+#[derive(Debug)]
+pub enum CorgError {
+    Io(io::Error),
+    /// The front matter block isn't valid YAML.
+    FrontMatter { location: Location, message: String },
+    /// The document contains secrets which haven't been allow-listed.
+    Secrets(Vec<SecretFinding>),
+    /// Variables couldn't be read from the front matter, a vars file or `--var`.
+    Vars(String),
+    /// A `{{ var }}` placeholder without a value.
+    UndefinedVariable { location: Location, name: String },
+    /// Attributes of a code fence or heading which don't make sense.
+    InvalidAttribute { location: Location, message: String },
+    /// The runbook can't be scheduled, e.g. without a calendar to run on.
+    Schedule(String),
+    /// The host inventory can't be read or names hosts which don't exist.
+    Inventory(String),
+    /// A record of a past run can't be read.
+    History(String),
+    /// Variables for a run couldn't be read from an env file or a secrets provider.
+    Env(String),
+    /// Code can't run as another user on a host, e.g. without sudo.
+    Become(String),
+    /// A target can't be reached or files can't be moved to or from it.
+    Transport(String),
+}
+
+impl fmt::Display for CorgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::FrontMatter { location, message } => {
+                write!(f, "{}: invalid front matter: {}", location, message)
+            }
+            Self::Secrets(findings) => {
+                write!(f, "found {} possible secret(s)", findings.len())?;
+                for finding in findings {
+                    write!(f, "\n{}", finding)?;
+                }
+                Ok(())
+            }
+            Self::Vars(message) => write!(f, "{}", message),
+            Self::UndefinedVariable { location, name } => {
+                write!(f, "{}: undefined variable '{}'", location, name)
+            }
+            Self::InvalidAttribute { location, message } => write!(f, "{}: {}", location, message),
+            Self::Schedule(message) => write!(f, "{}", message),
+            Self::Inventory(message) => write!(f, "{}", message),
+            Self::History(message) => write!(f, "{}", message),
+            Self::Env(message) => write!(f, "{}", message),
+            Self::Become(message) => write!(f, "{}", message),
+            Self::Transport(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CorgError {}
+
+impl From<io::Error> for CorgError {
+    fn from(err: io::Error) -> CorgError {
+        CorgError::Io(err)
+    }
+}
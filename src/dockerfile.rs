@@ -0,0 +1,329 @@
+use crate::attributes::Backoff;
+use crate::condition::{Condition, ConditionKey, Operator};
+use crate::document::{CodeBlock, Document, Section};
+use crate::vars::CorgVars;
+use crate::CORG_VERSION;
+use chrono::prelude::*;
+use std::collections::HashMap;
+
+/// Base image used when none is given.
+pub const DEFAULT_BASE_IMAGE: &str = "debian:12";
+
+/// Ends the heredocs of `RUN` and `COPY` instructions.
+const DELIMITER: &str = "CORG_EOF";
+
+/// Renders a document as a Dockerfile building on `base`.
+///
+/// - the variables become `ARG`s, code refers to them as `${name}` (see
+///   `placeholders`) so they can be changed with `--build-arg`.
+/// - every code block becomes a `RUN` instruction below a comment with the
+///   heading of its section, `when` conditions and retries are checked in it.
+/// - `{file=...}` blocks become `COPY` instructions, or a `RUN` writing the file
+///   when it's only written under a condition. Their content is taken from
+///   `resolved`, the document parsed with the values of the variables, since the
+///   shell doesn't expand placeholders in files.
+///
+/// Prose isn't part of the Dockerfile and `parallel` blocks run one after another.
+pub fn push_dockerfile(
+    s: &mut String,
+    file_name: &str,
+    base: &str,
+    document: &Document,
+    resolved: &Document,
+    vars: &CorgVars,
+) {
+    // Heredocs need the newer syntax, the directive has to be the first line.
+    s.push_str("# syntax=docker/dockerfile:1\n");
+    s.push_str(&format!("# -- GENERATED BY CORG v{} --\n", CORG_VERSION));
+    s.push_str(&format!("# filename: {}\n", file_name));
+    s.push_str(&format!("# date: {}\n\n", Utc::now().to_rfc3339()));
+
+    s.push_str(&format!("FROM {}\n", base));
+
+    let args: Vec<(&str, &str)> = vars.entries().into_iter().filter(|(key, _)| is_arg_name(key)).collect();
+    if !args.is_empty() {
+        s.push('\n');
+        for (key, value) in args {
+            s.push_str(&format!("ARG {}={}\n", key, arg_value(value)));
+        }
+    }
+
+    let mut files = HashMap::new();
+    collect_files(&resolved.blocks, &resolved.sections, &mut files);
+
+    let mut writer = DockerfileWriter {
+        output: s,
+        files,
+        conditions: vec![],
+    };
+
+    if let Some(condition) = document.title.as_ref().and_then(|title| title.when.as_ref()) {
+        writer.conditions.push(condition.clone());
+    }
+
+    writer.write_blocks(&document.blocks);
+    for section in document.sections.iter() {
+        writer.write_section(section);
+    }
+}
+
+/// Returns the variables to parse a document with for a Dockerfile, variables
+/// which can be an `ARG` are left to the shell as `${name}`, the others are
+/// filled in.
+pub fn placeholders(vars: &CorgVars) -> CorgVars {
+    let mut placeholders = CorgVars::new();
+
+    for (key, value) in vars.entries() {
+        if is_arg_name(key) {
+            placeholders.insert(key, &format!("${{{}}}", key));
+        } else {
+            placeholders.insert(key, value);
+        }
+    }
+
+    placeholders
+}
+
+struct DockerfileWriter<'s, 'd> {
+    output: &'s mut String,
+    /// Content of the `{file=...}` blocks by their offset in the markdown.
+    files: HashMap<usize, &'d str>,
+    /// Conditions of the title and the open sections.
+    conditions: Vec<Condition>,
+}
+
+impl<'s, 'd> DockerfileWriter<'s, 'd> {
+    fn write(&mut self, s: &str) {
+        self.output.push_str(s);
+    }
+
+    fn write_section(&mut self, section: &Section) {
+        self.write(&format!("\n# {}\n", section.heading.text));
+
+        if let Some(condition) = &section.heading.when {
+            self.conditions.push(condition.clone());
+        }
+
+        self.write_blocks(&section.blocks);
+        for child in section.children.iter() {
+            self.write_section(child);
+        }
+
+        if section.heading.when.is_some() {
+            self.conditions.pop();
+        }
+    }
+
+    fn write_blocks(&mut self, blocks: &[CodeBlock]) {
+        for block in blocks.iter() {
+            match &block.file {
+                Some(path) => self.write_file(block, path),
+                None => self.write_run(block),
+            }
+        }
+    }
+
+    /// Files which are always written are copied, the others written by a script.
+    fn write_file(&mut self, block: &CodeBlock, path: &str) {
+        let content = self.files.get(&block.range.start).copied().unwrap_or(&block.code);
+
+        if self.conditions.is_empty() && block.when.is_none() && block.creates.is_none() {
+            let chmod = match &block.mode {
+                Some(mode) => format!("--chmod={} ", mode),
+                None => String::new(),
+            };
+            self.write(&format!("COPY {}<<'{}' {}\n", chmod, DELIMITER, path));
+            self.write(&heredoc_body(content));
+            return;
+        }
+
+        let mut script = String::new();
+        if let Some(parent) = std::path::Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            script.push_str(&format!("mkdir -p {}\n", shell_quote(&parent.to_string_lossy())));
+        }
+        script.push_str(&format!("cat > {} << 'CORG_FILE_EOF'\n{}", shell_quote(path), content));
+        if !content.is_empty() && !content.ends_with('\n') {
+            script.push('\n');
+        }
+        script.push_str("CORG_FILE_EOF\n");
+        if let Some(mode) = &block.mode {
+            script.push_str(&format!("chmod {} {}\n", mode, shell_quote(path)));
+        }
+
+        self.write_script(block, "sh", &script);
+    }
+
+    fn write_run(&mut self, block: &CodeBlock) {
+        let script = match &block.retry {
+            Some(retry) => {
+                let mut script = format!("corg_block=$(cat << 'CORG_BLOCK_EOF'\n{}CORG_BLOCK_EOF\n)\n", block.code);
+                let delay = retry.delay.ceil() as u64;
+                let next_delay = match retry.backoff {
+                    Backoff::Fixed => None,
+                    Backoff::Linear => Some(format!("$((corg_delay + {}))", delay)),
+                    Backoff::Exponential => Some(String::from("$((corg_delay * 2))")),
+                };
+                script.push_str(&format!("corg_attempt=1\ncorg_delay={}\n", delay));
+                script.push_str(&format!("until {} -ec \"$corg_block\"; do\n", interpreter(&block.lang)));
+                script.push_str(&format!("\t[ \"$corg_attempt\" -lt {} ] || exit 1\n", retry.attempts));
+                script.push_str(&format!(
+                    "\techo \"Retrying {} in ${{corg_delay}}s\"\n",
+                    block.name.replace('"', "\\\"")
+                ));
+                script.push_str("\tsleep \"$corg_delay\"\n");
+                script.push_str("\tcorg_attempt=$((corg_attempt + 1))\n");
+                if let Some(next_delay) = next_delay {
+                    script.push_str(&format!("\tcorg_delay={}\n", next_delay));
+                }
+                script.push_str("done\n");
+                script
+            }
+            None => block.code.clone(),
+        };
+
+        self.write_script(block, interpreter(&block.lang), &script);
+    }
+
+    /// Writes a `RUN` instruction running the script when the conditions of the
+    /// sections and the block hold.
+    fn write_script(&mut self, block: &CodeBlock, interpreter: &str, script: &str) {
+        let mut tests: Vec<String> = self.conditions.iter().map(condition_test).collect();
+        if let Some(condition) = &block.when {
+            tests.push(condition_test(condition));
+        }
+        if let Some(path) = &block.creates {
+            tests.push(format!("[ ! -e {} ]", shell_quote(path)));
+        }
+
+        let mut body = format!("#!/usr/bin/env {}\nset -e\n", interpreter);
+        if tests.is_empty() {
+            body.push_str(script);
+        } else {
+            // Not indented, heredocs in the script have to end at the start of a line.
+            body.push_str(&format!("if {}; then\n", tests.join(" && ")));
+            body.push_str(script);
+            body.push_str(&format!("else\n\techo \"Skipping {}\"\nfi\n", block.name.replace('"', "\\\"")));
+        }
+
+        self.write(&format!("RUN <<'{}'\n", DELIMITER));
+        self.write(&heredoc_body(&body));
+    }
+}
+
+/// Returns the shell running the block, code in `bash` or `zsh` blocks needs
+/// that shell in the image.
+fn interpreter(lang: &str) -> &str {
+    match lang {
+        "bash" | "zsh" => lang,
+        _ => "sh",
+    }
+}
+
+/// Returns the POSIX shell test checking the condition, the logger script isn't
+/// part of the image.
+fn condition_test(condition: &Condition) -> String {
+    match condition {
+        Condition::Compare { key, operator, value } => {
+            let actual = match key {
+                ConditionKey::Os => "\"$(. /etc/os-release && echo \"$ID\")\"",
+                ConditionKey::Host => "\"$(hostname)\"",
+            };
+            let value = shell_quote(value);
+
+            match operator {
+                Operator::Equal => format!("[ {} = {} ]", actual, value),
+                Operator::NotEqual => format!("[ {} != {} ]", actual, value),
+                Operator::Matches => format!("printf '%s' {} | grep -Eq {}", actual, value),
+                Operator::NotMatches => format!("! printf '%s' {} | grep -Eq {}", actual, value),
+            }
+        }
+        Condition::Shell(test) => test.clone(),
+    }
+}
+
+fn collect_files<'d>(blocks: &'d [CodeBlock], sections: &'d [Section], files: &mut HashMap<usize, &'d str>) {
+    for block in blocks.iter().filter(|block| block.file.is_some()) {
+        files.insert(block.range.start, &block.code);
+    }
+
+    for section in sections.iter() {
+        collect_files(&section.blocks, &section.children, files);
+    }
+}
+
+/// Returns the heredoc lines up to and including the delimiter.
+fn heredoc_body(content: &str) -> String {
+    let mut body = String::from(content);
+    if !body.is_empty() && !body.ends_with('\n') {
+        body.push('\n');
+    }
+    body.push_str(DELIMITER);
+    body.push('\n');
+    body
+}
+
+/// `ARG` names can't contain dots or dashes, such variables are filled in.
+fn is_arg_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+fn arg_value(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:@+".contains(c)) {
+        String::from(value)
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$"))
+    }
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "---\nvars:\n  user: faye\n  greeting: hello world\n  db:\n    host: db1\n---\n\
+                          # Faye\n\n## Packages\n\n```shell\napt-get update\napt-get install -y nginx\n```\n\n\
+                          ```conf {file=/etc/motd, mode=0644}\nWelcome {{ user }}, db is {{ db.host }}\n```\n\n\
+                          ### Debian {when=\"os=debian\"}\n\n```bash {retry=3, backoff=exp}\nuseradd {{ user }}\n```\n";
+
+    fn dockerfile() -> String {
+        let front_matter = crate::front_matter::FrontMatter::split(SOURCE).unwrap().0;
+        let vars = CorgVars::from_front_matter(front_matter.as_ref(), None).unwrap();
+        let document = Document::parse(SOURCE, &placeholders(&vars), None).unwrap();
+        let resolved = Document::parse(SOURCE, &vars, None).unwrap();
+
+        let mut output = String::new();
+        push_dockerfile(&mut output, "faye.Dockerfile", "debian:12", &document, &resolved, &vars);
+        output
+    }
+
+    #[test]
+    fn test_push_dockerfile() {
+        let output = dockerfile();
+
+        assert!(output.starts_with("# syntax=docker/dockerfile:1\n"));
+        assert!(output.contains("FROM debian:12\n\nARG greeting=\"hello world\"\nARG user=faye\n\n# Packages\n"));
+        assert!(!output.contains("ARG db"));
+        assert!(output.contains("RUN <<'CORG_EOF'\n#!/usr/bin/env sh\nset -e\napt-get update\napt-get install -y nginx\nCORG_EOF\n"));
+        assert!(output.contains("COPY --chmod=0644 <<'CORG_EOF' /etc/motd\nWelcome faye, db is db1\nCORG_EOF\n"));
+        assert!(output.contains("\n# Debian\nRUN <<'CORG_EOF'\n#!/usr/bin/env bash\nset -e\n\
+                                 if [ \"$(. /etc/os-release && echo \"$ID\")\" = 'debian' ]; then\n"));
+        assert!(output.contains("\nuseradd ${user}\nCORG_BLOCK_EOF\n"));
+        assert!(output.contains("until bash -ec \"$corg_block\"; do\n\t[ \"$corg_attempt\" -lt 3 ] || exit 1\n"));
+        assert!(output.contains("\tcorg_delay=$((corg_delay * 2))\ndone\nelse\n\techo \"Skipping packages#3\"\nfi\nCORG_EOF\n"));
+    }
+
+    #[test]
+    fn test_condition_test() {
+        let condition = Condition::parse("host!~^db").unwrap();
+
+        assert_eq!(condition_test(&condition), "! printf '%s' \"$(hostname)\" | grep -Eq '^db'");
+    }
+}
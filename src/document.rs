@@ -0,0 +1,867 @@
+use crate::attributes::{split_trailing, CorgAttributes, RetryPolicy};
+use crate::condition::Condition;
+use crate::corg_error::CorgError;
+use crate::front_matter::FrontMatter;
+use crate::location::{LineIndex, Location};
+use crate::secrets;
+use crate::vars::CorgVars;
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use serde_yaml::Mapping;
+use std::ops::Range;
+
+/// Attributes a code fence may carry, e.g. ```` ```shell {retry=5} ````
+const CODE_BLOCK_ATTRIBUTES: &[&str] = &["retry", "backoff", "delay", "name", "parallel", "when", "creates", "file", "mode", "sudo"];
+
+/// Attributes a heading may carry, e.g. `## Install Nix {when="os=nixos"}`
+const HEADING_ATTRIBUTES: &[&str] = &["when", "needs", "sudo"];
+
+/// User `{sudo}` without a value runs as.
+pub const DEFAULT_SUDO_USER: &str = "root";
+
+/// Group name of blocks marked with a bare `{parallel}`.
+const DEFAULT_PARALLEL_GROUP: &str = "parallel";
+
+/// A runbook read from markdown. It's built in one pass over the parser events
+/// and rendered by the back ends, e.g. `shell`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    /// The first level-1 heading.
+    pub title: Option<Heading>,
+    /// Values of the front matter.
+    pub meta: Mapping,
+    /// Prose and code before the first section.
+    pub prose: Vec<Prose>,
+    pub blocks: Vec<CodeBlock>,
+    pub sections: Vec<Section>,
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heading {
+    pub level: i32,
+    /// The heading text without its attributes.
+    pub text: String,
+    /// The section is skipped when the condition doesn't hold.
+    pub when: Option<Condition>,
+    /// Slugs of the steps which have to run before this one, from
+    /// `{needs="install-nix, configure"}`.
+    pub needs: Vec<String>,
+    /// User the code blocks of the section run as, from `{sudo}` or
+    /// `{sudo=postgres}`.
+    pub sudo: Option<String>,
+    pub range: Range<usize>,
+    pub location: Location,
+}
+
+/// A heading and everything up to the next heading of the same or a higher level,
+/// deeper headings become its children.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub heading: Heading,
+    pub slug: String,
+    /// From the start of the heading to the start of the next heading of the same
+    /// or a higher level (or the end of the document).
+    pub range: Range<usize>,
+    pub prose: Vec<Prose>,
+    pub blocks: Vec<CodeBlock>,
+    pub children: Vec<Section>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProseKind {
+    Paragraph,
+    Item,
+    Quote,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prose {
+    pub kind: ProseKind,
+    pub text: String,
+    pub range: Range<usize>,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    pub lang: String,
+    pub attributes: CorgAttributes,
+    /// Name used in log messages about the block, `name=...` or `<step slug>#<n>`.
+    pub name: String,
+    pub code: String,
+    pub retry: Option<RetryPolicy>,
+    /// Consecutive blocks of the same parallel group run as background jobs.
+    pub parallel: Option<String>,
+    /// The block is skipped when the condition doesn't hold.
+    pub when: Option<Condition>,
+    /// The block is skipped when this path already exists, from `{creates=/nix}`.
+    pub creates: Option<String>,
+    /// The code is written to this path instead of being run, from
+    /// `{file=/etc/nginx/nginx.conf}`.
+    pub file: Option<String>,
+    /// Octal permissions of the file, from `{mode=0644}`.
+    pub mode: Option<String>,
+    /// User the block runs as through sudo, from its own `{sudo}` or the one of
+    /// the sections it's in.
+    pub sudo: Option<String>,
+    pub range: Range<usize>,
+    pub location: Location,
+}
+
+/// A link in the prose or headings of the document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub text: String,
+    pub destination: String,
+    pub title: String,
+    /// Slug of the deepest section the link is in.
+    pub section: Option<String>,
+    pub location: Location,
+}
+
+/// Prose or code of a section, in document order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Content<'a> {
+    Prose(&'a Prose),
+    Block(&'a CodeBlock),
+}
+
+impl Document {
+    /// Parses a markdown document with optional front matter. Refuses documents
+    /// containing secrets which haven't been allow-listed, `{{ var }}` placeholders
+    /// are filled in from the front matter (overlaid with the given environment)
+    /// and then `vars`.
+    pub fn parse(source: &str, vars: &CorgVars, environment: Option<&str>) -> Result<Document, CorgError> {
+        // The front matter is configuration, not part of the document.
+        let (front_matter, body_offset) = FrontMatter::split(source)?;
+
+        let findings = secrets::scan(source, front_matter.as_ref(), body_offset);
+        if !findings.is_empty() {
+            return Err(CorgError::Secrets(findings));
+        }
+
+        // Given variables win over the ones in the front matter.
+        let mut document_vars = CorgVars::from_front_matter(front_matter.as_ref(), environment)?;
+        document_vars.extend(vars);
+
+        let line_index = LineIndex::new(source);
+        let events = resolve_events(source, &line_index, &document_vars, body_offset)?;
+        let meta = front_matter.map(|front_matter| front_matter.values).unwrap_or_default();
+
+        Document::from_events(events, &line_index, meta)
+    }
+
+    /// Builds the document from parser events paired with their offsets in the
+    /// markdown source, `line_index` is used to locate invalid attributes.
+    pub fn from_events<'a, I>(events: I, line_index: &LineIndex, meta: Mapping) -> Result<Document, CorgError>
+    where
+        I: IntoIterator<Item = (Event<'a>, Range<usize>)>,
+    {
+        let mut builder = DocumentBuilder::new(line_index, meta);
+
+        for (event, range) in events {
+            builder.push_event(event, range)?;
+        }
+
+        builder.finish()
+    }
+
+    pub fn content(&self) -> Vec<Content<'_>> {
+        content(&self.prose, &self.blocks)
+    }
+
+    /// Returns the step with the given slug.
+    pub fn step(&self, slug: &str) -> Option<&Section> {
+        self.steps().into_iter().find(|step| step.slug == slug)
+    }
+
+    /// Runs the code blocks which don't say who they run as as `user`, e.g. for
+    /// `--become`.
+    pub fn become_user(&mut self, user: &str) {
+        fn become_section(section: &mut Section, user: &str) {
+            for block in section.blocks.iter_mut().filter(|block| block.sudo.is_none()) {
+                block.sudo = Some(String::from(user));
+            }
+            for child in section.children.iter_mut() {
+                become_section(child, user);
+            }
+        }
+
+        for block in self.blocks.iter_mut().filter(|block| block.sudo.is_none()) {
+            block.sudo = Some(String::from(user));
+        }
+        for section in self.sections.iter_mut() {
+            become_section(section, user);
+        }
+    }
+
+    /// Level-2 sections of the whole document, the steps a runbook runs in order.
+    pub fn steps(&self) -> Vec<&Section> {
+        let mut steps = vec![];
+
+        for section in self.sections.iter() {
+            section.collect_steps(&mut steps);
+        }
+
+        steps
+    }
+}
+
+impl Section {
+    /// Level-2 sections are the steps of the runbook, e.g. the functions of a
+    /// shell script.
+    pub fn is_step(&self) -> bool {
+        self.heading.level == 2
+    }
+
+    pub fn content(&self) -> Vec<Content<'_>> {
+        content(&self.prose, &self.blocks)
+    }
+
+    fn collect_steps<'a>(&'a self, steps: &mut Vec<&'a Section>) {
+        if self.is_step() {
+            steps.push(self);
+        }
+
+        for child in self.children.iter() {
+            child.collect_steps(steps);
+        }
+    }
+}
+
+/// Returns a name for the heading which can be used as a shell function name.
+pub fn slug(text: &str) -> String {
+    text.to_lowercase().replace(" ", "-")
+}
+
+fn content<'a>(prose: &'a [Prose], blocks: &'a [CodeBlock]) -> Vec<Content<'a>> {
+    let mut content: Vec<Content> = prose
+        .iter()
+        .map(Content::Prose)
+        .chain(blocks.iter().map(Content::Block))
+        .collect();
+
+    content.sort_by_key(|content| match content {
+        Content::Prose(prose) => prose.range.start,
+        Content::Block(block) => block.range.start,
+    });
+
+    content
+}
+
+/// Parses the markdown body and fills in the `{{ var }}` placeholders of text
+/// and code.
+fn resolve_events<'a>(
+    source: &'a str,
+    line_index: &LineIndex,
+    vars: &CorgVars,
+    body_offset: usize,
+) -> Result<Vec<(Event<'a>, Range<usize>)>, CorgError> {
+    let body = &source[body_offset..];
+    let mut events = vec![];
+
+    for (event, range) in Parser::new_ext(body, Options::empty()).into_offset_iter() {
+        let range = range.start + body_offset..range.end + body_offset;
+        let substitute = |text: &str| {
+            vars.substitute(text).map_err(|undefined| {
+                // Point at the placeholder itself rather than the start of the text.
+                let offset = source[range.start..]
+                    .find(&undefined.placeholder)
+                    .map(|found| range.start + found)
+                    .unwrap_or(range.start);
+
+                CorgError::UndefinedVariable {
+                    location: line_index.location(offset),
+                    name: undefined.name,
+                }
+            })
+        };
+
+        let event = match event {
+            Event::Start(Tag::Image(..)) | Event::End(Tag::Image(..)) => continue,
+            Event::Text(text) => Event::Text(substitute(&text)?.into()),
+            Event::Code(text) => Event::Code(substitute(&text)?.into()),
+            event => event,
+        };
+
+        events.push((event, range));
+    }
+
+    Ok(events)
+}
+
+/// Parses the condition of a `{when="..."}` attribute.
+fn when_condition(attributes: &CorgAttributes) -> Result<Option<Condition>, String> {
+    match attributes.get("when") {
+        Some(condition) => Ok(Some(Condition::parse(condition)?)),
+        None if attributes.has("when") => Err(String::from("missing value for 'when'")),
+        None => Ok(None),
+    }
+}
+
+/// Parses the path and permissions of a `{file=..., mode=...}` block, files are
+/// written rather than run so they can't be retried or run in the background.
+fn file_attributes(attributes: &CorgAttributes) -> Result<(Option<String>, Option<String>), String> {
+    let file = match (attributes.has("file"), attributes.get("file")) {
+        (true, Some(path)) => String::from(path),
+        (true, None) => return Err(String::from("missing value for 'file'")),
+        _ if attributes.has("mode") => return Err(String::from("mode only applies to blocks with file")),
+        _ => return Ok((None, None)),
+    };
+
+    if attributes.has("retry") || attributes.has("parallel") {
+        return Err(String::from("file blocks can't be retried or run in parallel"));
+    }
+
+    let mode = match attributes.get("mode") {
+        Some(mode) if (3..=4).contains(&mode.len()) && mode.chars().all(|c| ('0'..='7').contains(&c)) => {
+            Some(String::from(mode))
+        }
+        Some(mode) => return Err(format!("mode must be octal permissions like 0644, got '{}'", mode)),
+        None if attributes.has("mode") => return Err(String::from("missing value for 'mode'")),
+        None => None,
+    };
+
+    Ok((Some(file), mode))
+}
+
+/// Parses the user of a `{sudo}` attribute, root when it has no value.
+fn sudo_user(attributes: &CorgAttributes) -> Result<Option<String>, String> {
+    match attributes.get("sudo") {
+        Some(user) if is_user_name(user) => Ok(Some(String::from(user))),
+        Some(user) => Err(format!("sudo needs a user name, got '{}'", user)),
+        None if attributes.has("sudo") => Ok(Some(String::from(DEFAULT_SUDO_USER))),
+        None => Ok(None),
+    }
+}
+
+/// Whether the text can be passed to `sudo -u` as it is.
+pub fn is_user_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Parses the steps of a `{needs="..."}` attribute, they can be written as
+/// slugs or heading text.
+fn needs(attributes: &CorgAttributes, level: i32) -> Result<Vec<String>, String> {
+    let needs = match attributes.get("needs") {
+        Some(needs) => needs,
+        None if attributes.has("needs") => return Err(String::from("missing value for 'needs'")),
+        None => return Ok(vec![]),
+    };
+
+    if level != 2 {
+        return Err(String::from("needs only applies to level-2 headings"));
+    }
+
+    Ok(needs
+        .split(',')
+        .map(str::trim)
+        .filter(|need| !need.is_empty())
+        .map(slug)
+        .collect())
+}
+
+/// Makes sure every step a step needs exists, and that steps don't need each
+/// other in a circle.
+fn check_needs(document: &Document) -> Result<(), CorgError> {
+    let steps = document.steps();
+
+    for step in steps.iter() {
+        let heading = &step.heading;
+        let invalid_attribute = |message| CorgError::InvalidAttribute {
+            location: heading.location,
+            message,
+        };
+
+        for need in heading.needs.iter() {
+            if document.step(need).is_none() {
+                return Err(invalid_attribute(format!("needs unknown step '{}'", need)));
+            }
+        }
+
+        // Walk the needs depth first looking for a way back to the step.
+        let mut path = vec![step.slug.as_str()];
+        let mut pending: Vec<(usize, &str)> = heading.needs.iter().map(|need| (1, need.as_str())).collect();
+        while let Some((depth, need)) = pending.pop() {
+            path.truncate(depth);
+            path.push(need);
+
+            if need == step.slug {
+                return Err(invalid_attribute(format!("steps need each other: {}", path.join(" -> "))));
+            }
+
+            if let Some(needed) = document.step(need) {
+                if path[..depth].contains(&need) {
+                    continue;
+                }
+                pending.extend(needed.heading.needs.iter().map(|next| (depth + 1, next.as_str())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps track of what's being read while the events stream by.
+struct DocumentBuilder<'l> {
+    line_index: &'l LineIndex,
+    document: Document,
+    /// Sections which haven't been ended by a heading of the same or a higher
+    /// level yet, the deepest last.
+    open_sections: Vec<Section>,
+    /// Level, text and range of the heading being read.
+    heading: Option<(i32, String, Range<usize>)>,
+    prose: Option<Prose>,
+    /// Info string, code and range of the code block being read.
+    code: Option<(String, String, Range<usize>)>,
+    link: Option<Link>,
+    /// Where the last event ends, the end of the last section.
+    end_offset: usize,
+    /// Slug of the current step, blocks are named after it and counted per step.
+    step_slug: Option<String>,
+    step_block_count: usize,
+}
+
+impl<'l> DocumentBuilder<'l> {
+    fn new(line_index: &'l LineIndex, meta: Mapping) -> DocumentBuilder<'l> {
+        DocumentBuilder {
+            line_index,
+            document: Document {
+                title: None,
+                meta,
+                prose: vec![],
+                blocks: vec![],
+                sections: vec![],
+                links: vec![],
+            },
+            open_sections: vec![],
+            heading: None,
+            prose: None,
+            code: None,
+            link: None,
+            end_offset: 0,
+            step_slug: None,
+            step_block_count: 0,
+        }
+    }
+
+    fn push_event(&mut self, event: Event, range: Range<usize>) -> Result<(), CorgError> {
+        self.end_offset = self.end_offset.max(range.end);
+
+        match event {
+            Event::Start(Tag::Header(level)) => {
+                self.heading = Some((level, String::new(), range));
+            }
+            Event::End(Tag::Header(_)) => self.end_heading()?,
+            Event::Start(Tag::CodeBlock(info)) => {
+                self.code = Some((info.to_string(), String::new(), range));
+            }
+            Event::End(Tag::CodeBlock(_)) => self.end_code_block()?,
+            Event::Start(Tag::Paragraph) => self.start_prose(ProseKind::Paragraph, range),
+            Event::Start(Tag::Item) => self.start_prose(ProseKind::Item, range),
+            Event::Start(Tag::BlockQuote) => self.start_prose(ProseKind::Quote, range),
+            Event::End(Tag::Paragraph) => self.end_prose(ProseKind::Paragraph),
+            Event::End(Tag::Item) => self.end_prose(ProseKind::Item),
+            Event::End(Tag::BlockQuote) => self.end_prose(ProseKind::Quote),
+            Event::Start(Tag::Link(_, destination, title)) => {
+                self.link = Some(Link {
+                    text: String::new(),
+                    destination: destination.to_string(),
+                    title: title.to_string(),
+                    section: self.open_sections.last().map(|section| section.slug.clone()),
+                    location: self.line_index.location(range.start),
+                });
+            }
+            Event::End(Tag::Link(..)) => {
+                if let Some(link) = self.link.take() {
+                    self.document.links.push(link);
+                }
+            }
+            Event::Text(text) => self.push_text(&text),
+            Event::Code(text) => {
+                if self.heading.is_some() {
+                    self.push_text(&text)
+                } else {
+                    self.push_text(&format!("`{}`", text))
+                }
+            }
+            Event::SoftBreak | Event::HardBreak => self.push_text("\n"),
+            // Tables, HTML, rules and footnotes aren't part of a runbook.
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if let Some(link) = &mut self.link {
+            link.text.push_str(text);
+        }
+
+        if let Some((_, heading_text, _)) = &mut self.heading {
+            heading_text.push_str(text);
+        } else if let Some((_, code, _)) = &mut self.code {
+            code.push_str(text);
+        } else if let Some(prose) = &mut self.prose {
+            prose.text.push_str(text);
+        }
+    }
+
+    /// Paragraphs inside list items and quotes are part of them, a nested list
+    /// item ends the one it's nested in.
+    fn start_prose(&mut self, kind: ProseKind, range: Range<usize>) {
+        match &mut self.prose {
+            Some(prose) if kind == ProseKind::Item && prose.kind == ProseKind::Item => {
+                self.end_prose(ProseKind::Item);
+            }
+            Some(prose) => {
+                if !prose.text.is_empty() && !prose.text.ends_with('\n') {
+                    prose.text.push('\n');
+                }
+                return;
+            }
+            None => (),
+        }
+
+        self.prose = Some(Prose {
+            kind,
+            text: String::new(),
+            location: self.line_index.location(range.start),
+            range,
+        });
+    }
+
+    fn end_prose(&mut self, kind: ProseKind) {
+        match self.prose.take() {
+            Some(prose) if prose.kind == kind => {
+                if !prose.text.trim().is_empty() {
+                    match self.open_sections.last_mut() {
+                        Some(section) => section.prose.push(prose),
+                        None => self.document.prose.push(prose),
+                    }
+                }
+            }
+            prose => self.prose = prose,
+        }
+    }
+
+    fn end_heading(&mut self) -> Result<(), CorgError> {
+        let (level, heading_text, range) = match self.heading.take() {
+            Some(heading) => heading,
+            None => return Ok(()),
+        };
+        let location = self.line_index.location(range.start);
+        let invalid_attribute = |message| CorgError::InvalidAttribute { location, message };
+
+        let (text, attributes) = split_trailing(&heading_text);
+        let attributes = match attributes {
+            Some(attributes) => CorgAttributes::parse(attributes).map_err(invalid_attribute)?,
+            None => CorgAttributes::default(),
+        };
+        let when = attributes
+            .check_keys(HEADING_ATTRIBUTES)
+            .and_then(|_| when_condition(&attributes))
+            .map_err(invalid_attribute)?;
+        let needs = needs(&attributes, level).map_err(invalid_attribute)?;
+        let sudo = sudo_user(&attributes).map_err(invalid_attribute)?;
+
+        let heading = Heading {
+            level,
+            text: String::from(text.trim()),
+            when,
+            needs,
+            sudo,
+            range,
+            location,
+        };
+
+        // The first level-1 heading names the document.
+        let is_title = level == 1
+            && self.document.title.is_none()
+            && self.document.sections.is_empty()
+            && self.open_sections.is_empty();
+
+        // Steps count their blocks from scratch.
+        if level <= 2 {
+            self.step_slug = None;
+            self.step_block_count = 0;
+        }
+
+        if is_title {
+            self.document.title = Some(heading);
+            return Ok(());
+        }
+
+        self.close_sections(level, heading.range.start);
+
+        let slug = slug(&heading.text);
+        if level == 2 {
+            self.step_slug = Some(slug.clone());
+        }
+
+        self.open_sections.push(Section {
+            range: heading.range.clone(),
+            heading,
+            slug,
+            prose: vec![],
+            blocks: vec![],
+            children: vec![],
+        });
+
+        Ok(())
+    }
+
+    fn end_code_block(&mut self) -> Result<(), CorgError> {
+        let (info, code, range) = match self.code.take() {
+            Some(code) => code,
+            None => return Ok(()),
+        };
+        let location = self.line_index.location(range.start);
+        let invalid_attribute = |message| CorgError::InvalidAttribute { location, message };
+
+        let (lang, attributes) = CorgAttributes::from_info(&info).map_err(invalid_attribute)?;
+        attributes.check_keys(CODE_BLOCK_ATTRIBUTES).map_err(invalid_attribute)?;
+
+        let retry = RetryPolicy::from_attributes(&attributes).map_err(invalid_attribute)?;
+        let when = when_condition(&attributes).map_err(invalid_attribute)?;
+        let parallel = match (attributes.has("parallel"), attributes.get("parallel")) {
+            (true, Some(group)) => Some(String::from(group)),
+            (true, None) => Some(String::from(DEFAULT_PARALLEL_GROUP)),
+            _ => None,
+        };
+        let creates = match (attributes.has("creates"), attributes.get("creates")) {
+            (true, Some(path)) => Some(String::from(path)),
+            (true, None) => return Err(invalid_attribute(String::from("missing value for 'creates'"))),
+            _ => None,
+        };
+        let (file, mode) = file_attributes(&attributes).map_err(invalid_attribute)?;
+        // Blocks run as the user of the deepest section saying who they run as.
+        let sudo = match sudo_user(&attributes).map_err(invalid_attribute)? {
+            Some(user) => Some(user),
+            None => self
+                .open_sections
+                .iter()
+                .rev()
+                .map(|section| &section.heading)
+                .chain(self.document.title.iter())
+                .find_map(|heading| heading.sudo.clone()),
+        };
+
+        self.step_block_count += 1;
+        let name = match attributes.get("name") {
+            Some(name) => String::from(name),
+            None => {
+                let step = self.step_slug.as_deref().unwrap_or("block");
+                format!("{}#{}", step, self.step_block_count)
+            }
+        };
+
+        let block = CodeBlock {
+            lang,
+            attributes,
+            name,
+            code,
+            retry,
+            parallel,
+            when,
+            creates,
+            file,
+            mode,
+            sudo,
+            range,
+            location,
+        };
+
+        match self.open_sections.last_mut() {
+            Some(section) => section.blocks.push(block),
+            None => self.document.blocks.push(block),
+        }
+
+        Ok(())
+    }
+
+    /// Ends the open sections of the given level and deeper at the given offset,
+    /// attaching each to its parent.
+    fn close_sections(&mut self, level: i32, end: usize) {
+        while let Some(mut section) = self.open_sections.pop() {
+            if section.heading.level < level {
+                self.open_sections.push(section);
+                break;
+            }
+
+            section.range.end = end;
+
+            match self.open_sections.last_mut() {
+                Some(parent) => parent.children.push(section),
+                None => self.document.sections.push(section),
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<Document, CorgError> {
+        self.close_sections(i32::MIN, self.end_offset);
+        check_needs(&self.document)?;
+
+        Ok(self.document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(source: &str) -> Result<Document, CorgError> {
+        let events = Parser::new_ext(source, Options::empty()).into_offset_iter();
+        Document::from_events(events, &LineIndex::new(source), Mapping::new())
+    }
+
+    #[test]
+    fn test_slug() {
+        assert_eq!(slug("Holla Cheese Burgers"), "holla-cheese-burgers".to_string());
+    }
+
+    #[test]
+    fn test_from_events() {
+        let source = "# Faye\n\nIntro.\n\n## Install Nix {when=\"os=nixos\"}\n\n- one\n- two `x`\n\n\
+                      ```shell {retry=3}\ncurl nix\n```\n\n### Check\n\n```shell\nnix --version\n```\n\n\
+                      ## Deploy\n\n> Careful.\n";
+        let document = document(source).unwrap();
+
+        assert_eq!(document.title.as_ref().map(|title| title.text.as_str()), Some("Faye"));
+        assert_eq!(document.prose[0].text, "Intro.");
+
+        let slugs: Vec<&str> = document.steps().iter().map(|step| step.slug.as_str()).collect();
+        assert_eq!(slugs, vec!["install-nix", "deploy"]);
+
+        let install = &document.sections[0];
+        assert_eq!(install.heading.text, "Install Nix");
+        assert_eq!(install.heading.location, Location { line: 5, column: 1 });
+        assert!(install.heading.when.is_some());
+
+        let prose: Vec<&str> = install.prose.iter().map(|prose| prose.text.as_str()).collect();
+        assert_eq!(prose, vec!["one", "two `x`"]);
+        assert_eq!(install.blocks[0].name, "install-nix#1");
+        assert_eq!(install.blocks[0].code, "curl nix\n");
+        assert_eq!(install.blocks[0].retry.as_ref().map(|retry| retry.attempts), Some(3));
+
+        let check = &install.children[0];
+        assert_eq!(check.slug, "check");
+        assert_eq!(check.blocks[0].name, "install-nix#2");
+
+        assert_eq!(document.sections[1].prose[0].kind, ProseKind::Quote);
+        assert_eq!(&source[install.range.clone()][..14], "## Install Nix");
+        assert!(source[install.range.clone()].ends_with("nix --version\n```\n\n"));
+    }
+
+    #[test]
+    fn test_links() {
+        let document = document("See [the manual](https://nixos.org/manual \"Nix\").\n\n## Deploy\n\n- [faye](faye.md)\n").unwrap();
+
+        let links: Vec<(&str, &str, Option<&str>)> = document
+            .links
+            .iter()
+            .map(|link| (link.text.as_str(), link.destination.as_str(), link.section.as_deref()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![("the manual", "https://nixos.org/manual", None), ("faye", "faye.md", Some("deploy"))]
+        );
+        assert_eq!(document.links[0].title, "Nix");
+        assert_eq!(document.links[1].location.line, 5);
+    }
+
+    #[test]
+    fn test_content_order() {
+        let document = document("## Deploy\n\nFirst.\n\n```shell\necho 1\n```\n\nThen.\n").unwrap();
+
+        let kinds: Vec<bool> = document.sections[0]
+            .content()
+            .iter()
+            .map(|content| match content {
+                Content::Prose(_) => true,
+                Content::Block(_) => false,
+            })
+            .collect();
+        assert_eq!(kinds, vec![true, false, true]);
+    }
+
+    #[test]
+    fn test_needs() {
+        let document = document("## Install Nix\n\n## Deploy {needs=\"install-nix, Configure\"}\n\n## Configure\n").unwrap();
+
+        assert_eq!(document.sections[1].heading.needs, vec!["install-nix", "configure"]);
+    }
+
+    #[test]
+    fn test_needs_errors() {
+        let invalid = &[
+            "## Deploy {needs=install}\n",
+            "## Deploy {needs=deploy}\n",
+            "## A {needs=b}\n\n## B {needs=c}\n\n## C {needs=a}\n",
+            "## Deploy\n\n### Check {needs=deploy}\n",
+        ];
+
+        for source in invalid {
+            assert!(document(source).is_err(), "{}", source);
+        }
+
+        match document("## A {needs=b}\n\n## B {needs=a}\n") {
+            Err(CorgError::InvalidAttribute { location, message }) => {
+                assert_eq!(location.line, 1);
+                assert_eq!(message, "steps need each other: a -> b -> a");
+            }
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_attribute() {
+        match document("## Deploy\n\n```shell {when=\"distro=debian\"}\necho 1\n```\n") {
+            Err(CorgError::InvalidAttribute { location, message }) => {
+                assert_eq!(location.line, 3);
+                assert!(message.contains("distro"));
+            }
+            other => panic!("expected an invalid attribute, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_block() {
+        let parsed = document("## Nginx\n\n```nginx {file=/etc/nginx/nginx.conf, mode=0644}\nworker_processes 2;\n```\n").unwrap();
+        let block = &parsed.sections[0].blocks[0];
+
+        assert_eq!(block.file.as_deref(), Some("/etc/nginx/nginx.conf"));
+        assert_eq!(block.mode.as_deref(), Some("0644"));
+
+        for info in &["shell {mode=0644}", "shell {file=/a, mode=rw}", "shell {file=/a, retry=3}"] {
+            let source = format!("```{}\necho 1\n```\n", info);
+            assert!(document(&source).is_err(), "{}", info);
+        }
+    }
+
+    #[test]
+    fn test_sudo() {
+        let source = "## Database {sudo=postgres}\n\n```shell\npsql\n```\n\n### Packages\n\n```shell {sudo}\napt-get update\n```\n\n\
+                      ```shell\ncreatedb faye\n```\n\n## Deploy\n\n```shell\nmake\n```\n";
+        let mut parsed = document(source).unwrap();
+        let users = |document: &Document| -> Vec<Option<String>> {
+            let database = &document.sections[0];
+            vec![
+                database.blocks[0].sudo.clone(),
+                database.children[0].blocks[0].sudo.clone(),
+                database.children[0].blocks[1].sudo.clone(),
+                document.sections[1].blocks[0].sudo.clone(),
+            ]
+        };
+
+        let postgres = Some(String::from("postgres"));
+        assert_eq!(users(&parsed), vec![postgres.clone(), Some(String::from("root")), postgres.clone(), None]);
+
+        parsed.become_user("deploy");
+        assert_eq!(users(&parsed)[3].as_deref(), Some("deploy"));
+        assert_eq!(users(&parsed)[0], postgres);
+
+        assert!(document("```shell {sudo=\"root; rm\"}\necho 1\n```\n").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+/// Environment variable with the file `corg_log` appends every line it logs to
+/// as JSON, the side channel of a run next to its colored output.
+pub const LOG_FILE_ENV_VAR: &str = "CORG_LOG_FILE";
+
+/// Level of the lines a script wrote to stdout or stderr without the logger.
+pub const STDOUT_LEVEL: &str = "stdout";
+pub const STDERR_LEVEL: &str = "stderr";
+
+/// A line of a run's structured log, either logged by the logger script or
+/// written to stdout or stderr as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEvent {
+    pub timestamp: DateTime<Utc>,
+    /// The level of the logger, e.g. `info` or `step`, or `stdout` and `stderr`.
+    pub level: String,
+    /// Slug of the step the line came from, none before the first step.
+    pub section: Option<String>,
+    pub host: String,
+    pub message: String,
+}
+
+impl LogEvent {
+    /// Renders the event as a line of JSON, its keys in the order the logger
+    /// script writes them.
+    pub fn to_json_line(&self) -> String {
+        format!(
+            "{{\"timestamp\":{},\"level\":{},\"section\":{},\"host\":{},\"message\":{}}}",
+            json!(self.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            json!(self.level),
+            json!(self.section),
+            json!(self.host),
+            json!(self.message)
+        )
+    }
+
+    /// Reads an event the logger script wrote, its timestamps carry the offset of
+    /// the machine it ran on, e.g. `2026-10-18T11:30:00.250+0200`.
+    pub fn from_json(value: &Value) -> Option<LogEvent> {
+        let text = |key: &str| value.get(key).and_then(Value::as_str);
+
+        let timestamp = text("timestamp")?;
+        let timestamp = DateTime::parse_from_rfc3339(timestamp)
+            .or_else(|_| DateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f%z"))
+            .ok()?;
+
+        Some(LogEvent {
+            timestamp: timestamp.with_timezone(&Utc),
+            level: String::from(text("level")?),
+            section: text("section").filter(|section| !section.is_empty()).map(String::from),
+            host: String::from(text("host").unwrap_or_default()),
+            message: String::from(text("message").unwrap_or_default()),
+        })
+    }
+}
+
+/// Reads the JSON lines the logger script wrote to `CORG_LOG_FILE`, lines which
+/// aren't events (e.g. cut off when the script was killed) are left out.
+pub fn parse_events(text: &str) -> Vec<LogEvent> {
+    text.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter_map(|value: Value| LogEvent::from_json(&value))
+        .collect()
+}
+
+/// Renders the events as JSON lines, one event per line.
+pub fn to_jsonl(events: &[LogEvent]) -> String {
+    events.iter().map(|event| format!("{}\n", event.to_json_line())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events() {
+        let text = "{\"timestamp\":\"2026-10-18T11:30:00.250+0200\",\"level\":\"info\",\"section\":\"\",\"host\":\"faye\",\"message\":\"say \\\"hi\\\"\"}\n\
+                    {\"timestamp\":\"2026-10-18T09:30:01.000Z\",\"level\":\"step\",\"section\":\"deploy\",\"host\":\"faye\",\"message\":\"deploy\"}\n\
+                    {\"timestamp\":\"2026-10-18T09:30";
+        let events = parse_events(text);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].section, None);
+        assert_eq!(events[0].message, "say \"hi\"");
+        assert_eq!(events[1].section.as_deref(), Some("deploy"));
+        assert_eq!(
+            to_jsonl(&events[..1]),
+            "{\"timestamp\":\"2026-10-18T09:30:00.250Z\",\"level\":\"info\",\"section\":null,\"host\":\"faye\",\"message\":\"say \\\"hi\\\"\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_events_control_characters() {
+        let line = "{\"timestamp\":\"2026-10-18T09:30:00.250Z\",\"level\":\"info\",\"section\":null,\"host\":\"faye\",\"message\":\"bell\\u0007 back\\u0008 feed\\u000c escape\\u001b\"}\n";
+        let events = parse_events(line);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message, "bell\u{7} back\u{8} feed\u{c} escape\u{1b}");
+        assert_eq!(parse_events(&to_jsonl(&events)), events);
+    }
+
+    #[test]
+    #[ignore = "needs zsh"]
+    fn test_logger_escapes_control_characters() {
+        let dir = std::env::temp_dir().join(format!("corg-events-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.jsonl");
+        let script = format!(
+            "{}\ncorg_log_event info $'bell\\a back\\b feed\\f escape\\e tab\\t'\n",
+            String::from_utf8_lossy(crate::CORG_LOGGER_SHELL_SCRIPT)
+        );
+
+        let status = std::process::Command::new("zsh")
+            .args(["-c", &script])
+            .env(LOG_FILE_ENV_VAR, &path)
+            .status()
+            .unwrap();
+        let events = parse_events(&std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(status.success());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message, "bell\u{7} back\u{8} feed\u{c} escape\u{1b} tab\t");
+    }
+}
@@ -0,0 +1,357 @@
+use crate::corg_error::CorgError;
+use crate::events::LogEvent;
+use crate::history::{self, RunLog};
+use crate::runner::{self, Stream};
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a run is rolled out across hosts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanOut {
+    /// Hosts running at the same time.
+    pub parallel: usize,
+    /// Stops starting hosts after the first failure.
+    pub fail_fast: bool,
+    /// Stops starting hosts once more than this percentage of all hosts failed.
+    pub max_failures: Option<u8>,
+    /// Hosts which run one at a time before the others, when one of them fails
+    /// the rest don't run.
+    pub canaries: usize,
+}
+
+impl Default for FanOut {
+    fn default() -> Self {
+        FanOut {
+            parallel: 1,
+            fail_fast: false,
+            max_failures: None,
+            canaries: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunStatus {
+    Ok,
+    /// The exit code the script failed with.
+    Failed(i32),
+    /// The script couldn't be started, e.g. ssh is missing.
+    Error,
+    /// Never started because the rollout stopped.
+    Skipped,
+}
+
+/// How the run on one host went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostRun {
+    pub host: String,
+    pub status: RunStatus,
+    /// When the run on the host started, none if it was skipped.
+    pub started_at: Option<DateTime<Utc>>,
+    pub duration: Duration,
+    /// Slugs of the steps the script started, the last one is the one it failed in.
+    pub steps: Vec<String>,
+    /// Slugs of the steps the script finished.
+    pub finished: Vec<String>,
+    /// Output of the host, without colors.
+    pub log_path: PathBuf,
+}
+
+impl HostRun {
+    pub fn failed(&self) -> bool {
+        self.status != RunStatus::Ok
+    }
+}
+
+/// Runs `run` for every host, at most `fan_out.parallel` at a time. The output of
+/// each host goes to `<log_dir>/<host>.log` and to `on_line`, which is called on
+/// the calling thread as lines come in. The events of the logger `run` returns
+/// are merged with the output into `<log_dir>/<host>.jsonl`. Hosts which already
+/// started finish when the rollout stops, the remaining ones are skipped.
+pub fn fan_out<R, L>(hosts: &[String], fan_out: &FanOut, log_dir: &Path, run: R, mut on_line: L) -> Result<Vec<HostRun>, CorgError>
+where
+    R: Fn(usize, &mut dyn FnMut(Stream, &str)) -> Result<(ExitStatus, Vec<LogEvent>), CorgError> + Sync,
+    L: FnMut(&str, Stream, &str),
+{
+    fs::create_dir_all(log_dir)?;
+    // Installed once for the rollout, the hosts running at once all get interrupts.
+    let _interrupts = runner::forward_interrupts();
+
+    let rollout = Rollout {
+        hosts,
+        fan_out,
+        log_dir,
+        runs: Mutex::new(vec![None; hosts.len()]),
+        failures: AtomicUsize::new(0),
+        stopped: AtomicBool::new(false),
+    };
+
+    let canaries = fan_out.canaries.min(hosts.len());
+    let waves = [(0..canaries, 1), (canaries..hosts.len(), fan_out.parallel.max(1))];
+
+    for (index, (range, parallel)) in waves.iter().cloned().enumerate() {
+        rollout.run_wave(range.collect(), parallel, &run, &mut on_line);
+
+        // A failed canary stops the rollout.
+        if index == 0 && rollout.failures.load(Ordering::SeqCst) > 0 {
+            rollout.stopped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let runs = rollout.runs.into_inner().unwrap_or_else(|err| err.into_inner());
+    let runs = runs
+        .into_iter()
+        .enumerate()
+        .map(|(index, run)| {
+            run.unwrap_or_else(|| HostRun {
+                host: hosts[index].clone(),
+                status: RunStatus::Skipped,
+                started_at: None,
+                duration: Duration::default(),
+                steps: vec![],
+                finished: vec![],
+                log_path: log_dir.join(log_file_name(&hosts[index])),
+            })
+        })
+        .collect();
+
+    Ok(runs)
+}
+
+/// Renders a table of the runs, one row per host.
+pub fn summary(runs: &[HostRun]) -> String {
+    let rows: Vec<[String; 4]> = runs
+        .iter()
+        .map(|run| {
+            let status = match run.status {
+                RunStatus::Ok => String::from("ok"),
+                RunStatus::Failed(code) => format!("failed ({})", code),
+                RunStatus::Error => String::from("error"),
+                RunStatus::Skipped => String::from("skipped"),
+            };
+            let duration = format!("{:.1}s", run.duration.as_secs_f64());
+            let step = match (&run.status, run.steps.last()) {
+                (RunStatus::Failed(_), Some(step)) => step.clone(),
+                _ => String::from("-"),
+            };
+
+            [run.host.clone(), status, duration, step]
+        })
+        .collect();
+
+    let header = [String::from("HOST"), String::from("STATUS"), String::from("DURATION"), String::from("FAILED STEP")];
+    let mut widths = [0; 4];
+    for row in rows.iter().chain(std::iter::once(&header)) {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut s = String::new();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+        s.push_str(cells.join("  ").trim_end());
+        s.push('\n');
+    }
+
+    s
+}
+
+struct Rollout<'a> {
+    hosts: &'a [String],
+    fan_out: &'a FanOut,
+    log_dir: &'a Path,
+    runs: Mutex<Vec<Option<HostRun>>>,
+    failures: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl<'a> Rollout<'a> {
+    fn run_wave<R, L>(&self, indices: Vec<usize>, parallel: usize, run: &R, on_line: &mut L)
+    where
+        R: Fn(usize, &mut dyn FnMut(Stream, &str)) -> Result<(ExitStatus, Vec<LogEvent>), CorgError> + Sync,
+        L: FnMut(&str, Stream, &str),
+    {
+        let queue = Mutex::new(VecDeque::from(indices));
+        let (sender, receiver) = mpsc::channel::<(usize, Stream, String)>();
+
+        thread::scope(|scope| {
+            for _ in 0..parallel {
+                let sender = sender.clone();
+                let queue = &queue;
+                scope.spawn(move || loop {
+                    if self.stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let index = match queue.lock().unwrap_or_else(|err| err.into_inner()).pop_front() {
+                        Some(index) => index,
+                        None => return,
+                    };
+                    self.run_host(index, run, &sender);
+                });
+            }
+
+            // Ends once every worker finished and dropped its sender.
+            drop(sender);
+            for (index, stream, line) in receiver {
+                on_line(&self.hosts[index], stream, &line);
+            }
+        });
+    }
+
+    fn run_host<R>(&self, index: usize, run: &R, sender: &mpsc::Sender<(usize, Stream, String)>)
+    where
+        R: Fn(usize, &mut dyn FnMut(Stream, &str)) -> Result<(ExitStatus, Vec<LogEvent>), CorgError> + Sync,
+    {
+        let host = &self.hosts[index];
+        let mut log = RunLog::create(&self.log_dir.join(log_file_name(host)), host);
+        let started_at = Utc::now();
+        let started = Instant::now();
+
+        let result = run(index, &mut |stream, line| {
+            log.line(stream, line);
+            let _ = sender.send((index, stream, String::from(line)));
+        });
+
+        let status = match result {
+            Ok((status, events)) => {
+                // Like the log, a structured log which can't be written doesn't fail the host.
+                let _ = log.write_events(events);
+                match status.success() {
+                    true => RunStatus::Ok,
+                    false => RunStatus::Failed(runner::exit_code(&status)),
+                }
+            }
+            Err(err) => {
+                let _ = sender.send((index, Stream::Stderr, err.to_string()));
+                RunStatus::Error
+            }
+        };
+
+        if status != RunStatus::Ok {
+            let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+            let over_limit = match self.fan_out.max_failures {
+                Some(percent) => failures * 100 > usize::from(percent) * self.hosts.len(),
+                None => false,
+            };
+
+            if self.fan_out.fail_fast || over_limit {
+                self.stopped.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let host_run = HostRun {
+            host: host.clone(),
+            status,
+            started_at: Some(started_at),
+            duration: started.elapsed(),
+            steps: log.steps,
+            finished: log.finished,
+            log_path: log.path,
+        };
+        self.runs.lock().unwrap_or_else(|err| err.into_inner())[index] = Some(host_run);
+    }
+}
+
+fn log_file_name(host: &str) -> String {
+    format!("{}.log", history::file_name(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn hosts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    fn log_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("corg-fanout-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let hosts = hosts(&["faye", "microwave", "janet"]);
+        let dir = log_dir("parallel");
+        let fan_out = FanOut {
+            parallel: 2,
+            ..FanOut::default()
+        };
+
+        let mut lines = vec![];
+        let runs = super::fan_out(
+            &hosts,
+            &fan_out,
+            &dir,
+            |index, on_line| {
+                on_line(Stream::Stdout, "[step] install-nix");
+                on_line(Stream::Stdout, "\x1b[01;36mhello\x1b[00m");
+                // Exit status 3, as waitpid reports it.
+                Ok((ExitStatus::from_raw(if index == 1 { 3 << 8 } else { 0 }), vec![]))
+            },
+            |host, _, line| lines.push(format!("{}: {}", host, line)),
+        )
+        .unwrap();
+
+        assert_eq!(runs[0].status, RunStatus::Ok);
+        assert_eq!(runs[1].status, RunStatus::Failed(3));
+        assert_eq!(runs[1].steps, vec![String::from("install-nix")]);
+        assert_eq!(runs[2].status, RunStatus::Ok);
+        assert_eq!(lines.len(), 6);
+        assert!(lines.contains(&String::from("janet: [step] install-nix")));
+        assert_eq!(fs::read_to_string(dir.join("microwave.log")).unwrap(), "[step] install-nix\nhello\n");
+        let events = fs::read_to_string(dir.join("microwave.jsonl")).unwrap();
+        assert!(events.lines().nth(1).unwrap().ends_with("\"level\":\"stdout\",\"section\":\"install-nix\",\"host\":\"microwave\",\"message\":\"hello\"}"));
+
+        let summary = summary(&runs);
+        assert!(summary.starts_with("HOST       STATUS      DURATION  FAILED STEP\n"));
+        assert!(summary.contains("microwave  failed (3)"));
+        assert!(summary.trim_end().ends_with("-"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fan_out_canary_stops_rollout() {
+        let hosts = hosts(&["faye", "microwave", "janet"]);
+        let dir = log_dir("canary");
+        let fan_out = FanOut {
+            canaries: 1,
+            parallel: 2,
+            ..FanOut::default()
+        };
+
+        let runs = super::fan_out(&hosts, &fan_out, &dir, |_, _| Ok((ExitStatus::from_raw(1 << 8), vec![])), |_, _, _| {}).unwrap();
+        let statuses: Vec<RunStatus> = runs.iter().map(|run| run.status).collect();
+
+        assert_eq!(statuses, vec![RunStatus::Failed(1), RunStatus::Skipped, RunStatus::Skipped]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fan_out_max_failures() {
+        let hosts = hosts(&["a", "b", "c", "d"]);
+        let dir = log_dir("max-failures");
+        let fan_out = FanOut {
+            max_failures: Some(25),
+            ..FanOut::default()
+        };
+
+        // The first failure is within 25%, the second one isn't.
+        let runs = super::fan_out(&hosts, &fan_out, &dir, |_, _| Ok((ExitStatus::from_raw(1 << 8), vec![])), |_, _, _| {}).unwrap();
+        let statuses: Vec<RunStatus> = runs.iter().map(|run| run.status).collect();
+
+        assert_eq!(statuses, vec![RunStatus::Failed(1), RunStatus::Failed(1), RunStatus::Skipped, RunStatus::Skipped]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use crate::corg_error::CorgError;
+use crate::location::{LineIndex, Location};
+use serde_yaml::{Mapping, Value};
+
+/// The optional YAML block at the very top of a document:
+///
+/// ```yaml
+/// ---
+/// title: Faye
+/// allow-secrets: [high-entropy]
+/// ---
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrontMatter {
+    /// The YAML between the two fences.
+    pub raw: String,
+    /// Byte offset of `raw` inside the document.
+    pub offset: usize,
+    pub values: Mapping,
+}
+
+impl FrontMatter {
+    /// Splits the front matter off the top of the source. Returns the parsed front
+    /// matter (if there is any) and the byte offset where the markdown body starts.
+    pub fn split(source: &str) -> Result<(Option<FrontMatter>, usize), CorgError> {
+        let first_line_end = match source.find('\n') {
+            Some(end) => end + 1,
+            None => return Ok((None, 0)),
+        };
+
+        if source[..first_line_end].trim_end() != "---" {
+            return Ok((None, 0));
+        }
+
+        // Look for the closing fence, `...` is allowed as well since it's valid YAML.
+        let mut line_start = first_line_end;
+        while line_start < source.len() {
+            let line_end = source[line_start..]
+                .find('\n')
+                .map(|end| line_start + end + 1)
+                .unwrap_or_else(|| source.len());
+            let line = source[line_start..line_end].trim_end();
+
+            if line == "---" || line == "..." {
+                let raw = &source[first_line_end..line_start];
+                let front_matter = FrontMatter::parse(source, raw, first_line_end)?;
+                return Ok((Some(front_matter), line_end));
+            }
+
+            line_start = line_end;
+        }
+
+        // No closing fence, treat it as a plain markdown document.
+        Ok((None, 0))
+    }
+
+    fn parse(source: &str, raw: &str, offset: usize) -> Result<FrontMatter, CorgError> {
+        let values = if raw.trim().is_empty() {
+            Mapping::new()
+        } else {
+            match serde_yaml::from_str::<Value>(raw) {
+                Ok(Value::Mapping(values)) => values,
+                Ok(Value::Null) => Mapping::new(),
+                Ok(_) => {
+                    return Err(CorgError::FrontMatter {
+                        location: LineIndex::new(source).location(offset),
+                        message: String::from("expected a mapping of keys to values"),
+                    })
+                }
+                Err(err) => {
+                    let start = LineIndex::new(source).location(offset);
+                    let location = match err.location() {
+                        Some(at) => Location {
+                            line: start.line + at.line() - 1,
+                            column: at.column(),
+                        },
+                        None => start,
+                    };
+
+                    return Err(CorgError::FrontMatter {
+                        location,
+                        message: err.to_string(),
+                    });
+                }
+            }
+        };
+
+        Ok(FrontMatter {
+            raw: String::from(raw),
+            offset,
+            values,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(&Value::String(String::from(key)))
+    }
+
+    /// Returns a list of strings for the key, accepting either a YAML sequence
+    /// or a single comma separated string.
+    pub fn get_list(&self, key: &str) -> Vec<String> {
+        match self.get(key) {
+            Some(Value::Sequence(items)) => items.iter().filter_map(scalar_to_string).collect(),
+            Some(value) => scalar_to_string(value)
+                .map(|list| {
+                    list.split(',')
+                        .map(|item| item.trim().to_string())
+                        .filter(|item| !item.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None => vec![],
+        }
+    }
+}
+
+/// Converts scalar YAML values (strings, numbers and booleans) to a string.
+pub fn scalar_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let source = "---\ntitle: Faye\nallow-secrets: [high-entropy, private-key]\n---\n# Faye\n";
+        let (front_matter, body_offset) = FrontMatter::split(source).unwrap();
+        let front_matter = front_matter.unwrap();
+
+        assert_eq!(&source[body_offset..], "# Faye\n");
+        assert_eq!(front_matter.offset, 4);
+        assert_eq!(
+            front_matter.get_list("allow-secrets"),
+            vec!["high-entropy".to_string(), "private-key".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_split_without_front_matter() {
+        let source = "# Faye\n\n---\n\ntext\n";
+        let (front_matter, body_offset) = FrontMatter::split(source).unwrap();
+
+        assert_eq!(front_matter, None);
+        assert_eq!(body_offset, 0);
+    }
+
+    #[test]
+    fn test_split_invalid_yaml() {
+        let source = "---\ntitle: Faye\n  nope: [\n---\n# Faye\n";
+
+        match FrontMatter::split(source) {
+            Err(CorgError::FrontMatter { location, .. }) => assert!(location.line > 1),
+            other => panic!("expected a front matter error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use crate::corg_error::CorgError;
+use crate::events::{self, LogEvent};
+use crate::runner::{self, StepEvent, Stream};
+use crate::selection::{script_steps, step_function, Selection};
+use crate::vars::CorgVars;
+use crate::ConvertOptions;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Directory every run writes its record to, one JSON file per run.
+pub const RUNS_DIR: &str = ".corg/runs";
+
+/// What happened to a step of a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepStatus {
+    Ok,
+    Failed,
+    /// Not selected, see `selection::Selection`.
+    Skipped,
+    /// Came after the step which failed.
+    NotRun,
+}
+
+impl StepStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            StepStatus::Ok => "ok",
+            StepStatus::Failed => "failed",
+            StepStatus::Skipped => "skipped",
+            StepStatus::NotRun => "not_run",
+        }
+    }
+
+    fn parse(status: &str) -> Option<StepStatus> {
+        match status {
+            "ok" => Some(StepStatus::Ok),
+            "failed" => Some(StepStatus::Failed),
+            "skipped" => Some(StepStatus::Skipped),
+            "not_run" => Some(StepStatus::NotRun),
+            _ => None,
+        }
+    }
+}
+
+/// A step of a recorded run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepRecord {
+    pub slug: String,
+    pub status: StepStatus,
+    /// SHA-256 of the function running the step, to tell whether it changed.
+    pub hash: String,
+}
+
+/// A run of a script on one host, as kept in `.corg/runs/<id>.json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunRecord {
+    pub id: String,
+    /// Path of the document or script as given to `corg run`.
+    pub document: String,
+    /// SHA-256 of the document.
+    pub document_hash: String,
+    /// SHA-256 of the script which ran, which differs from the document's for
+    /// documents and between hosts with different variables.
+    pub script_hash: String,
+    pub host: String,
+    /// Who ran corg.
+    pub user: String,
+    /// Environment overlay of the front matter variables the script was
+    /// generated with.
+    pub environment: Option<String>,
+    /// Variables the script was generated with on top of the front matter.
+    pub vars: CorgVars,
+    /// User blocks which don't say who they run as ran as, from `--become`.
+    pub become_user: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub exit_code: i32,
+    /// The steps of the script and what happened to them, in order.
+    pub steps: Vec<StepRecord>,
+    /// The output of the run, without colors.
+    pub log: Option<PathBuf>,
+}
+
+impl RunRecord {
+    /// Starts the record of a run of `script` on the host, generated from the
+    /// document at `document` whose contents are `source` with `options`. Its
+    /// steps count as skipped until the run finished.
+    pub fn start(document: &str, source: &str, script: &str, host: &str, options: &ConvertOptions, started_at: DateTime<Utc>) -> RunRecord {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("LOGNAME"))
+            .unwrap_or_else(|_| String::from("unknown"));
+
+        RunRecord {
+            id: RunRecord::id(&started_at, host),
+            document: String::from(document),
+            document_hash: sha256(source),
+            script_hash: sha256(script),
+            host: String::from(host),
+            user,
+            environment: options.environment.clone(),
+            vars: options.vars.clone(),
+            become_user: options.become_user.clone(),
+            started_at,
+            finished_at: started_at,
+            exit_code: 0,
+            steps: script_steps(script)
+                .into_iter()
+                .map(|slug| StepRecord {
+                    hash: sha256(step_function(script, &slug).unwrap_or("")),
+                    slug,
+                    status: StepStatus::Skipped,
+                })
+                .collect(),
+            log: None,
+        }
+    }
+
+    /// Fills in how the run ended from the steps the script started and finished.
+    pub fn finish(&mut self, finished_at: DateTime<Utc>, exit_code: i32, started: &[String], finished: &[String], log: Option<&Path>) {
+        let slugs: Vec<String> = self.steps.iter().map(|step| step.slug.clone()).collect();
+        let statuses = step_statuses(&slugs, started, finished, exit_code);
+
+        self.finished_at = finished_at;
+        self.exit_code = exit_code;
+        for (step, status) in self.steps.iter_mut().zip(statuses) {
+            step.status = status;
+        }
+        self.log = log.map(Path::to_path_buf);
+    }
+
+    /// The options to generate the script of the run again with.
+    pub fn options(&self) -> ConvertOptions {
+        ConvertOptions {
+            vars: self.vars.clone(),
+            environment: self.environment.clone(),
+            become_user: self.become_user.clone(),
+            standalone: true,
+            ..ConvertOptions::default()
+        }
+    }
+
+    /// Selects the step the run failed in and the ones after it which didn't run.
+    /// `script` is the script generated again for the same host, resuming is
+    /// refused when the failed step changed since.
+    pub fn resume(&self, script: &str) -> Result<Selection, String> {
+        if self.exit_code == 0 {
+            return Err(format!("run {} succeeded, there is nothing to resume", self.id));
+        }
+
+        let failed = self
+            .steps
+            .iter()
+            .find(|step| step.status == StepStatus::Failed)
+            .ok_or_else(|| format!("run {} didn't fail in a step, run it again instead", self.id))?;
+
+        match step_function(script, &failed.slug) {
+            Some(function) if sha256(function) == failed.hash => (),
+            Some(_) => return Err(format!("step '{}' changed since run {}", failed.slug, self.id)),
+            None => return Err(format!("step '{}' of run {} is gone", failed.slug, self.id)),
+        }
+
+        let sections = self
+            .steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Failed || step.status == StepStatus::NotRun)
+            .map(|step| step.slug.clone())
+            .collect();
+
+        Ok(Selection {
+            sections,
+            ..Selection::default()
+        })
+    }
+
+    /// Ids sort by the time the run started.
+    pub fn id(started_at: &DateTime<Utc>, host: &str) -> String {
+        format!("{}-{}", started_at.format("%Y%m%dT%H%M%S%3f"), file_name(host))
+    }
+
+    pub fn failed_step(&self) -> Option<&str> {
+        self.steps
+            .iter()
+            .find(|step| step.status == StepStatus::Failed)
+            .map(|step| step.slug.as_str())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let steps: Vec<Value> = self
+            .steps
+            .iter()
+            .map(|step| json!({ "slug": step.slug, "status": step.status.as_str(), "hash": step.hash }))
+            .collect();
+
+        json!({
+            "id": self.id,
+            "document": self.document,
+            "document_hash": self.document_hash,
+            "script_hash": self.script_hash,
+            "host": self.host,
+            "user": self.user,
+            "environment": self.environment,
+            "become": self.become_user,
+            "vars": self.vars.entries().into_iter().map(|(key, value)| (String::from(key), Value::from(value))).collect::<serde_json::Map<_, _>>(),
+            "started_at": self.started_at.to_rfc3339(),
+            "finished_at": self.finished_at.to_rfc3339(),
+            "exit_code": self.exit_code,
+            "steps": steps,
+            "log": self.log.as_ref().map(|log| log.to_string_lossy()),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<RunRecord, String> {
+        let string = |key: &str| -> Result<String, String> {
+            match value[key].as_str() {
+                Some(value) => Ok(String::from(value)),
+                None => Err(format!("`{}` must be a string", key)),
+            }
+        };
+        let time = |key: &str| -> Result<DateTime<Utc>, String> {
+            DateTime::parse_from_rfc3339(&string(key)?)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|err| format!("`{}`: {}", key, err))
+        };
+
+        let mut steps = vec![];
+        for step in value["steps"].as_array().ok_or("`steps` must be a list")? {
+            let slug = step["slug"].as_str().ok_or("`steps.slug` must be a string")?;
+            let status = step["status"].as_str().and_then(StepStatus::parse).ok_or("invalid `steps.status`")?;
+            steps.push(StepRecord {
+                slug: String::from(slug),
+                status,
+                hash: String::from(step["hash"].as_str().unwrap_or("")),
+            });
+        }
+
+        let mut vars = CorgVars::new();
+        if let Some(values) = value["vars"].as_object() {
+            for (key, value) in values {
+                vars.insert(key, value.as_str().ok_or("`vars` must hold strings")?);
+            }
+        }
+
+        Ok(RunRecord {
+            id: string("id")?,
+            document: string("document")?,
+            document_hash: string("document_hash")?,
+            script_hash: string("script_hash")?,
+            host: string("host")?,
+            user: string("user")?,
+            environment: value["environment"].as_str().map(String::from),
+            become_user: value["become"].as_str().map(String::from),
+            vars,
+            started_at: time("started_at")?,
+            finished_at: time("finished_at")?,
+            exit_code: value["exit_code"].as_i64().ok_or("`exit_code` must be a number")? as i32,
+            steps,
+            log: value["log"].as_str().map(PathBuf::from),
+        })
+    }
+}
+
+/// Which runs `corg history` lists.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryFilter {
+    pub host: Option<String>,
+    /// Part of the path of the document.
+    pub document: Option<String>,
+    pub failed: bool,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, record: &RunRecord) -> bool {
+        self.host.as_ref().is_none_or(|host| record.host == *host)
+            && self.document.as_ref().is_none_or(|document| record.document.contains(document.as_str()))
+            && (!self.failed || record.exit_code != 0)
+            && self.since.is_none_or(|since| record.started_at >= since)
+    }
+}
+
+/// Captures the output of a run on `host` to its log file and keeps track of the
+/// steps the script started.
+pub struct RunLog {
+    file: Option<File>,
+    pub path: PathBuf,
+    /// Slugs of the steps the script started, in order.
+    pub steps: Vec<String>,
+    /// Slugs of the steps the script finished.
+    pub finished: Vec<String>,
+    host: String,
+    /// The step the script is in.
+    section: Option<String>,
+    /// Lines the script wrote without the logger, as they came in.
+    output: Vec<LogEvent>,
+    /// Lines of the logger, standing in for its events when it wrote none.
+    logged: Vec<LogEvent>,
+}
+
+impl RunLog {
+    /// Starts the log at `path`. When it can't be written the output still
+    /// reaches the console, so the run goes ahead without it.
+    pub fn create(path: &Path, host: &str) -> RunLog {
+        let file = path.parent().and_then(|dir| fs::create_dir_all(dir).ok()).and_then(|_| File::create(path).ok());
+
+        RunLog {
+            file,
+            path: path.to_path_buf(),
+            steps: vec![],
+            finished: vec![],
+            host: String::from(host),
+            section: None,
+            output: vec![],
+            logged: vec![],
+        }
+    }
+
+    pub fn line(&mut self, stream: Stream, line: &str) {
+        let step_event = runner::parse_step_event(line);
+        match &step_event {
+            Some(StepEvent::Paused(slug)) | Some(StepEvent::Started(slug)) => self.section = Some(slug.clone()),
+            Some(StepEvent::Finished(_)) | None => (),
+        }
+
+        let log_line = runner::split_log_line(line).filter(|_| step_event.is_some() || runner::parse_log_line(line).is_some());
+        let is_logged = log_line.is_some();
+        let (level, message) = log_line.unwrap_or_else(|| {
+            let level = match stream {
+                Stream::Stdout => events::STDOUT_LEVEL,
+                Stream::Stderr => events::STDERR_LEVEL,
+            };
+            (String::from(level), runner::strip_colors(line).to_string())
+        });
+        let event = LogEvent {
+            timestamp: Utc::now(),
+            level,
+            section: self.section.clone(),
+            host: self.host.clone(),
+            message,
+        };
+        if is_logged {
+            self.logged.push(event);
+        } else {
+            self.output.push(event);
+        }
+
+        match step_event {
+            Some(StepEvent::Started(slug)) => self.steps.push(slug),
+            Some(StepEvent::Finished(slug)) => {
+                self.finished.push(slug);
+                self.section = None;
+            }
+            Some(StepEvent::Paused(_)) | None => (),
+        }
+        if let Some(file) = self.file.as_mut() {
+            let _ = writeln!(file, "{}", runner::strip_colors(line));
+        }
+    }
+
+    /// The log file, if it could be written.
+    pub fn written(&self) -> Option<&Path> {
+        self.file.as_ref().map(|_| self.path.as_path())
+    }
+
+    /// Merges the events the logger wrote to `CORG_LOG_FILE` with the rest of the
+    /// output by time. Without events, e.g. for scripts with an older logger, its
+    /// lines in the output stand in for them.
+    pub fn events(&self, events: Vec<LogEvent>) -> Vec<LogEvent> {
+        let (events, host) = match events.first() {
+            Some(event) => {
+                let host = event.host.clone();
+                (events, host)
+            }
+            None => (self.logged.clone(), self.host.clone()),
+        };
+
+        let output = self.output.iter().map(|event| LogEvent {
+            host: host.clone(),
+            ..event.clone()
+        });
+        let mut events: Vec<LogEvent> = events.into_iter().chain(output).collect();
+        events.sort_by_key(|event| event.timestamp);
+
+        events
+    }
+
+    /// Writes the merged events next to the log file as JSON lines, e.g.
+    /// `.corg/runs/<id>.jsonl`, and returns its path.
+    pub fn write_events(&self, events: Vec<LogEvent>) -> Result<PathBuf, CorgError> {
+        let path = self.path.with_extension("jsonl");
+        fs::write(&path, events::to_jsonl(&self.events(events)))?;
+
+        Ok(path)
+    }
+}
+
+/// What happened to each of the script's steps, from the ones it started and
+/// finished. When the script failed, it did so in the last step it started unless
+/// that one finished.
+pub fn step_statuses(steps: &[String], started: &[String], finished: &[String], exit_code: i32) -> Vec<StepStatus> {
+    let failed = match started.last() {
+        Some(last) if exit_code != 0 && !finished.contains(last) => Some(last),
+        _ => None,
+    };
+    let failed_index = failed.and_then(|failed| steps.iter().position(|step| step == failed));
+
+    steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| {
+            if Some(step) == failed {
+                StepStatus::Failed
+            } else if started.contains(step) {
+                StepStatus::Ok
+            } else if failed_index.is_some_and(|failed_index| index > failed_index) {
+                StepStatus::NotRun
+            } else {
+                StepStatus::Skipped
+            }
+        })
+        .collect()
+}
+
+pub fn sha256(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Writes the record to `<dir>/<id>.json`.
+pub fn save(dir: &Path, record: &RunRecord) -> Result<PathBuf, CorgError> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{}.json", record.id));
+    let json = serde_json::to_string_pretty(&record.to_json()).unwrap_or_default();
+    fs::write(&path, json + "\n")?;
+
+    Ok(path)
+}
+
+/// Reads the records in `dir`, oldest first. No directory means no runs yet.
+pub fn load(dir: &Path) -> Result<Vec<RunRecord>, CorgError> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut records = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+
+        records.push(read(&path)?);
+    }
+
+    records.sort_by(|a: &RunRecord, b| a.started_at.cmp(&b.started_at).then(a.id.cmp(&b.id)));
+    Ok(records)
+}
+
+/// Reads the record of the run with the id, none when there's no such run.
+pub fn find(dir: &Path, id: &str) -> Result<Option<RunRecord>, CorgError> {
+    let path = dir.join(format!("{}.json", id));
+
+    if path.exists() {
+        read(&path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn read(path: &Path) -> Result<RunRecord, CorgError> {
+    let contents = fs::read_to_string(path)?;
+
+    serde_json::from_str(&contents)
+        .map_err(|err| err.to_string())
+        .and_then(|value| RunRecord::from_json(&value))
+        .map_err(|err| CorgError::History(format!("{}: {}", path.display(), err)))
+}
+
+/// Renders a table of the runs, one row per run.
+pub fn table(records: &[RunRecord]) -> String {
+    let header = ["STARTED", "HOST", "DOCUMENT", "EXIT", "DURATION", "FAILED STEP"].map(String::from);
+    let rows: Vec<[String; 6]> = records
+        .iter()
+        .map(|record| {
+            let duration = (record.finished_at - record.started_at).num_milliseconds() as f64 / 1000.0;
+            [
+                record.started_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                record.host.clone(),
+                record.document.clone(),
+                record.exit_code.to_string(),
+                format!("{:.1}s", duration),
+                String::from(record.failed_step().unwrap_or("-")),
+            ]
+        })
+        .collect();
+
+    let mut widths = [0; 6];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut s = String::new();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+        s.push_str(cells.join("  ").trim_end());
+        s.push('\n');
+    }
+
+    s
+}
+
+/// Host names can hold characters which don't belong in file names, e.g. `user@host`.
+pub fn file_name(host: &str) -> String {
+    host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SCRIPT: &str = "#!/bin/zsh\n\n# - begin function:\nfunction install-nix {\necho nix\n}\n# - end function\n\n\
+                          # - begin function:\nfunction configure {\necho configure\n}\n# - end function\n\n\
+                          # - begin function:\nfunction deploy {\necho deploy\n}\n# - end function\n\n\
+                          # - run doc: \ncorg_step install-nix\ncorg_step configure\ncorg_step deploy";
+
+    fn record() -> RunRecord {
+        let started_at = Utc.ymd(2026, 10, 18).and_hms(9, 30, 0);
+        let mut options = ConvertOptions::default();
+        options.vars.insert("port", "2222");
+        options.become_user = Some(String::from("root"));
+        let mut record = RunRecord::start("docs/nix.md", "# Nix\n", SCRIPT, "seve@faye", &options, started_at);
+        record.host = String::from("faye");
+        record.user = String::from("seve");
+
+        let mut log = RunLog::create(&std::env::temp_dir().join(format!("corg-history-record-{}.log", std::process::id())), "faye");
+        for line in &["[step] install-nix", "[done] install-nix", "[step] configure"] {
+            log.line(Stream::Stdout, line);
+        }
+        record.finish(started_at + chrono::Duration::milliseconds(2500), 2, &log.steps, &log.finished, log.written());
+        fs::remove_file(&log.path).unwrap();
+        record.log = Some(PathBuf::from(".corg/runs/faye.log"));
+
+        record
+    }
+
+    #[test]
+    fn test_step_statuses() {
+        let record = record();
+
+        assert_eq!(record.id, "20261018T093000000-seve_faye");
+        assert_eq!(record.document_hash, "29a34a418228bbbf05b6fe90d7725e226bdb01fac5b1a7efb8ec6219eb4225fd");
+        assert_eq!(record.failed_step(), Some("configure"));
+        assert_eq!(record.steps[2].status, StepStatus::NotRun);
+
+        let steps = vec![String::from("build"), String::from("deploy")];
+        let statuses = step_statuses(&steps, &steps[1..], &[], 0);
+        assert_eq!(statuses, vec![StepStatus::Skipped, StepStatus::Ok]);
+        // Failed after the last step finished.
+        let statuses = step_statuses(&steps, &steps, &steps, 1);
+        assert_eq!(statuses, vec![StepStatus::Ok, StepStatus::Ok]);
+    }
+
+    #[test]
+    fn test_resume() {
+        let record = record();
+
+        let selection = record.resume(SCRIPT).unwrap();
+        assert_eq!(selection.sections, vec!["configure", "deploy"]);
+
+        let changed = SCRIPT.replace("echo configure", "echo reconfigure");
+        assert_eq!(record.resume(&changed).unwrap_err(), format!("step 'configure' changed since run {}", record.id));
+        // Other steps may change.
+        assert!(record.resume(&SCRIPT.replace("echo deploy", "echo redeploy")).is_ok());
+    }
+
+    #[test]
+    fn test_run_log_events() {
+        let path = std::env::temp_dir().join(format!("corg-history-events-{}.log", std::process::id()));
+        let mut log = RunLog::create(&path, "faye");
+        log.line(Stream::Stdout, "\x1b[01;35m[step]\x1b[00m deploy");
+        log.line(Stream::Stderr, "warning: no cache");
+        fs::remove_file(&path).unwrap();
+
+        // Without events of the logger, its lines in the output stand in for them.
+        let events: Vec<(String, Option<String>, String)> = log
+            .events(vec![])
+            .into_iter()
+            .map(|event| (event.level, event.section, event.message))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (String::from("step"), Some(String::from("deploy")), String::from("deploy")),
+                (String::from("stderr"), Some(String::from("deploy")), String::from("warning: no cache")),
+            ]
+        );
+
+        let logged = LogEvent {
+            timestamp: Utc.ymd(2026, 10, 18).and_hms(9, 30, 0),
+            level: String::from("info"),
+            section: None,
+            host: String::from("faye.futuregadgetlab.dev"),
+            message: String::from("Running Faye"),
+        };
+        let events = log.events(vec![logged.clone()]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], logged);
+        assert_eq!(events[1].host, "faye.futuregadgetlab.dev");
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("corg-history-{}", std::process::id()));
+        let record = record();
+
+        let path = save(&dir, &record).unwrap();
+        assert!(path.ends_with("20261018T093000000-seve_faye.json"));
+        assert_eq!(load(&dir).unwrap(), vec![record.clone()]);
+        assert_eq!(find(&dir, &record.id).unwrap(), Some(record.clone()));
+        assert_eq!(find(&dir, "20261018T093000000-microwave").unwrap(), None);
+
+        let filter = HistoryFilter {
+            host: Some(String::from("faye")),
+            failed: true,
+            ..HistoryFilter::default()
+        };
+        assert!(filter.matches(&record));
+        assert!(!HistoryFilter { document: Some(String::from("certs")), ..HistoryFilter::default() }.matches(&record));
+
+        let table = table(&[record]);
+        assert!(table.starts_with("STARTED              HOST  DOCUMENT     EXIT  DURATION  FAILED STEP\n"));
+        assert!(table.ends_with("2026-10-18 09:30:00  faye  docs/nix.md  2     2.5s      configure\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(load(&dir).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::condition::Condition;
+use crate::document::{CodeBlock, Document, Heading, Link, Prose, ProseKind, Section};
+use crate::location::LineIndex;
+use serde_json::{json, Map, Value};
+use std::ops::Range;
+
+/// Describes the structure of a document as JSON, e.g. for dashboards or CI
+/// checks which want to reason about runbooks:
+///
+/// ```json
+/// {
+///   "title": { "text": "Faye", "level": 1, "when": null, "lines": { "start": 1, "end": 1 } },
+///   "front_matter": { "vars": { "host": "faye" } },
+///   "blocks": [],
+///   "sections": [{ "heading": { .. }, "slug": "install-nix", "step": true, "lines": { .. },
+///                  "prose": [..], "blocks": [..], "children": [..] }],
+///   "links": [{ "text": "manual", "destination": "https://nixos.org", "title": "", "section": null, "line": 3 }]
+/// }
+/// ```
+///
+/// Lines start at 1 and ranges include their end line, `source` has to be the
+/// markdown the document was parsed from.
+pub fn to_json(document: &Document, source: &str) -> Value {
+    let inspector = Inspector {
+        line_index: LineIndex::new(source),
+    };
+
+    json!({
+        "title": document.title.as_ref().map(|title| inspector.heading(title)),
+        "front_matter": serde_json::to_value(&document.meta).unwrap_or(Value::Null),
+        "prose": document.prose.iter().map(|prose| inspector.prose(prose)).collect::<Vec<_>>(),
+        "blocks": document.blocks.iter().map(|block| inspector.block(block)).collect::<Vec<_>>(),
+        "sections": document.sections.iter().map(|section| inspector.section(section)).collect::<Vec<_>>(),
+        "links": document.links.iter().map(link).collect::<Vec<_>>(),
+    })
+}
+
+/// Describes the sections of a document as an indented outline with their lines,
+/// steps are marked with the name of the function running them.
+pub fn outline(document: &Document, source: &str) -> String {
+    let line_index = LineIndex::new(source);
+    let mut output = String::new();
+
+    if let Some(title) = &document.title {
+        output.push_str(&format!("{}\n", title.text));
+    }
+
+    fn push_section(output: &mut String, line_index: &LineIndex, section: &Section) {
+        let indent = "  ".repeat((section.heading.level.max(2) - 2) as usize);
+        let lines = line_range(line_index, &section.range);
+        let step = if section.is_step() {
+            format!(" -> {}", section.slug)
+        } else {
+            String::new()
+        };
+        let when = match &section.heading.when {
+            Some(condition) => format!(" when {}", condition),
+            None => String::new(),
+        };
+
+        output.push_str(&format!(
+            "{}- {} (lines {}-{}, {} code blocks){}{}\n",
+            indent,
+            section.heading.text,
+            lines.start,
+            lines.end,
+            section.blocks.len(),
+            step,
+            when
+        ));
+
+        for child in section.children.iter() {
+            push_section(output, line_index, child);
+        }
+    }
+
+    for section in document.sections.iter() {
+        push_section(&mut output, &line_index, section);
+    }
+
+    output
+}
+
+struct Inspector {
+    line_index: LineIndex,
+}
+
+impl Inspector {
+    fn lines(&self, range: &Range<usize>) -> Value {
+        let lines = line_range(&self.line_index, range);
+
+        json!({ "start": lines.start, "end": lines.end })
+    }
+
+    fn heading(&self, heading: &Heading) -> Value {
+        json!({
+            "text": heading.text,
+            "level": heading.level,
+            "when": heading.when.as_ref().map(condition),
+            "lines": self.lines(&heading.range),
+        })
+    }
+
+    fn section(&self, section: &Section) -> Value {
+        json!({
+            "heading": self.heading(&section.heading),
+            "slug": section.slug,
+            "step": section.is_step(),
+            "lines": self.lines(&section.range),
+            "prose": section.prose.iter().map(|prose| self.prose(prose)).collect::<Vec<_>>(),
+            "blocks": section.blocks.iter().map(|block| self.block(block)).collect::<Vec<_>>(),
+            "children": section.children.iter().map(|child| self.section(child)).collect::<Vec<_>>(),
+        })
+    }
+
+    fn prose(&self, prose: &Prose) -> Value {
+        let kind = match prose.kind {
+            ProseKind::Paragraph => "paragraph",
+            ProseKind::Item => "item",
+            ProseKind::Quote => "quote",
+        };
+
+        json!({
+            "kind": kind,
+            "text": prose.text,
+            "lines": self.lines(&prose.range),
+        })
+    }
+
+    fn block(&self, block: &CodeBlock) -> Value {
+        let mut attributes = Map::new();
+        for (key, value) in block.attributes.entries() {
+            let value = match value {
+                Some(value) => Value::from(value),
+                None => Value::Bool(true),
+            };
+            attributes.insert(String::from(key), value);
+        }
+
+        json!({
+            "name": block.name,
+            "lang": block.lang,
+            "attributes": attributes,
+            "when": block.when.as_ref().map(condition),
+            "code": block.code,
+            "lines": self.lines(&block.range),
+        })
+    }
+}
+
+fn condition(condition: &Condition) -> Value {
+    Value::from(condition.to_string())
+}
+
+fn link(link: &Link) -> Value {
+    json!({
+        "text": link.text,
+        "destination": link.destination,
+        "title": link.title,
+        "section": link.section,
+        "line": link.location.line,
+    })
+}
+
+/// Returns the first and last line of the range.
+fn line_range(line_index: &LineIndex, range: &Range<usize>) -> Range<usize> {
+    let start = line_index.location(range.start).line;
+    let end = line_index.location(range.end.max(range.start + 1) - 1).line;
+
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vars::CorgVars;
+
+    const SOURCE: &str = "---\nowner: ops\n---\n# Faye\n\n## Install Nix {when=\"os=nixos\"}\n\n\
+                          See [the manual](https://nixos.org/manual).\n\n\
+                          ```shell {retry=3, parallel}\ncurl nix\n```\n\n### Check\n\nChecks it.\n";
+
+    #[test]
+    fn test_to_json() {
+        let document = Document::parse(SOURCE, &CorgVars::new(), None).unwrap();
+        let json = to_json(&document, SOURCE);
+
+        assert_eq!(json["title"]["text"], "Faye");
+        assert_eq!(json["front_matter"]["owner"], "ops");
+
+        let section = &json["sections"][0];
+        assert_eq!(section["slug"], "install-nix");
+        assert_eq!(section["step"], true);
+        assert_eq!(section["heading"]["when"], "os=nixos");
+        assert_eq!(section["lines"], json!({ "start": 6, "end": 16 }));
+        assert_eq!(section["blocks"][0]["attributes"], json!({ "retry": "3", "parallel": true }));
+        assert_eq!(section["blocks"][0]["code"], "curl nix\n");
+        assert_eq!(section["blocks"][0]["lines"], json!({ "start": 10, "end": 12 }));
+        assert_eq!(section["children"][0]["slug"], "check");
+        assert_eq!(json["links"][0]["destination"], "https://nixos.org/manual");
+        assert_eq!(json["links"][0]["section"], "install-nix");
+    }
+
+    #[test]
+    fn test_outline() {
+        let document = Document::parse(SOURCE, &CorgVars::new(), None).unwrap();
+
+        assert_eq!(
+            outline(&document, SOURCE),
+            "Faye\n- Install Nix (lines 6-16, 1 code blocks) -> install-nix when os=nixos\n  \
+             - Check (lines 14-16, 0 code blocks)\n"
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use crate::corg_error::CorgError;
+use crate::runner::Ssh;
+use crate::vars::CorgVars;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+/// Inventory corg looks for in the current directory.
+pub const INVENTORY_FILE_NAME: &str = "corg.hosts.toml";
+
+/// The hosts runbooks run on and the groups they're in, read from
+/// `corg.hosts.toml`:
+///
+/// ```toml
+/// [hosts.faye]
+/// address = "faye.futuregadgetlab.dev"
+/// user = "seve"
+/// port = 2222
+/// jump = "bastion.futuregadgetlab.dev"
+/// vars = { nginx_workers = 4 }
+///
+/// [groups.web]
+/// hosts = ["faye"]
+/// vars = { domain = "futuregadgetlab.dev" }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inventory {
+    /// Hosts sorted by name.
+    pub hosts: Vec<InventoryHost>,
+    /// Groups sorted by name.
+    pub groups: Vec<Group>,
+}
+
+/// A host of the inventory and how to connect to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InventoryHost {
+    pub name: String,
+    /// Host name or IP ssh connects to, the name of the host if not given.
+    pub address: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// Host ssh jumps through to reach this one, ssh's `-J`.
+    pub jump: Option<String>,
+    /// Shell the script is piped to, zsh if not given.
+    pub shell: Option<String>,
+    pub vars: CorgVars,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    /// Names of the hosts in the group, in the order they're listed.
+    pub hosts: Vec<String>,
+    pub vars: CorgVars,
+}
+
+impl Inventory {
+    /// Reads the inventory at `path`, errors are prefixed with the path.
+    pub fn load(path: &Path) -> Result<Inventory, CorgError> {
+        let source = fs::read_to_string(path).map_err(|err| CorgError::Inventory(format!("{}: {}", path.display(), err)))?;
+
+        Inventory::parse(&source).map_err(|err| CorgError::Inventory(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Parses an inventory, errors name the offending entry, e.g.
+    /// `hosts.faye.port: must be a number between 1 and 65535`.
+    pub fn parse(source: &str) -> Result<Inventory, String> {
+        let root: Value = toml::from_str(source).map_err(|err| err.to_string())?;
+        let root = root.as_table().ok_or("expected a table")?;
+
+        if let Some(key) = root.keys().find(|key| *key != "hosts" && *key != "groups") {
+            return Err(format!("{}: unknown key, expected `hosts` or `groups`", key));
+        }
+
+        let mut inventory = Inventory::default();
+
+        for (name, value) in table(root.get("hosts"), "hosts")? {
+            inventory.hosts.push(parse_host(name, value)?);
+        }
+
+        for (name, value) in table(root.get("groups"), "groups")? {
+            let group = parse_group(name, value)?;
+
+            if inventory.host(name).is_some() {
+                return Err(format!("groups.{}: a host has the same name", name));
+            }
+            if let Some(unknown) = group.hosts.iter().find(|host| inventory.host(host).is_none()) {
+                return Err(format!("groups.{}.hosts: unknown host '{}'", name, unknown));
+            }
+
+            inventory.groups.push(group);
+        }
+
+        Ok(inventory)
+    }
+
+    pub fn host(&self, name: &str) -> Option<&InventoryHost> {
+        self.hosts.iter().find(|host| host.name == name)
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    /// Variables of a run on the host, the ones of its groups overlaid with its own.
+    pub fn vars(&self, host: &InventoryHost) -> CorgVars {
+        let mut vars = CorgVars::new();
+
+        for group in self.groups.iter().filter(|group| group.hosts.contains(&host.name)) {
+            vars.extend(&group.vars);
+        }
+        vars.extend(&host.vars);
+
+        vars
+    }
+}
+
+impl InventoryHost {
+    /// A host which isn't in the inventory, ssh connects to it as given.
+    pub fn new(address: &str) -> InventoryHost {
+        InventoryHost {
+            name: String::from(address),
+            address: String::from(address),
+            user: None,
+            port: None,
+            jump: None,
+            shell: None,
+            vars: CorgVars::new(),
+        }
+    }
+
+    /// Connection settings for the host, the ones given to `ssh` win over the
+    /// inventory.
+    pub fn ssh(&self, ssh: &Ssh) -> Ssh {
+        let mut ssh = ssh.clone();
+
+        ssh.user = ssh.user.or_else(|| self.user.clone());
+        ssh.port = ssh.port.or(self.port);
+        ssh.jump = ssh.jump.or_else(|| self.jump.clone());
+        if let Some(shell) = &self.shell {
+            ssh.shell = shell.clone();
+        }
+
+        ssh
+    }
+}
+
+fn table<'a>(value: Option<&'a Value>, key: &str) -> Result<Vec<(&'a String, &'a Value)>, String> {
+    match value {
+        Some(Value::Table(table)) => Ok(table.iter().collect()),
+        Some(_) => Err(format!("{}: must be a table", key)),
+        None => Ok(vec![]),
+    }
+}
+
+fn parse_host(name: &str, value: &Value) -> Result<InventoryHost, String> {
+    let key = format!("hosts.{}", name);
+    let mut host = InventoryHost::new(name);
+
+    for (field, value) in table(Some(value), &key)? {
+        let field_key = format!("{}.{}", key, field);
+
+        match field.as_str() {
+            "address" => host.address = string(value, &field_key)?,
+            "user" => host.user = Some(string(value, &field_key)?),
+            "jump" => host.jump = Some(string(value, &field_key)?),
+            "shell" => host.shell = Some(string(value, &field_key)?),
+            "port" => match value.as_integer() {
+                Some(port) if port > 0 && port <= i64::from(u16::MAX) => host.port = Some(port as u16),
+                _ => return Err(format!("{}: must be a number between 1 and 65535", field_key)),
+            },
+            "vars" => host.vars = vars(value, &field_key)?,
+            _ => return Err(format!("{}: unknown key", field_key)),
+        }
+    }
+
+    Ok(host)
+}
+
+fn parse_group(name: &str, value: &Value) -> Result<Group, String> {
+    let key = format!("groups.{}", name);
+    let mut group = Group {
+        name: String::from(name),
+        hosts: vec![],
+        vars: CorgVars::new(),
+    };
+
+    for (field, value) in table(Some(value), &key)? {
+        let field_key = format!("{}.{}", key, field);
+
+        match field.as_str() {
+            "hosts" => match value.as_array() {
+                Some(hosts) => {
+                    for host in hosts {
+                        group.hosts.push(string(host, &field_key)?);
+                    }
+                }
+                None => return Err(format!("{}: must be a list of host names", field_key)),
+            },
+            "vars" => group.vars = vars(value, &field_key)?,
+            _ => return Err(format!("{}: unknown key", field_key)),
+        }
+    }
+
+    Ok(group)
+}
+
+fn string(value: &Value, key: &str) -> Result<String, String> {
+    match value.as_str() {
+        Some(value) => Ok(String::from(value)),
+        None => Err(format!("{}: must be a string", key)),
+    }
+}
+
+fn vars(value: &Value, key: &str) -> Result<CorgVars, String> {
+    let mut vars = CorgVars::new();
+
+    for (name, value) in table(Some(value), key)? {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Boolean(value) => value.to_string(),
+            _ => return Err(format!("{}.{}: must be a string, number or boolean", key, name)),
+        };
+        vars.insert(name, &value);
+    }
+
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"
+[hosts.faye]
+address = "faye.futuregadgetlab.dev"
+user = "seve"
+port = 2222
+vars = { nginx_workers = 4, domain = "faye.dev" }
+
+[hosts.microwave]
+
+[groups.web]
+hosts = ["faye", "microwave"]
+vars = { domain = "futuregadgetlab.dev", tls = true }
+"#;
+
+    #[test]
+    fn test_parse() {
+        let inventory = Inventory::parse(SOURCE).unwrap();
+        let faye = inventory.host("faye").unwrap();
+
+        assert_eq!(faye.address, "faye.futuregadgetlab.dev");
+        assert_eq!(faye.port, Some(2222));
+        assert_eq!(inventory.host("microwave").unwrap().address, "microwave");
+        assert_eq!(inventory.group("web").unwrap().hosts, vec!["faye", "microwave"]);
+
+        // The host's own vars win over the group's.
+        let vars = inventory.vars(faye);
+        assert_eq!(vars.entries(), vec![("domain", "faye.dev"), ("nginx_workers", "4"), ("tls", "true")]);
+
+        let ssh = faye.ssh(&Ssh { port: Some(22), ..Ssh::default() });
+        assert_eq!((ssh.user.as_deref(), ssh.port), (Some("seve"), Some(22)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let errors = [
+            ("[hosts.faye]\nport = 70000\n", "hosts.faye.port: must be a number between 1 and 65535"),
+            ("[hosts.faye]\nusr = \"seve\"\n", "hosts.faye.usr: unknown key"),
+            ("[groups.web]\nhosts = [\"janet\"]\n", "groups.web.hosts: unknown host 'janet'"),
+            ("[hosts.faye]\nvars = { list = [1] }\n", "hosts.faye.vars.list: must be a string, number or boolean"),
+        ];
+
+        for (source, expected) in errors.iter() {
+            assert_eq!(Inventory::parse(source).unwrap_err(), *expected);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+
+/// A human readable position inside a markdown document, both values start at 1.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Maps byte offsets produced by the markdown parser back to lines and columns
+/// of the original source.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> LineIndex {
+        let mut line_starts = vec![0];
+
+        for (offset, byte) in source.bytes().enumerate() {
+            if byte == b'\n' {
+                line_starts.push(offset + 1);
+            }
+        }
+
+        LineIndex { line_starts }
+    }
+
+    /// Returns the location of the given byte offset.
+    pub fn location(&self, offset: usize) -> Location {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        };
+
+        Location {
+            line: line + 1,
+            column: offset - self.line_starts[line] + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let index = LineIndex::new("# Title\n\nsome text\n");
+
+        assert_eq!(index.location(0), Location { line: 1, column: 1 });
+        assert_eq!(index.location(8), Location { line: 2, column: 1 });
+        assert_eq!(index.location(14), Location { line: 3, column: 6 });
+    }
+}
@@ -7,6 +7,7 @@ use pulldown_cmark::{Event, Options, Parser, Tag};
 use std::path::Path;
 use std::io::Write;
 use std::io;
+use std::process;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
@@ -16,21 +17,23 @@ use tui::widgets::{Widget, Block, Borders};
 use tui::layout::{Layout, Constraint, Direction};
 
 mod ui;
-mod shell;
-mod corg_file;
 mod corg_doc;
-mod clogger;
 mod util;
 
-use corg_file::CorgFile;
+use corg::{ConvertOptions, CorgError, CorgVars, Format};
+use corg::events::LogEvent;
+use corg::fanout::{FanOut, RunStatus};
+use corg::history::{HistoryFilter, RunLog, RunRecord, RUNS_DIR};
+use corg::inventory::{Inventory, InventoryHost};
+use corg::run_env::RunEnv;
+use corg::selection::Selection;
+use corg::sudo::SudoAccess;
+use corg::transport::{CommandTransport, LocalTransport, Target, Transport};
 
 use util::*;
-use clogger::*;
+use corg::clogger::*;
 use termion::event::Key;
 
-const CORG_LOGGER_SHELL_SCRIPT: &'static [u8] = include_bytes!("../static/scripts/corg-logger.sh");
-pub const CORG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
-
 fn read_file(name: &str) -> String {
     let mut file_buffer = String::new();
     let mut file = match File::open(&name) {
@@ -48,22 +51,28 @@ fn read_file(name: &str) -> String {
 }
 
 fn write_corg_logger() {
-    let corg_logger_file_path = "scripts/utils/corg-logger.sh";
-    let corg_logger_sh = File::create(corg_logger_file_path);
-
-    match corg_logger_sh {
-        Ok(mut file_handler) => {
-            println!("Writing logger util to file: {}", &corg_logger_file_path);
-            let _ = file_handler.write_all(b"\n# - start logger:\n");
-            let _ = file_handler.write_all(CORG_LOGGER_SHELL_SCRIPT);
-            let _ = file_handler.write_all(b"\n# - end logger:\n");
+    match corg::write_logger(Path::new("scripts")) {
+        Ok(corg_logger_file_path) => {
+            println!("Writing logger util to file: {}", corg_logger_file_path.display());
         },
         _ => log_error_message("Cannot write log file")
     }
 }
 
-fn play(file_name: &str, clogger: &mut Clog) {
-    let mut app = ui::App::new(file_name, vec![], vec![]);
+/// Opens the TUI for the document, its host panel lists the hosts of the
+/// inventory. Runs aren't started from it yet.
+fn play(file_name: &str, inventory: Option<&str>, clogger: &mut Clog) {
+    let inventory = load_inventory(inventory, clogger);
+    let servers = inventory
+        .hosts
+        .iter()
+        .map(|host| ui::Host {
+            name: &host.name,
+            fqdn: &host.address,
+            scheme: "ssh",
+        })
+        .collect();
+    let mut app = ui::App::new(file_name, vec![], servers);
     let stdout = io::stdout().into_raw_mode().unwrap();
     /// Note: AlternateScreen
     /// Causes the terminal to show a new empty screen for drawing. This is good
@@ -111,7 +120,7 @@ fn play(file_name: &str, clogger: &mut Clog) {
 //    });
 }
 
-fn convert(file: &str, clogger: &mut Clog) {
+fn convert(file: &str, options: ConvertOptions, clogger: &mut Clog) {
     let log_message = format!("Converting {}", &file);
     clogger.info(&log_message);
 
@@ -120,17 +129,24 @@ fn convert(file: &str, clogger: &mut Clog) {
 
     if let Some(file_name) = maybe_file_name.to_str() {
         let corgdown_source = read_file(file);
-        let out_shell_filename = format!("scripts/{}.sh", file_name);
-        let mut le_file = CorgFile::new(&out_shell_filename, &corgdown_source);
-        le_file.push_corgdown();
+        let out_filename = format!("scripts/{}.{}", file_name, options.format.extension());
+
+        let output = match corg::convert(&out_filename, &corgdown_source, &options) {
+            Ok(output) => output,
+            Err(err) => {
+                log_corg_error(file, &err, clogger);
+                clogger.error(&format!("Refusing to convert {}", file));
+                process::exit(1);
+            }
+        };
 
-        match le_file.write_file() {
+        match fs::write(&out_filename, output) {
             Ok(_) => {
-                let message = format!("Wrote file to {}", &le_file.file_name);
+                let message = format!("Wrote file to {}", &out_filename);
                 clogger.success(&message)
             },
             Err(_) => {
-                let message = format!("Le fuck... failed to write file to {}", &le_file.file_name);
+                let message = format!("Le fuck... failed to write file to {}", &out_filename);
                 clogger.error(&message)
             }
         }
@@ -141,6 +157,764 @@ fn convert(file: &str, clogger: &mut Clog) {
     }
 }
 
+/// Prints the structure of the document, as JSON for other tools or as an outline.
+fn inspect(file: &str, options: ConvertOptions, json: bool, clogger: &mut Clog) {
+    let corgdown_source = read_file(file);
+    let document = match corg::parse(&corgdown_source, &options) {
+        Ok(document) => document,
+        Err(err) => {
+            log_corg_error(file, &err, clogger);
+            process::exit(1);
+        }
+    };
+
+    if json {
+        let json = corg::inspect::to_json(&document, &corgdown_source);
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+    } else {
+        print!("{}", corg::inspect::outline(&document, &corgdown_source));
+    }
+}
+
+/// Runs a script, or a markdown document converted on the fly, and streams its
+/// output with the levels of the logger colored like corg's own. In step mode
+/// every step is shown before the script asks what to do with it. The variables
+/// of `run_env` are exported to it and masked in its output. Returns the exit
+/// code of the script.
+fn run(script: &str, options: ConvertOptions, selection: &Selection, step_mode: bool, run_env: &RunEnv, clogger: &mut Clog) -> i32 {
+    let script_path = Path::new(script);
+    let is_markdown = script_path.extension().is_some_and(|extension| extension == "md");
+
+    // Documents run from the current directory, scripts from their own so they
+    // find the logger.
+    let (run_path, run_dir, temp_dir, contents) = if is_markdown {
+        let source = read_file(script);
+        let stem = script_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("runbook");
+        let temp_dir = std::env::temp_dir().join(format!("corg-run-{}", process::id()));
+        let run_path = temp_dir.join(format!("{}.sh", stem));
+
+        let output = match corg::convert(&run_path.to_string_lossy(), &source, &options) {
+            Ok(output) => output,
+            Err(err) => {
+                log_corg_error(script, &err, clogger);
+                return 1;
+            }
+        };
+
+        if let Err(err) = fs::create_dir_all(&temp_dir).and_then(|_| fs::write(&run_path, &output)) {
+            clogger.error(&format!("Failed to write {}: {}", run_path.display(), err));
+            return 1;
+        }
+
+        (run_path, std::path::PathBuf::from("."), Some(temp_dir), output)
+    } else {
+        let dir = match script_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        };
+        let file_name = script_path.file_name().map(std::path::PathBuf::from).unwrap_or_default();
+
+        (file_name, dir, None, read_file(script))
+    };
+
+    let sections = match selected_steps(script, &contents, selection, clogger) {
+        Some(steps) => steps.join(" "),
+        None => {
+            if let Some(temp_dir) = temp_dir {
+                let _ = fs::remove_dir_all(temp_dir);
+            }
+            return 1;
+        }
+    };
+    // The logger writes its events to a private file, see `Transport::temp_file`.
+    let local = LocalTransport::new(&run_dir, &[]);
+    let events_path = match local.temp_file() {
+        Ok(path) => path,
+        Err(err) => {
+            if let Some(temp_dir) = temp_dir {
+                let _ = fs::remove_dir_all(temp_dir);
+            }
+            log_corg_error(script, &err, clogger);
+            return 1;
+        }
+    };
+    let mut env = run_env.env();
+    env.push((corg::selection::SECTIONS_ENV_VAR, sections.as_str()));
+    env.push((corg::events::LOG_FILE_ENV_VAR, &events_path));
+    if step_mode {
+        env.push((corg::step_mode::STEP_MODE_ENV_VAR, "1"));
+    }
+    // Shows the prose and code of steps, only the functions of plain scripts.
+    let document = if step_mode && is_markdown {
+        corg::parse(&read_file(script), &options).ok()
+    } else {
+        None
+    };
+    let record = RunRecord::start(script, &read_file(script), &contents, corg::systemd::LOCAL_HOST, &options, chrono::Utc::now());
+    let mut log = RunLog::create(&Path::new(RUNS_DIR).join(format!("{}.log", record.id)), corg::systemd::LOCAL_HOST);
+
+    clogger.info(&format!("Running {}", script));
+
+    let result = corg::runner::run_script(&run_path, &run_dir, &env, |stream, line| {
+        let line = run_env.mask(line);
+        let line = line.as_ref();
+        log.line(stream, line);
+        if let Some(corg::runner::StepEvent::Paused(slug)) = corg::runner::parse_step_event(line) {
+            print_step(&slug, document.as_ref(), &contents);
+            return;
+        }
+        match (corg::runner::parse_log_line(line), stream) {
+            (Some((level, message)), _) => clogger.log(level, &message),
+            (None, corg::runner::Stream::Stdout) => println!("{}", line),
+            (None, corg::runner::Stream::Stderr) => eprintln!("{}", line),
+        }
+    });
+
+    if let Some(temp_dir) = temp_dir {
+        let _ = fs::remove_dir_all(temp_dir);
+    }
+
+    let events = local.download(&events_path).unwrap_or_default();
+    let _ = local.cleanup();
+    write_events(&log, corg::events::parse_events(&String::from_utf8_lossy(&events)), run_env, clogger);
+
+    finish_record(record, &result, &log, clogger);
+    log_run_result(script, result, clogger)
+}
+
+/// Writes the events of the logger merged with the rest of the output next to
+/// the log of the run, with the values of `run_env` masked. Like the log, it
+/// doesn't fail the run when it can't be written.
+fn write_events(log: &RunLog, events: Vec<LogEvent>, run_env: &RunEnv, clogger: &mut Clog) {
+    if let Err(err) = log.write_events(mask_events(events, run_env)) {
+        clogger.warning(&format!("Failed to write the structured log of the run: {}", err));
+    }
+}
+
+fn mask_events(events: Vec<LogEvent>, run_env: &RunEnv) -> Vec<LogEvent> {
+    events
+        .into_iter()
+        .map(|event| LogEvent {
+            message: run_env.mask(&event.message).into_owned(),
+            ..event
+        })
+        .collect()
+}
+
+/// Shows a step before the script asks what to do with it in step mode.
+fn print_step(slug: &str, document: Option<&corg::document::Document>, script: &str) {
+    let description = match document.and_then(|document| document.step(slug)) {
+        Some(section) => corg::step_mode::describe(section),
+        None => corg::selection::step_function(script, slug).map(String::from).unwrap_or_default(),
+    };
+
+    println!("\n{}", description);
+}
+
+/// Variables of a run on the host, the ones given on the command line win over
+/// the inventory.
+fn host_options(host: &InventoryHost, inventory: &Inventory, options: &ConvertOptions) -> ConvertOptions {
+    let mut vars = inventory.vars(host);
+    vars.extend(&options.vars);
+
+    ConvertOptions { vars, ..options.clone() }
+}
+
+/// Rolls the script out to the hosts of a group, each with the options of its
+/// run, prints a summary of how it went on each of them and returns 1 unless it
+/// succeeded everywhere.
+fn run_group(
+    script: &str,
+    hosts: &[(InventoryHost, ConvertOptions)],
+    ssh: &corg::runner::Ssh,
+    selection: &Selection,
+    fan_out: &FanOut,
+    run_env: &RunEnv,
+    clogger: &mut Clog,
+) -> i32 {
+    // Converted up front, so a broken document doesn't start a rollout.
+    let mut scripts = vec![];
+    for (_, options) in hosts {
+        match standalone_script(script, options, selection, clogger) {
+            Some(standalone) => scripts.push(standalone),
+            None => return 1,
+        }
+    }
+
+    let exported: Vec<String> = scripts.iter().map(|standalone| run_env.export(standalone)).collect();
+    let names: Vec<String> = hosts.iter().map(|(host, _)| host.name.clone()).collect();
+    let addresses: Vec<&str> = hosts.iter().map(|(host, _)| host.address.as_str()).collect();
+    let sshs: Vec<corg::runner::Ssh> = hosts.iter().map(|(host, _)| host.ssh(ssh)).collect();
+    let transports: Vec<CommandTransport> =
+        sshs.iter().zip(addresses.iter()).map(|(ssh, address)| CommandTransport::ssh(ssh, address)).collect();
+
+    // Every host has to be able to run as the users of its script before any starts.
+    for (index, standalone) in scripts.iter().enumerate() {
+        if !check_sudo(standalone, &transports[index], &names[index], run_env, clogger) {
+            return 1;
+        }
+    }
+    let started_at = chrono::Utc::now().format("%Y%m%dT%H%M%S").to_string();
+    let log_dir = Path::new(".corg/logs").join(&started_at);
+
+    clogger.info(&format!("Running {} on {} host(s), logs go to {}", script, hosts.len(), log_dir.display()));
+
+    let result = corg::fanout::fan_out(
+        &names,
+        fan_out,
+        &log_dir,
+        |index, on_line| {
+            let (status, events) = corg::transport::run_script_with_events(
+                &transports[index],
+                &sshs[index].shell,
+                &exported[index],
+                &mut |stream, line| on_line(stream, &run_env.mask(line)),
+            )?;
+            Ok((status, mask_events(events, run_env)))
+        },
+        |name, stream, line| log_host_line(name, stream, line, clogger),
+    );
+
+    let runs = match result {
+        Ok(runs) => runs,
+        Err(err) => {
+            log_corg_error(script, &err, clogger);
+            return 1;
+        }
+    };
+
+    let source = read_file(script);
+    for ((run, standalone), (_, options)) in runs.iter().zip(scripts.iter()).zip(hosts.iter()) {
+        let (started_at, exit_code) = match (run.started_at, run.status) {
+            (Some(started_at), RunStatus::Ok) => (started_at, 0),
+            (Some(started_at), RunStatus::Failed(code)) => (started_at, code),
+            _ => continue,
+        };
+
+        let mut record = RunRecord::start(script, &source, standalone, &run.host, options, started_at);
+        let finished_at = started_at + chrono::Duration::from_std(run.duration).unwrap_or_else(|_| chrono::Duration::zero());
+        record.finish(finished_at, exit_code, &run.steps, &run.finished, Some(&run.log_path));
+        save_record(&record, clogger);
+    }
+
+    print!("\n{}", corg::fanout::summary(&runs));
+
+    let failed = runs.iter().filter(|run| run.failed()).count();
+    if failed == 0 {
+        clogger.success(&format!("Finished {} on {} host(s)", script, runs.len()));
+        0
+    } else {
+        clogger.error(&format!("{} failed or didn't run on {} of {} host(s)", script, failed, runs.len()));
+        1
+    }
+}
+
+/// The script run on other hosts, a document converted with the logger inlined
+/// or a script with its logger inlined, which only runs the selected steps.
+/// Logs why there is none.
+fn standalone_script(script: &str, options: &ConvertOptions, selection: &Selection, clogger: &mut Clog) -> Option<String> {
+    let source = read_file(script);
+
+    let standalone = if Path::new(script).extension().is_some_and(|extension| extension == "md") {
+        match corg::convert(script, &source, options) {
+            Ok(output) => output,
+            Err(err) => {
+                log_corg_error(script, &err, clogger);
+                return None;
+            }
+        }
+    } else {
+        corg::corg_file::inline_logger(&source)
+    };
+
+    match selected_steps(script, &standalone, selection, clogger)? {
+        steps if steps.is_empty() => Some(standalone),
+        steps => Some(corg::selection::export_sections(&standalone, &steps)),
+    }
+}
+
+/// Resolves the selected steps against the ones the script runs, all of them
+/// run when there are none. Logs names which don't match a step.
+fn selected_steps(script: &str, contents: &str, selection: &Selection, clogger: &mut Clog) -> Option<Vec<String>> {
+    if selection.is_empty() {
+        return Some(vec![]);
+    }
+
+    match selection.resolve(&corg::selection::script_steps(contents)) {
+        Ok(steps) => Some(steps),
+        Err(err) => {
+            clogger.error(&format!("{}: {}", script, err));
+            None
+        }
+    }
+}
+
+/// Prints a line of a host's output prefixed with the host, lines of the logger
+/// are logged at their level.
+fn log_host_line(host: &str, stream: corg::runner::Stream, line: &str, clogger: &mut Clog) {
+    match (corg::runner::parse_log_line(line), stream) {
+        (Some((level, message)), _) => clogger.log(level, &format!("{}: {}", host, message)),
+        (None, corg::runner::Stream::Stdout) => println!("{}: {}", host, line),
+        (None, corg::runner::Stream::Stderr) => eprintln!("{}: {}", host, line),
+    }
+}
+
+/// Pipes the standalone script to `shell` on the target named `name`, e.g. a host
+/// over ssh, the lines of its output are prefixed with the name. The variables of
+/// `run_env` are exported at the top of what's piped and masked in the output.
+#[allow(clippy::too_many_arguments)]
+fn run_remote(
+    script: &str,
+    name: &str,
+    transport: &dyn Transport,
+    shell: &str,
+    options: &ConvertOptions,
+    selection: &Selection,
+    run_env: &RunEnv,
+    clogger: &mut Clog,
+) -> i32 {
+    let standalone = match standalone_script(script, options, selection, clogger) {
+        Some(standalone) => standalone,
+        None => return 1,
+    };
+
+    if !check_sudo(&standalone, transport, name, run_env, clogger) {
+        return 1;
+    }
+
+    let record = RunRecord::start(script, &read_file(script), &standalone, name, options, chrono::Utc::now());
+    let mut log = RunLog::create(&Path::new(RUNS_DIR).join(format!("{}.log", record.id)), name);
+
+    clogger.info(&format!("Running {} on {}", script, name));
+
+    let result = corg::transport::run_script_with_events(transport, shell, &run_env.export(&standalone), &mut |stream, line| {
+        let line = run_env.mask(line);
+        let line = line.as_ref();
+        log.line(stream, line);
+        log_host_line(name, stream, line, clogger)
+    });
+    let result = result.map(|(status, events)| {
+        write_events(&log, events, run_env, clogger);
+        status
+    });
+    finish_record(record, &result, &log, clogger);
+
+    match result {
+        // Reported by the transport itself rather than the script, e.g. ssh.
+        Ok(status) if transport.unreachable(&status) => {
+            clogger.error(&format!("{} to {} failed", transport.kind(), name));
+            corg::runner::exit_code(&status)
+        }
+        result => log_run_result(&format!("{} on {}", script, name), result, clogger),
+    }
+}
+
+/// Checks that the target lets the script run code as the users it needs through
+/// sudo, logs why not otherwise.
+fn check_sudo(script: &str, transport: &dyn Transport, name: &str, run_env: &RunEnv, clogger: &mut Clog) -> bool {
+    let users = corg::sudo::script_users(script);
+    if users.is_empty() {
+        return true;
+    }
+
+    let users_text = users.join(", ");
+    match corg::sudo::check(transport, name, &users, run_env) {
+        Ok(SudoAccess::Ready) => true,
+        Ok(SudoAccess::Missing) => {
+            clogger.error(&format!("sudo isn't installed on {}, it's needed to run as {}", name, users_text));
+            false
+        }
+        Ok(SudoAccess::NeedsPassword) if run_env.contains(corg::sudo::BECOME_PASSWORD_ENV_VAR) => {
+            clogger.error(&format!("sudo on {} didn't take the password to run as {}", name, users_text));
+            false
+        }
+        Ok(SudoAccess::NeedsPassword) => {
+            clogger.error(&format!(
+                "sudo on {} needs a password to run as {}, pass --ask-become-pass or allow NOPASSWD",
+                name, users_text
+            ));
+            false
+        }
+        Err(err) => {
+            clogger.error(&err.to_string());
+            false
+        }
+    }
+}
+
+/// Runs the recorded run with the id again from the step it failed in, on the
+/// same host with the same variables. Refuses when the step changed since.
+fn resume(id: &str, inventory: &Inventory, ssh: &corg::runner::Ssh, matches: &clap::ArgMatches, clogger: &mut Clog) -> i32 {
+    let record = match corg::history::find(Path::new(RUNS_DIR), id) {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            clogger.error(&format!("No run {} in {}, see `corg history`", id, RUNS_DIR));
+            return 1;
+        }
+        Err(err) => {
+            clogger.error(&err.to_string());
+            return 1;
+        }
+    };
+
+    let script = record.document.as_str();
+    let options = record.options();
+    let standalone = match standalone_script(script, &options, &Selection::default(), clogger) {
+        Some(standalone) => standalone,
+        None => return 1,
+    };
+    let selection = match record.resume(&standalone) {
+        Ok(selection) => selection,
+        Err(err) => {
+            clogger.error(&format!("Can't resume: {}", err));
+            return 1;
+        }
+    };
+
+    let run_env = load_run_env(matches, script, clogger);
+    clogger.info(&format!("Resuming {} from step '{}'", record.id, selection.sections[0]));
+
+    if record.host == corg::systemd::LOCAL_HOST {
+        run(script, options, &selection, false, &run_env, clogger)
+    } else if let Ok(target) = record.host.parse::<Target>() {
+        run_target(script, &target, &options, &selection, &run_env, clogger)
+    } else {
+        let host = inventory.host(&record.host).cloned().unwrap_or_else(|| InventoryHost::new(&record.host));
+        let ssh = host.ssh(ssh);
+        let transport = CommandTransport::ssh(&ssh, &host.address);
+        run_remote(script, &host.name, &transport, &ssh.shell, &options, &selection, &run_env, clogger)
+    }
+}
+
+/// Pipes the standalone script to zsh in a container, chroot or the namespaces of
+/// a process on this machine, see `run_remote`.
+fn run_target(script: &str, target: &Target, options: &ConvertOptions, selection: &Selection, run_env: &RunEnv, clogger: &mut Clog) -> i32 {
+    match CommandTransport::target(target) {
+        Ok(transport) => run_remote(script, &target.to_string(), &transport, "zsh", options, selection, run_env, clogger),
+        Err(err) => {
+            clogger.error(&err.to_string());
+            1
+        }
+    }
+}
+
+/// Reads the variables exported to a run, from `--env-file`, the secrets
+/// providers of the document and then `--secret`. Exits when one can't be read.
+fn load_run_env(matches: &clap::ArgMatches, script: &str, clogger: &mut Clog) -> RunEnv {
+    let mut run_env = RunEnv::new();
+    let mut providers = vec![];
+
+    if Path::new(script).extension().is_some_and(|extension| extension == "md") {
+        let source = read_file(script);
+        match corg::front_matter::FrontMatter::split(&source).and_then(|(front_matter, _)| corg::run_env::secrets_providers(front_matter.as_ref())) {
+            Ok(document_providers) => providers.extend(document_providers),
+            Err(err) => {
+                log_corg_error(script, &err, clogger);
+                process::exit(1);
+            }
+        }
+    }
+
+    for secret in matches.values_of("secret").into_iter().flatten() {
+        match CorgVars::parse_assignment(secret) {
+            Ok(provider) => providers.push(provider),
+            Err(err) => {
+                clogger.error(&format!("--secret: {}", err));
+                process::exit(1);
+            }
+        }
+    }
+
+    let result = matches
+        .values_of("env-file")
+        .into_iter()
+        .flatten()
+        .try_for_each(|path| run_env.read_env_file(path))
+        .and_then(|_| run_env.read_secrets(&providers));
+
+    if let Err(err) = result {
+        clogger.error(&err.to_string());
+        process::exit(1);
+    }
+
+    if matches.is_present("ask-become-pass") {
+        let password = ask_become_pass(clogger);
+        // A valid name, it can't fail.
+        let _ = run_env.insert(corg::sudo::BECOME_PASSWORD_ENV_VAR, &password);
+    }
+
+    run_env
+}
+
+/// Asks for the sudo password on the terminal without echoing it. Exits when
+/// there's no terminal or no answer.
+fn ask_become_pass(clogger: &mut Clog) -> String {
+    use termion::input::TermRead;
+
+    if !termion::is_tty(&io::stdin()) {
+        clogger.error("--ask-become-pass asks for the sudo password, it needs a terminal");
+        process::exit(1);
+    }
+
+    print!("sudo password: ");
+    let _ = io::stdout().flush();
+    let password = io::stdin().read_passwd(&mut io::stdout());
+    println!();
+
+    match password {
+        Ok(Some(password)) => password,
+        _ => {
+            clogger.error("No sudo password given");
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads the inventory given with `--inventory`, or `corg.hosts.toml` when there
+/// is one. Exits when it's invalid.
+fn load_inventory(path: Option<&str>, clogger: &mut Clog) -> Inventory {
+    let default_path = Path::new(corg::inventory::INVENTORY_FILE_NAME);
+    let path = match path {
+        Some(path) => Path::new(path),
+        None if default_path.exists() => default_path,
+        None => return Inventory::default(),
+    };
+
+    match Inventory::load(path) {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            clogger.error(&err.to_string());
+            process::exit(1);
+        }
+    }
+}
+
+/// The hosts `--host` or `--group` select, none for a run on this machine. Hosts
+/// which aren't in the inventory are passed on to ssh as they are.
+fn run_hosts(matches: &clap::ArgMatches, inventory: &Inventory, clogger: &mut Clog) -> Vec<InventoryHost> {
+    if let Some(name) = matches.value_of("group") {
+        return match inventory.group(name) {
+            Some(group) => group.hosts.iter().filter_map(|host| inventory.host(host)).cloned().collect(),
+            None => {
+                clogger.error(&format!("Unknown group '{}', add it to `groups` in the inventory", name));
+                process::exit(1);
+            }
+        };
+    }
+
+    match matches.value_of("host") {
+        Some(name) if name != corg::systemd::LOCAL_HOST => {
+            vec![inventory.host(name).cloned().unwrap_or_else(|| InventoryHost::new(name))]
+        }
+        _ => vec![],
+    }
+}
+
+/// How `--group` rolls a run out, from `--parallel`, `--fail-fast`,
+/// `--max-failures` and `--canary`.
+fn fan_out_settings(matches: &clap::ArgMatches, clogger: &mut Clog) -> FanOut {
+    let mut number = |name: &str, max: usize| -> Option<usize> {
+        match matches.value_of(name).map(str::parse::<usize>) {
+            Some(Ok(value)) if value <= max => Some(value),
+            Some(_) => {
+                clogger.error(&format!("--{} must be a number between 0 and {}", name, max));
+                process::exit(1);
+            }
+            None => None,
+        }
+    };
+
+    FanOut {
+        parallel: number("parallel", usize::MAX).unwrap_or(1).max(1),
+        fail_fast: matches.is_present("fail-fast"),
+        max_failures: number("max-failures", 100).map(|percent| percent as u8),
+        canaries: number("canary", usize::MAX).unwrap_or(0),
+    }
+}
+
+/// Records how a run which started ended, nothing is recorded when the script
+/// couldn't be started.
+fn finish_record(mut record: RunRecord, result: &Result<process::ExitStatus, CorgError>, log: &RunLog, clogger: &mut Clog) {
+    if let Ok(status) = result {
+        record.finish(chrono::Utc::now(), corg::runner::exit_code(status), &log.steps, &log.finished, log.written());
+        save_record(&record, clogger);
+    }
+}
+
+/// Keeps the record of a run in `.corg/runs`, a record which can't be written
+/// doesn't fail the run.
+fn save_record(record: &RunRecord, clogger: &mut Clog) {
+    if let Err(err) = corg::history::save(Path::new(RUNS_DIR), record) {
+        clogger.warning(&format!("Failed to record the run: {}", err));
+    }
+}
+
+/// Lists the recorded runs matching the filter, the latest last.
+fn history(filter: &HistoryFilter, limit: Option<usize>, json: bool, clogger: &mut Clog) {
+    let records = match corg::history::load(Path::new(RUNS_DIR)) {
+        Ok(records) => records,
+        Err(err) => {
+            clogger.error(&err.to_string());
+            process::exit(1);
+        }
+    };
+
+    let mut records: Vec<RunRecord> = records.into_iter().filter(|record| filter.matches(record)).collect();
+    if let Some(limit) = limit {
+        records.drain(..records.len().saturating_sub(limit));
+    }
+
+    if json {
+        let records: Vec<serde_json::Value> = records.iter().map(RunRecord::to_json).collect();
+        println!("{}", serde_json::to_string_pretty(&records).unwrap());
+    } else if records.is_empty() {
+        clogger.info("No runs recorded yet");
+    } else {
+        print!("{}", corg::history::table(&records));
+    }
+}
+
+/// Parses the time of `--since`, a date is its midnight in UTC.
+fn parse_since(since: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Some(time.with_timezone(&chrono::Utc));
+    }
+
+    let date = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d").ok()?;
+    Some(chrono::DateTime::from_utc(date.and_hms(0, 0, 0), chrono::Utc))
+}
+
+/// Logs how a run ended and returns the exit code corg leaves with.
+fn log_run_result(name: &str, result: Result<process::ExitStatus, CorgError>, clogger: &mut Clog) -> i32 {
+    match result {
+        Ok(status) if status.success() => {
+            clogger.success(&format!("Finished {}", name));
+            0
+        }
+        Ok(status) => {
+            let code = corg::runner::exit_code(&status);
+            clogger.error(&format!("{} failed with exit code {}", name, code));
+            code
+        }
+        Err(err) => {
+            log_corg_error(name, &err, clogger);
+            1
+        }
+    }
+}
+
+/// Writes the script and systemd units running the document on a schedule.
+fn schedule(file: &str, options: ConvertOptions, on_calendar: Option<&str>, host: &str, out_dir: &str, clogger: &mut Clog) {
+    clogger.info(&format!("Scheduling {}", file));
+
+    let corgdown_source = read_file(file);
+    match corg::schedule(Path::new(file), &corgdown_source, &options, on_calendar, host, Path::new(out_dir)) {
+        Ok(paths) => {
+            for path in paths.iter() {
+                clogger.success(&format!("Wrote file to {}", path.display()));
+            }
+            if let Some(timer) = paths.last().and_then(|timer| timer.file_name()) {
+                clogger.info(&format!(
+                    "Install the units into /etc/systemd/system and run `systemctl enable --now {}`",
+                    timer.to_string_lossy()
+                ));
+            }
+        }
+        Err(err) => {
+            log_corg_error(file, &err, clogger);
+            process::exit(1);
+        }
+    }
+}
+
+/// Checks the document for secrets, returns true when nothing was found.
+fn lint(file: &str, clogger: &mut Clog) -> bool {
+    let corgdown_source = read_file(file);
+    let findings = match corg::lint(&corgdown_source) {
+        Ok(findings) => findings,
+        Err(err) => {
+            log_corg_error(file, &err, clogger);
+            return false;
+        }
+    };
+
+    if findings.is_empty() {
+        clogger.success(&format!("No problems found in {}", file));
+        true
+    } else {
+        log_corg_error(file, &CorgError::Secrets(findings), clogger);
+        false
+    }
+}
+
+/// Logs an error prefixed with the markdown file so editors can jump to it.
+fn log_corg_error(file: &str, err: &CorgError, clogger: &mut Clog) {
+    match err {
+        CorgError::Secrets(findings) => {
+            for finding in findings {
+                clogger.error(&format!("{}:{}", file, finding));
+            }
+        }
+        CorgError::Io(_) | CorgError::Vars(_) | CorgError::Schedule(_) | CorgError::Inventory(_) => clogger.error(&format!("{}: {}", file, err)),
+        _ => clogger.error(&format!("{}:{}", file, err)),
+    }
+}
+
+/// Collects the variables given with `--vars-file` and `--var`, in that order so
+/// single variables win over the ones from files.
+fn cli_vars(matches: &clap::ArgMatches, clogger: &mut Clog) -> CorgVars {
+    let mut vars = CorgVars::new();
+
+    for path in matches.values_of("vars-file").into_iter().flatten() {
+        match CorgVars::read_file(path) {
+            Ok(file_vars) => vars.extend(&file_vars),
+            Err(err) => {
+                clogger.error(&err.to_string());
+                process::exit(1);
+            }
+        }
+    }
+
+    for assignment in matches.values_of("var").into_iter().flatten() {
+        match CorgVars::parse_assignment(assignment) {
+            Ok((key, value)) => vars.insert(&key, &value),
+            Err(err) => {
+                clogger.error(&format!("--var {}", err));
+                process::exit(1);
+            }
+        }
+    }
+
+    vars
+}
+
+/// Arguments setting the variables of a document, shared by the subcommands
+/// reading documents.
+fn vars_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("var")
+            .long("var")
+            .value_name("KEY=VALUE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Sets a variable used for {{ KEY }} placeholders, wins over all other sources."),
+        Arg::with_name("vars-file")
+            .long("vars-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Reads variables from a file with one KEY=VALUE per line."),
+        Arg::with_name("env")
+            .short("e")
+            .long("env")
+            .value_name("ENVIRONMENT")
+            .takes_value(true)
+            .help("Overlays the front matter variables with the ones of `environments.<ENVIRONMENT>`."),
+    ]
+}
+
 fn log_error_message(message: &str) {
     println!("Error! {}", message)
 }
@@ -162,6 +936,94 @@ fn main() {
                 .takes_value(true)
                 .help("Converts the given markdown file into an executable shell script."),
         )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert a markdown document into an executable shell script")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .index(1)
+                        .help("Path of the markdown document to convert, e.g. examples/nix.md")
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .takes_value(true)
+                        .possible_values(&["shell", "make", "just", "ansible", "cloud-init", "cloud-init-mime", "dockerfile"])
+                        .default_value("shell")
+                        .help("Writes a zsh script, a Makefile or justfile with a target per step, an Ansible playbook, cloud-init user-data or a Dockerfile.")
+                )
+                .arg(
+                    Arg::with_name("base")
+                        .long("base")
+                        .value_name("IMAGE")
+                        .takes_value(true)
+                        .help("Image the Dockerfile builds on, e.g. debian:12 (the default).")
+                )
+                .args(&vars_args())
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Show the sections, code blocks and links of a markdown document")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .index(1)
+                        .help("Path of the markdown document to inspect, e.g. examples/nix.md")
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints the structure of the document as JSON.")
+                )
+                .args(&vars_args())
+        )
+        .subcommand(
+            SubCommand::with_name("schedule")
+                .about("Write systemd units running a markdown document on a schedule")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .index(1)
+                        .help("Path of the markdown document to schedule, e.g. examples/certs.md")
+                )
+                .arg(
+                    Arg::with_name("on-calendar")
+                        .long("on-calendar")
+                        .value_name("CALENDAR")
+                        .takes_value(true)
+                        .help("When to run, e.g. daily or 'Mon *-*-* 04:00', wins over `schedule.on_calendar`.")
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .value_name("HOST")
+                        .takes_value(true)
+                        .default_value("local")
+                        .help("Where the script runs, `local` or a host to reach over ssh.")
+                )
+                .arg(
+                    Arg::with_name("out-dir")
+                        .long("out-dir")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .default_value("units")
+                        .help("Directory the script and units are written to.")
+                )
+                .args(&vars_args())
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Check markdown documents for secrets before they get committed")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .multiple(true)
+                        .index(1)
+                        .help("Paths of the markdown documents to check")
+                )
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Run a corg script locally or remotely")
@@ -173,7 +1035,85 @@ fn main() {
                         .long("script")
                         .value_name("CORG_SCRIPT")
                         .takes_value(true)
-                        .help("Path of the Corg shell script to execute, e.g. scripts/nix.sh")
+                        .required_unless_one(&["FILE", "resume"])
+                        .conflicts_with("FILE")
+                        .help("Path of the Corg shell script to execute, e.g. scripts/nix.sh, or of a markdown document to convert and run")
+                )
+                .arg(
+                    Arg::with_name("FILE")
+                        .index(1)
+                        .help("Same as --script")
+                )
+                .arg(
+                    Arg::with_name("env-file")
+                        .long("env-file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Exports the NAME=value lines of the file to the script when it runs, their values are masked in the output.")
+                )
+                .arg(
+                    Arg::with_name("secret")
+                        .long("secret")
+                        .value_name("NAME=COMMAND")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Exports what the command prints as NAME to the script when it runs, masked in the output, e.g. --secret 'DB_PASSWORD=pass show prod/db'.")
+                )
+                .arg(
+                    Arg::with_name("become")
+                        .long("become")
+                        .value_name("USER")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .validator(|user| if corg::document::is_user_name(&user) { Ok(()) } else { Err(format!("'{}' isn't a user name", user)) })
+                        .help("Runs the code blocks which don't have a {sudo} of their own as USER (root by default) through sudo, e.g. --become=postgres.")
+                )
+                .arg(
+                    Arg::with_name("ask-become-pass")
+                        .short("K")
+                        .long("ask-become-pass")
+                        .help("Asks for the sudo password before the run, for hosts where sudo needs one. It's masked in the output.")
+                )
+                .arg(
+                    Arg::with_name("step")
+                        .long("step")
+                        .conflicts_with_all(&["host", "group", "target", "resume"])
+                        .help("Shows every step before it runs and asks whether to run, skip or edit it, start a shell or abort. Local runs only.")
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .value_name("RUN_ID")
+                        .takes_value(true)
+                        .conflicts_with_all(&["script", "FILE", "group", "host", "target", "section", "from", "until", "env", "var", "vars-file", "become"])
+                        .help("Runs a failed run again from the step it failed in, on the same host with the same variables. See `corg history` for the ids.")
+                )
+                .arg(
+                    Arg::with_name("section")
+                        .long("section")
+                        .value_name("SECTION")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only runs the step with this slug or heading, can be given more than once.")
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("SECTION")
+                        .takes_value(true)
+                        .help("Runs the steps starting with this one.")
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .value_name("SECTION")
+                        .takes_value(true)
+                        .help("Runs the steps up to and including this one.")
                 )
                 .arg(
                     Arg::with_name("host")
@@ -183,13 +1123,283 @@ fn main() {
                         .takes_value(true)
                         .help("The hostname where the script will be executed (requires ssh).")
                 )
+                .arg(
+                    Arg::with_name("group")
+                        .short("g")
+                        .long("group")
+                        .value_name("GROUP")
+                        .takes_value(true)
+                        .conflicts_with("host")
+                        .help("Runs the script on every host of a group of the inventory, on up to --parallel hosts at once after the --canary ones.")
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .value_name("KIND:WHERE")
+                        .takes_value(true)
+                        .conflicts_with_all(&["host", "group"])
+                        .validator(|value| value.parse::<Target>().map(|_| ()))
+                        .help("Runs the script in podman:CONTAINER, chroot:DIR or nsenter:PID on this machine.")
+                )
+                .arg(
+                    Arg::with_name("parallel")
+                        .long("parallel")
+                        .value_name("N")
+                        .takes_value(true)
+                        .requires("group")
+                        .help("Runs on up to N hosts of the group at the same time, defaults to 1.")
+                )
+                .arg(
+                    Arg::with_name("fail-fast")
+                        .long("fail-fast")
+                        .requires("group")
+                        .help("Doesn't start any more hosts once one failed.")
+                )
+                .arg(
+                    Arg::with_name("max-failures")
+                        .long("max-failures")
+                        .value_name("PERCENT")
+                        .takes_value(true)
+                        .requires("group")
+                        .help("Doesn't start any more hosts once more than PERCENT of the group failed.")
+                )
+                .arg(
+                    Arg::with_name("canary")
+                        .long("canary")
+                        .value_name("N")
+                        .takes_value(true)
+                        .requires("group")
+                        .help("Runs on the first N hosts one at a time before the rest, stops if one of them fails.")
+                )
+                .arg(
+                    Arg::with_name("inventory")
+                        .long("inventory")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Inventory of hosts and groups, defaults to corg.hosts.toml when it exists.")
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .short("p")
+                        .long("port")
+                        .value_name("PORT")
+                        .takes_value(true)
+                        .help("Port of the host's ssh server, defaults to the one of ~/.ssh/config.")
+                )
+                .arg(
+                    Arg::with_name("identity")
+                        .short("i")
+                        .long("identity")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Private key ssh authenticates with.")
+                )
+                .arg(
+                    Arg::with_name("ssh-option")
+                        .short("o")
+                        .long("ssh-option")
+                        .value_name("KEY=VALUE")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Passes an option on to ssh, e.g. StrictHostKeyChecking=accept-new.")
+                )
+                .args(&vars_args())
+        )
+        .subcommand(
+            SubCommand::with_name("play")
+                .about("Open the TUI with the hosts of the inventory")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The document shown in the TUI.")
+                        .required(true)
+                        .index(1)
+                )
+                .arg(
+                    Arg::with_name("inventory")
+                        .long("inventory")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Inventory of hosts and groups, defaults to corg.hosts.toml when it exists.")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List the runs recorded in .corg/runs")
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .value_name("HOST")
+                        .takes_value(true)
+                        .help("Only lists runs on this host, `local` for runs on this machine.")
+                )
+                .arg(
+                    Arg::with_name("document")
+                        .long("document")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Only lists runs of documents whose path contains PATH.")
+                )
+                .arg(
+                    Arg::with_name("failed")
+                        .long("failed")
+                        .help("Only lists failed runs.")
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("DATE")
+                        .takes_value(true)
+                        .help("Only lists runs started on or after DATE, e.g. 2026-10-01 or an RFC 3339 time.")
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .short("n")
+                        .long("limit")
+                        .value_name("N")
+                        .takes_value(true)
+                        .help("Only lists the latest N runs.")
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints the records as JSON.")
+                )
         )
         .get_matches();
 
     if let Some(file) = matches.value_of("convert") {
-        convert(file, &mut clogger);
-    } else if let Some(file) = matches.subcommand_matches("run") {
-        play("Fart Salads", &mut clogger);
+        convert(file, ConvertOptions::default(), &mut clogger);
+    } else if let Some(convert_matches) = matches.subcommand_matches("convert") {
+        let options = ConvertOptions {
+            vars: cli_vars(convert_matches, &mut clogger),
+            environment: convert_matches.value_of("env").map(String::from),
+            format: convert_matches.value_of("format").and_then(Format::parse).unwrap_or_default(),
+            base_image: convert_matches.value_of("base").map(String::from),
+            ..ConvertOptions::default()
+        };
+        let file = convert_matches.value_of("FILE").unwrap();
+        convert(file, options, &mut clogger);
+    } else if let Some(inspect_matches) = matches.subcommand_matches("inspect") {
+        let options = ConvertOptions {
+            vars: cli_vars(inspect_matches, &mut clogger),
+            environment: inspect_matches.value_of("env").map(String::from),
+            ..ConvertOptions::default()
+        };
+        let file = inspect_matches.value_of("FILE").unwrap();
+        inspect(file, options, inspect_matches.is_present("json"), &mut clogger);
+    } else if let Some(schedule_matches) = matches.subcommand_matches("schedule") {
+        let options = ConvertOptions {
+            vars: cli_vars(schedule_matches, &mut clogger),
+            environment: schedule_matches.value_of("env").map(String::from),
+            ..ConvertOptions::default()
+        };
+        schedule(
+            schedule_matches.value_of("FILE").unwrap(),
+            options,
+            schedule_matches.value_of("on-calendar"),
+            schedule_matches.value_of("host").unwrap(),
+            schedule_matches.value_of("out-dir").unwrap(),
+            &mut clogger,
+        );
+    } else if let Some(lint_matches) = matches.subcommand_matches("lint") {
+        let mut clean = true;
+        for file in lint_matches.values_of("FILE").unwrap() {
+            clean = lint(file, &mut clogger) && clean;
+        }
+
+        if !clean {
+            process::exit(1);
+        }
+    } else if let Some(play_matches) = matches.subcommand_matches("play") {
+        play(play_matches.value_of("FILE").unwrap(), play_matches.value_of("inventory"), &mut clogger);
+    } else if let Some(history_matches) = matches.subcommand_matches("history") {
+        let since = history_matches.value_of("since").map(|since| match parse_since(since) {
+            Some(since) => since,
+            None => {
+                clogger.error(&format!("--since expects a date like 2026-10-01, got '{}'", since));
+                process::exit(1);
+            }
+        });
+        let limit = match history_matches.value_of("limit").map(str::parse::<usize>) {
+            Some(Ok(limit)) => Some(limit),
+            Some(Err(_)) => {
+                clogger.error("--limit must be a number");
+                process::exit(1);
+            }
+            None => None,
+        };
+        let filter = HistoryFilter {
+            host: history_matches.value_of("host").map(String::from),
+            document: history_matches.value_of("document").map(String::from),
+            failed: history_matches.is_present("failed"),
+            since,
+        };
+        history(&filter, limit, history_matches.is_present("json"), &mut clogger);
+    } else if let Some(run_matches) = matches.subcommand_matches("run") {
+        let options = ConvertOptions {
+            vars: cli_vars(run_matches, &mut clogger),
+            environment: run_matches.value_of("env").map(String::from),
+            standalone: true,
+            become_user: if run_matches.is_present("become") {
+                Some(String::from(run_matches.value_of("become").unwrap_or(corg::document::DEFAULT_SUDO_USER)))
+            } else {
+                None
+            },
+            ..ConvertOptions::default()
+        };
+        let port = match run_matches.value_of("port").map(str::parse::<u16>) {
+            Some(Ok(port)) => Some(port),
+            Some(Err(_)) => {
+                clogger.error("--port must be a number between 1 and 65535");
+                process::exit(1);
+            }
+            None => None,
+        };
+        let ssh = corg::runner::Ssh {
+            port,
+            identity: run_matches.value_of("identity").map(String::from),
+            options: run_matches.values_of("ssh-option").map(|values| values.map(String::from).collect()).unwrap_or_default(),
+            ..corg::runner::Ssh::default()
+        };
+        let inventory = load_inventory(run_matches.value_of("inventory"), &mut clogger);
+        let hosts = run_hosts(run_matches, &inventory, &mut clogger);
+        if run_matches.is_present("step") && !termion::is_tty(&io::stdin()) {
+            clogger.error("--step asks what to do before every step, it needs a terminal");
+            process::exit(1);
+        }
+        if let Some(id) = run_matches.value_of("resume") {
+            process::exit(resume(id, &inventory, &ssh, run_matches, &mut clogger));
+        }
+
+        let script = run_matches.value_of("FILE").or_else(|| run_matches.value_of("script")).unwrap();
+        let selection = Selection {
+            sections: run_matches.values_of("section").map(|values| values.map(String::from).collect()).unwrap_or_default(),
+            from: run_matches.value_of("from").map(String::from),
+            until: run_matches.value_of("until").map(String::from),
+        };
+        let run_env = load_run_env(run_matches, script, &mut clogger);
+        let code = if run_matches.is_present("group") {
+            let fan_out = fan_out_settings(run_matches, &mut clogger);
+            let hosts: Vec<(InventoryHost, ConvertOptions)> = hosts
+                .into_iter()
+                .map(|host| {
+                    let options = host_options(&host, &inventory, &options);
+                    (host, options)
+                })
+                .collect();
+            run_group(script, &hosts, &ssh, &selection, &fan_out, &run_env, &mut clogger)
+        } else if let Some(host) = hosts.first() {
+            let host_ssh = host.ssh(&ssh);
+            let transport = CommandTransport::ssh(&host_ssh, &host.address);
+            let options = host_options(host, &inventory, &options);
+            run_remote(script, &host.name, &transport, &host_ssh.shell, &options, &selection, &run_env, &mut clogger)
+        } else if let Some(target) = run_matches.value_of("target") {
+            run_target(script, &target.parse().unwrap(), &options, &selection, &run_env, &mut clogger)
+        } else {
+            run(script, options, &selection, run_matches.is_present("step"), &run_env, &mut clogger)
+        };
+        process::exit(code);
     } else {
         println!("No command found, please see --help for usage information");
     };
@@ -0,0 +1,186 @@
+use crate::document::{Document, Section};
+use crate::shell;
+use crate::{CORG_LOGGER_PATH, CORG_VERSION};
+use chrono::prelude::*;
+use std::collections::HashSet;
+
+/// Renders a document as a Makefile for GNU make. Every step becomes a target
+/// running its code blocks in one zsh (through `.ONESHELL`), the steps it
+/// `needs` become prerequisites and `all` runs them in document order.
+pub fn push_makefile(s: &mut String, file_name: &str, document: &Document) {
+    push_header(s, file_name);
+
+    s.push_str("SHELL := /bin/zsh\n");
+    s.push_str(".SHELLFLAGS := -ec\n");
+    s.push_str(".ONESHELL:\n");
+    // The logger lives next to the Makefile, which isn't the working directory
+    // when it's run with `make -f`.
+    s.push_str("CORG_DIR := $(dir $(lastword $(MAKEFILE_LIST)))\n\n");
+
+    let steps = document.steps();
+    let targets = target_names(&steps);
+    s.push_str(&format!(".PHONY: all {}\n\n", targets.join(" ")));
+    s.push_str(&format!("all: {}\n", targets.join(" ")));
+
+    for (step, target) in steps.iter().zip(targets.iter()) {
+        s.push_str(&format!("\n# {}\n", step.heading.text));
+        s.push_str(&format!("{}:{}\n", target, prerequisites(step, &steps, &targets)));
+        s.push_str(&format!("\tsource $(CORG_DIR){}\n", CORG_LOGGER_PATH));
+
+        for line in step_script(document, step).lines() {
+            s.push_str(&format!("\t{}\n", line.replace('$', "$$")));
+        }
+    }
+}
+
+/// Renders a document as a justfile. Every step becomes a zsh shebang recipe
+/// with the steps it `needs` as dependencies, `all` runs them in document order
+/// and is the default recipe.
+pub fn push_justfile(s: &mut String, file_name: &str, document: &Document) {
+    push_header(s, file_name);
+
+    let steps = document.steps();
+    let targets = target_names(&steps);
+    s.push_str("# Runs every step in document order\n");
+    s.push_str(&format!("all: {}\n", targets.join(" ")));
+
+    for (step, target) in steps.iter().zip(targets.iter()) {
+        // Comments right above a recipe are shown by `just --list`.
+        s.push_str(&format!("\n# {}\n", step.heading.text));
+        s.push_str(&format!("{}:{}\n", target, prerequisites(step, &steps, &targets)));
+        s.push_str("\t#!/usr/bin/env zsh\n");
+        s.push_str("\tset -e\n");
+        // Recipes run in the directory of the justfile, next to the logger.
+        s.push_str(&format!("\tsource ./{}\n", CORG_LOGGER_PATH));
+
+        for line in step_script(document, step).lines() {
+            if line.is_empty() {
+                s.push('\n');
+            } else {
+                s.push_str(&format!("\t{}\n", line.replace("{{", "{{{{")));
+            }
+        }
+    }
+}
+
+fn push_header(s: &mut String, file_name: &str) {
+    s.push_str(&format!("# -- GENERATED BY CORG v{} --\n", CORG_VERSION));
+    s.push_str(&format!("# filename: {}\n", file_name));
+    s.push_str(&format!("# date: {}\n\n", Utc::now().to_rfc3339()));
+}
+
+fn step_script(document: &Document, step: &Section) -> String {
+    let mut script = String::new();
+    shell::push_step_script(&mut script, document, step);
+    script
+}
+
+/// Returns the prerequisites of the step's target, with a leading space. A need
+/// is the first step with its slug, like for `Document::step`.
+fn prerequisites(step: &Section, steps: &[&Section], targets: &[String]) -> String {
+    step.heading
+        .needs
+        .iter()
+        .map(|need| match steps.iter().position(|step| step.slug == *need) {
+            Some(index) => format!(" {}", targets[index]),
+            None => format!(" {}", target_name(need)),
+        })
+        .collect()
+}
+
+/// Returns the targets of the steps in order. Make and just both choke on a
+/// target defined twice, so a step whose name is taken already (by `all` or an
+/// earlier step, e.g. "What's up" and "What-s up") gets a `-2`, `-3`... suffix.
+fn target_names(steps: &[&Section]) -> Vec<String> {
+    let mut taken: HashSet<String> = HashSet::new();
+    taken.insert(String::from("all"));
+
+    steps
+        .iter()
+        .map(|step| {
+            let name = target_name(&step.slug);
+            let mut target = name.clone();
+            let mut suffix = 2;
+            while !taken.insert(target.clone()) {
+                target = format!("{}-{}", name, suffix);
+                suffix += 1;
+            }
+            target
+        })
+        .collect()
+}
+
+/// Returns a name for the step which make and just both accept as a target.
+fn target_name(slug: &str) -> String {
+    let name: String = slug
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+
+    match name.chars().next() {
+        Some(first) if first.is_ascii_alphabetic() => name,
+        _ => format!("step-{}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vars::CorgVars;
+
+    const SOURCE: &str = "# Faye\n\n## Install Nix\n\n```shell\ncurl nix | sh\necho $HOME {{.State.Running}}\n```\n\n\
+                          ## Deploy {needs=\"install-nix\"}\n\n```shell {retry=3}\nnixos-rebuild switch\n```\n";
+
+    fn document() -> Document {
+        Document::parse(SOURCE, &CorgVars::new(), None).unwrap()
+    }
+
+    #[test]
+    fn test_push_makefile() {
+        let mut makefile = String::new();
+        push_makefile(&mut makefile, "faye.mk", &document());
+
+        assert!(makefile.contains(".PHONY: all install-nix deploy\n\nall: install-nix deploy\n"));
+        assert!(makefile.contains("\n# Install Nix\ninstall-nix:\n\tsource $(CORG_DIR)utils/corg-logger.sh\n"));
+        assert!(makefile.contains("\t\techo $$HOME {{.State.Running}}\n"));
+        assert!(makefile.contains("\n# Deploy\ndeploy: install-nix\n"));
+        assert!(makefile.contains("\tcorg_retry 3 fixed 1 'deploy#1' corg_block_1\n"));
+    }
+
+    #[test]
+    fn test_push_justfile() {
+        let mut justfile = String::new();
+        push_justfile(&mut justfile, "faye.just", &document());
+
+        assert!(justfile.contains("# Runs every step in document order\nall: install-nix deploy\n"));
+        assert!(justfile.contains("install-nix:\n\t#!/usr/bin/env zsh\n\tset -e\n\tsource ./utils/corg-logger.sh\n"));
+        assert!(justfile.contains("\t\techo $HOME {{{{.State.Running}}\n"));
+        assert!(justfile.contains("\n# Deploy\ndeploy: install-nix\n"));
+    }
+
+    #[test]
+    fn test_target_names_collide() {
+        let source = "# Faye\n\n## All\n\n```shell\necho all\n```\n\n## What's up\n\n```shell\necho up\n```\n\n\
+                      ## What-s up {needs=\"all\"}\n\n```shell\necho up\n```\n";
+        let document = Document::parse(source, &CorgVars::new(), None).unwrap();
+
+        let mut makefile = String::new();
+        push_makefile(&mut makefile, "faye.mk", &document);
+        assert!(makefile.contains(".PHONY: all all-2 what-s-up what-s-up-2\n\nall: all-2 what-s-up what-s-up-2\n"));
+        assert!(makefile.contains("\n# All\nall-2:\n"));
+        assert!(makefile.contains("\n# What-s up\nwhat-s-up-2: all-2\n"));
+
+        let mut justfile = String::new();
+        push_justfile(&mut justfile, "faye.just", &document);
+        assert!(justfile.contains("all: all-2 what-s-up what-s-up-2\n"));
+        assert!(justfile.contains("\n# What-s up\nwhat-s-up-2: all-2\n"));
+        assert_eq!(justfile.matches("\nall:").count(), 1);
+    }
+
+    #[test]
+    fn test_target_name() {
+        assert_eq!(target_name("install-nix"), "install-nix");
+        assert_eq!(target_name("what's-up?"), "what-s-up-");
+        assert_eq!(target_name("1st-boot"), "step-1st-boot");
+    }
+}
@@ -0,0 +1,258 @@
+use crate::corg_error::CorgError;
+use crate::front_matter::{scalar_to_string, FrontMatter};
+use crate::sudo;
+use crate::vars::CorgVars;
+use serde_yaml::Value;
+use std::borrow::Cow;
+use std::process::{Command, Stdio};
+
+/// Front matter key holding the commands which print secrets, by the name of the
+/// variable they're exported as:
+///
+/// ```yaml
+/// secrets:
+///   DB_PASSWORD: pass show prod/db
+///   API_TOKEN: sops -d --extract '["token"]' secrets.yaml
+/// ```
+pub const SECRETS_FRONT_MATTER_KEY: &str = "secrets";
+
+/// Environment variable naming the variables of a run, separated by commas, so
+/// `corg_sudo` keeps them for the user it runs as.
+pub const ENV_VARS_ENV_VAR: &str = "CORG_ENV_VARS";
+
+/// Values shorter than this aren't masked, they'd hide too much of the output.
+const MIN_MASKED_LENGTH: usize = 4;
+
+/// Replaces masked values in the output.
+const MASK: &str = "***";
+
+/// Variables exported to a script when it runs rather than written into it, from
+/// `--env-file` files and secrets providers. Their values are masked in the
+/// output of the run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunEnv {
+    /// Names and values in the order they were added, later ones win.
+    pub vars: Vec<(String, String)>,
+    /// Values to mask, longest first.
+    masked: Vec<String>,
+    /// Names of the variables for `ENV_VARS_ENV_VAR`, the become password stays
+    /// with sudo.
+    names: String,
+}
+
+impl RunEnv {
+    pub fn new() -> RunEnv {
+        RunEnv::default()
+    }
+
+    /// Reads an env file with one `NAME=value` per line, `#` starts a comment and
+    /// `export ` in front of a line is ignored.
+    pub fn read_env_file(&mut self, path: &str) -> Result<(), CorgError> {
+        let vars = CorgVars::read_file(path).map_err(|err| CorgError::Env(err.to_string()))?;
+
+        for (name, value) in vars.entries() {
+            self.insert(name, value).map_err(|err| CorgError::Env(format!("{}: {}", path, err)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the commands of the providers and adds what they print, without the
+    /// trailing newline. The commands run with `sh -c` from the current
+    /// directory, so they can prompt on the terminal.
+    pub fn read_secrets(&mut self, providers: &[(String, String)]) -> Result<(), CorgError> {
+        for (name, command) in providers {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(|err| CorgError::Env(format!("secrets.{}: failed to run `{}`: {}", name, command, err)))?;
+
+            if !output.status.success() {
+                let status = match output.status.code() {
+                    Some(code) => format!("exit code {}", code),
+                    None => String::from("a signal"),
+                };
+                return Err(CorgError::Env(format!("secrets.{}: `{}` failed with {}", name, command, status)));
+            }
+
+            let value = String::from_utf8(output.stdout)
+                .map_err(|_| CorgError::Env(format!("secrets.{}: `{}` didn't print UTF-8", name, command)))?;
+            let value = value.strip_suffix('\n').unwrap_or(&value);
+            let value = value.strip_suffix('\r').unwrap_or(value);
+
+            self.insert(name, value).map_err(|err| CorgError::Env(format!("secrets.{}: {}", name, err)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a variable, names have to be valid shell variable names.
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("'{}' is not a valid environment variable name", name));
+        }
+
+        self.vars.retain(|(existing, _)| existing != name);
+        self.vars.push((String::from(name), String::from(value)));
+
+        for line in value.lines().filter(|line| line.len() >= MIN_MASKED_LENGTH) {
+            if !self.masked.iter().any(|masked| masked == line) {
+                self.masked.push(String::from(line));
+            }
+        }
+        self.masked.sort_by_key(|masked| std::cmp::Reverse(masked.len()));
+
+        let names: Vec<&str> = self
+            .vars
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| *name != sudo::BECOME_PASSWORD_ENV_VAR)
+            .collect();
+        self.names = names.join(",");
+
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.vars.iter().any(|(existing, _)| existing == name)
+    }
+
+    /// The variables as given to a process, along with `ENV_VARS_ENV_VAR`.
+    pub fn env(&self) -> Vec<(&str, &str)> {
+        let mut env: Vec<(&str, &str)> = self.vars.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        if !self.names.is_empty() {
+            env.push((ENV_VARS_ENV_VAR, self.names.as_str()));
+        }
+
+        env
+    }
+
+    /// Exports the variables at the top of a script, for scripts piped to a shell
+    /// over ssh. The script is never written anywhere with them.
+    pub fn export(&self, script: &str) -> String {
+        export_vars(script, &self.env())
+    }
+
+    /// Replaces the values of the variables in a line of output with `***`.
+    pub fn mask<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let mut line = Cow::Borrowed(line);
+
+        for masked in self.masked.iter() {
+            if line.contains(masked.as_str()) {
+                line = Cow::Owned(line.replace(masked.as_str(), MASK));
+            }
+        }
+
+        line
+    }
+}
+
+/// Adds `export NAME='value'` lines for the variables at the top of a script,
+/// after its shebang.
+pub fn export_vars(script: &str, vars: &[(&str, &str)]) -> String {
+    let exports: String = vars
+        .iter()
+        .map(|(name, value)| format!("export {}='{}'\n", name, value.replace('\'', "'\\''")))
+        .collect();
+
+    // The shebang has to stay on the first line.
+    match script.strip_prefix("#!") {
+        Some(_) => {
+            let end = script.find('\n').map_or(script.len(), |index| index + 1);
+            format!("{}{}{}", &script[..end], exports, &script[end..])
+        }
+        None => format!("{}{}", exports, script),
+    }
+}
+
+/// Reads the secrets providers of the front matter, the variable names and the
+/// commands printing their values.
+pub fn secrets_providers(front_matter: Option<&FrontMatter>) -> Result<Vec<(String, String)>, CorgError> {
+    let mut providers = vec![];
+
+    match front_matter.and_then(|front_matter| front_matter.get(SECRETS_FRONT_MATTER_KEY)) {
+        Some(Value::Mapping(values)) => {
+            for (name, command) in values {
+                let name = scalar_to_string(name).unwrap_or_default();
+                match command.as_str() {
+                    Some(command) => providers.push((name, String::from(command))),
+                    None => return Err(CorgError::Env(format!("{}.{}: must be a command", SECRETS_FRONT_MATTER_KEY, name))),
+                }
+            }
+        }
+        Some(Value::Null) | None => (),
+        Some(_) => {
+            return Err(CorgError::Env(format!(
+                "{}: must map variable names to commands",
+                SECRETS_FRONT_MATTER_KEY
+            )))
+        }
+    }
+
+    Ok(providers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_run_env() {
+        let path = std::env::temp_dir().join(format!("corg-run-env-{}.env", std::process::id()));
+        fs::write(&path, "# prod\nexport DB_HOST=db.futuregadgetlab.dev\nDEBUG=1\n").unwrap();
+
+        let mut env = RunEnv::new();
+        env.read_env_file(&path.to_string_lossy()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let providers = vec![
+            (String::from("DB_PASSWORD"), String::from("printf 'hunter2\\n'")),
+            (String::from("TOKEN"), String::from("echo \"it's a secret\"")),
+        ];
+        env.read_secrets(&providers).unwrap();
+
+        assert_eq!(
+            env.env(),
+            vec![
+                ("DB_HOST", "db.futuregadgetlab.dev"),
+                ("DEBUG", "1"),
+                ("DB_PASSWORD", "hunter2"),
+                ("TOKEN", "it's a secret"),
+                ("CORG_ENV_VARS", "DB_HOST,DEBUG,DB_PASSWORD,TOKEN")
+            ]
+        );
+        // Short values stay readable.
+        assert_eq!(env.mask("connecting to db.futuregadgetlab.dev as hunter2, 1 try"), "connecting to *** as ***, 1 try");
+        assert_eq!(
+            env.export("#!/bin/zsh\necho $TOKEN\n"),
+            "#!/bin/zsh\nexport DB_HOST='db.futuregadgetlab.dev'\nexport DEBUG='1'\nexport DB_PASSWORD='hunter2'\n\
+             export TOKEN='it'\\''s a secret'\nexport CORG_ENV_VARS='DB_HOST,DEBUG,DB_PASSWORD,TOKEN'\necho $TOKEN\n"
+        );
+
+        let failing = vec![(String::from("TOKEN"), String::from("exit 3"))];
+        assert_eq!(env.read_secrets(&failing).unwrap_err().to_string(), "secrets.TOKEN: `exit 3` failed with exit code 3");
+        assert!(env.insert("db.host", "x").is_err());
+
+        // sudo gets the become password on its stdin, the code it runs doesn't.
+        env.insert(sudo::BECOME_PASSWORD_ENV_VAR, "hunter22").unwrap();
+        assert_eq!(env.env().last(), Some(&("CORG_ENV_VARS", "DB_HOST,DEBUG,DB_PASSWORD,TOKEN")));
+    }
+
+    #[test]
+    fn test_secrets_providers() {
+        let source = "---\nsecrets:\n  DB_PASSWORD: pass show prod/db\n---\n# Faye\n";
+        let (front_matter, _) = FrontMatter::split(source).unwrap();
+
+        assert_eq!(
+            secrets_providers(front_matter.as_ref()).unwrap(),
+            vec![(String::from("DB_PASSWORD"), String::from("pass show prod/db"))]
+        );
+        assert!(secrets_providers(None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,530 @@
+use crate::clogger::ClogLevel;
+use crate::corg_error::CorgError;
+use crate::transport::{self, CommandTransport, LocalTransport, Transport};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+
+/// Matches the escape sequences the logger script colors its output with.
+static ANSI_ESCAPE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
+
+/// Matches lines of the logger script, e.g. `[warn] Retrying pull#1`.
+static LOG_LINE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\[([a-z]+)\] ?(.*)$").unwrap());
+
+/// Most scripts signals are forwarded to at once, far more than group runs run
+/// in parallel.
+const MAX_CHILDREN: usize = 1024;
+
+/// Process ids of the running scripts, signals corg receives are forwarded to
+/// them. Group runs run several at once. Free slots hold 0, so the signal
+/// handler can read them without taking a lock.
+static CHILD_PIDS: [AtomicI32; MAX_CHILDREN] = [const { AtomicI32::new(0) }; MAX_CHILDREN];
+
+/// How many runs need interrupts forwarded and the handler corg had before.
+static INTERRUPT_FORWARDING: Mutex<Forwarding<libc::sigaction>> = Mutex::new(Forwarding::new());
+
+/// How the system ssh client is invoked for remote runs. Everything else comes
+/// from `~/.ssh/config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ssh {
+    /// The ssh executable, looked up on `PATH` unless it's a path.
+    pub program: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// Host to jump through, ssh's `-J`.
+    pub jump: Option<String>,
+    /// Private key to authenticate with, ssh's `-i`.
+    pub identity: Option<String>,
+    /// Extra `-o KEY=VALUE` options, e.g. `StrictHostKeyChecking=accept-new`.
+    pub options: Vec<String>,
+    /// Shell on the host the script is piped to.
+    pub shell: String,
+}
+
+impl Default for Ssh {
+    fn default() -> Self {
+        Ssh {
+            program: String::from("ssh"),
+            user: None,
+            port: None,
+            jump: None,
+            identity: None,
+            options: vec![],
+            shell: String::from("zsh"),
+        }
+    }
+}
+
+impl Ssh {
+    /// The command running a script piped to its stdin with the shell on `host`.
+    pub fn command(&self, host: &str) -> Command {
+        let args = self.args(host);
+        let mut command = Command::new(&args[0]);
+        command.args(&args[1..]).arg(&self.shell).arg("-s");
+        command
+    }
+
+    /// The ssh command and its arguments up to the host, what follows runs there.
+    pub fn args(&self, host: &str) -> Vec<String> {
+        // No pseudo-terminal, the script comes in over stdin.
+        let mut args = vec![self.program.clone(), String::from("-T")];
+
+        if let Some(user) = &self.user {
+            args.extend([String::from("-l"), user.clone()]);
+        }
+        if let Some(port) = self.port {
+            args.extend([String::from("-p"), port.to_string()]);
+        }
+        if let Some(jump) = &self.jump {
+            args.extend([String::from("-J"), jump.clone()]);
+        }
+        if let Some(identity) = &self.identity {
+            args.extend([String::from("-i"), identity.clone()]);
+        }
+        for option in self.options.iter() {
+            args.extend([String::from("-o"), option.clone()]);
+        }
+
+        args.push(String::from(host));
+        args
+    }
+}
+
+/// Where a line of the script's output was written to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Runs a script with zsh from `dir` with the extra environment variables,
+/// calling `on_line` with every line it writes to stdout or stderr as it comes
+/// in. Returns once the script exited.
+///
+/// The script shares corg's terminal and process group, so ctrl-c reaches it
+/// directly. A SIGINT sent to corg by another process (e.g. `kill -INT`) is
+/// forwarded to the script, either way corg waits for it to exit.
+pub fn run_script<F>(script: &Path, dir: &Path, env: &[(&str, &str)], mut on_line: F) -> Result<ExitStatus, CorgError>
+where
+    F: FnMut(Stream, &str),
+{
+    let transport = LocalTransport::new(dir, env);
+    transport.exec_streaming(&["zsh", &script.to_string_lossy()], None, &mut on_line)
+}
+
+/// Runs a script on `host` by piping it to the shell over ssh, nothing is left behind
+/// on the host. The script has to be standalone (see `corg_file::inline_logger`)
+/// and gets no stdin of its own. Output is passed to `on_line` like for
+/// `run_script`, ssh exits with the exit status of the script or 255 when it
+/// couldn't connect.
+pub fn run_remote<F>(ssh: &Ssh, host: &str, script: &str, mut on_line: F) -> Result<ExitStatus, CorgError>
+where
+    F: FnMut(Stream, &str),
+{
+    transport::run_script(&CommandTransport::ssh(ssh, host), &ssh.shell, script, &mut on_line)
+}
+
+/// Spawns the command, writing `input` to its stdin or letting it share corg's.
+pub(crate) fn run_command<F>(mut command: Command, input: Option<String>, mut on_line: F) -> Result<ExitStatus, CorgError>
+where
+    F: FnMut(Stream, &str),
+{
+    let stdin = if input.is_some() { Stdio::piped() } else { Stdio::inherit() };
+    let mut child = command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Written from a thread of its own, the command may fill its output pipes
+    // before it read all of its input.
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => Some(thread::spawn(move || {
+            // Fails when the command exits early, which its status reports.
+            let _ = stdin.write_all(input.as_bytes());
+        })),
+        _ => None,
+    };
+
+    let forwarding = forward_interrupts();
+    let child_slot = watch_child(child.id() as i32);
+
+    let (sender, receiver) = mpsc::channel();
+    let readers = vec![
+        read_lines(child.stdout.take(), Stream::Stdout, sender.clone()),
+        read_lines(child.stderr.take(), Stream::Stderr, sender),
+    ];
+
+    // Ends once both readers hit the end of their stream and dropped the sender.
+    for (stream, line) in receiver {
+        on_line(stream, &line);
+    }
+
+    for reader in readers {
+        let _ = reader.join();
+    }
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+
+    let status = child.wait();
+    drop(child_slot);
+    drop(forwarding);
+
+    Ok(status?)
+}
+
+/// Returns the level and message of a line written by the logger script, the
+/// colors it added are stripped.
+pub fn parse_log_line(line: &str) -> Option<(ClogLevel, String)> {
+    let (level, message) = split_log_line(line)?;
+
+    let level = match level.as_str() {
+        "debug" => ClogLevel::Debug,
+        "info" => ClogLevel::Info,
+        "ok" | "success" | "done" => ClogLevel::Success,
+        "warn" | "warning" => ClogLevel::Warning,
+        "error" => ClogLevel::Error,
+        "trace" => ClogLevel::Trace,
+        "announce" | "step" => ClogLevel::Announce,
+        _ => return None,
+    };
+
+    Some((level, message))
+}
+
+/// Returns the level and message of a line shaped like the logger's, e.g.
+/// `("warn", "Retrying pull#1")`, whether corg knows the level or not.
+pub fn split_log_line(line: &str) -> Option<(String, String)> {
+    let line = ANSI_ESCAPE.replace_all(line, "");
+    let captures = LOG_LINE.captures(&line)?;
+
+    Some((String::from(&captures[1]), String::from(&captures[2])))
+}
+
+/// Returns the line without the colors of the logger, e.g. for log files.
+pub fn strip_colors(line: &str) -> Cow<'_, str> {
+    ANSI_ESCAPE.replace_all(line, "")
+}
+
+/// A step starting or finishing, as `corg_step` reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepEvent {
+    /// In step mode, the script waits for the operator before the step.
+    Paused(String),
+    Started(String),
+    Finished(String),
+}
+
+/// Returns the step a line of `corg_step` reports on, with its slug.
+pub fn parse_step_event(line: &str) -> Option<StepEvent> {
+    let (level, slug) = split_log_line(line)?;
+
+    match level.as_str() {
+        "pause" => Some(StepEvent::Paused(slug)),
+        "step" => Some(StepEvent::Started(slug)),
+        "done" => Some(StepEvent::Finished(slug)),
+        _ => None,
+    }
+}
+
+/// Returns the exit code to leave with after the script, shells report a script
+/// killed by a signal as 128 + the signal.
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+fn read_lines<R>(output: Option<R>, stream: Stream, sender: mpsc::Sender<(Stream, String)>) -> thread::JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let output = match output {
+            Some(output) => output,
+            None => return,
+        };
+
+        for line in BufReader::new(output).split(b'\n') {
+            match line {
+                // Commands don't have to write UTF-8.
+                Ok(line) => {
+                    let line = String::from_utf8_lossy(&line).trim_end_matches('\r').to_string();
+                    if sender.send((stream, line)).is_err() {
+                        return;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            }
+        }
+    })
+}
+
+// Only installed outside of tests, see `install_handler`.
+#[cfg_attr(test, allow(dead_code))]
+extern "C" fn on_interrupt(signal: libc::c_int, info: *mut libc::siginfo_t, _context: *mut libc::c_void) {
+    if info.is_null() {
+        return;
+    }
+
+    // ctrl-c is sent by the kernel to the whole process group, which the scripts
+    // are part of, so only signals sent by other processes are passed on.
+    let sent_by_process = unsafe { (*info).si_code } <= 0;
+    if !sent_by_process {
+        return;
+    }
+
+    for slot in CHILD_PIDS.iter() {
+        let pid = slot.load(Ordering::SeqCst);
+        if pid > 0 {
+            unsafe {
+                libc::kill(pid, signal);
+            }
+        }
+    }
+}
+
+/// Holds the slot of a running script in `CHILD_PIDS` until it's dropped.
+struct ChildSlot(Option<usize>);
+
+/// Registers the script for forwarded signals. Scripts beyond `MAX_CHILDREN`
+/// still run, they just only get the signals of their process group.
+fn watch_child(pid: i32) -> ChildSlot {
+    let slot = CHILD_PIDS
+        .iter()
+        .position(|slot| slot.compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+
+    ChildSlot(slot)
+}
+
+impl Drop for ChildSlot {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            CHILD_PIDS[slot].store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Counts the runs which need interrupts forwarded. The first one installs the
+/// forwarding handler and the last one puts back the handler from before.
+struct Forwarding<H> {
+    count: usize,
+    previous: Option<H>,
+}
+
+impl<H> Forwarding<H> {
+    const fn new() -> Forwarding<H> {
+        Forwarding { count: 0, previous: None }
+    }
+
+    /// `install` returns the handler it replaced.
+    fn acquire(&mut self, install: impl FnOnce() -> H) {
+        if self.count == 0 {
+            self.previous = Some(install());
+        }
+        self.count += 1;
+    }
+
+    fn release(&mut self, restore: impl FnOnce(H)) {
+        self.count -= 1;
+        if self.count == 0 {
+            if let Some(previous) = self.previous.take() {
+                restore(previous);
+            }
+        }
+    }
+}
+
+/// Forwards SIGINT to the running scripts until it's dropped, see `run_script`.
+/// Group runs hold one for the whole rollout, so hosts finishing don't restore
+/// corg's own handler while others still run.
+pub struct InterruptForwarding {
+    _private: (),
+}
+
+pub fn forward_interrupts() -> InterruptForwarding {
+    lock(&INTERRUPT_FORWARDING).acquire(install_handler);
+
+    InterruptForwarding { _private: () }
+}
+
+impl Drop for InterruptForwarding {
+    fn drop(&mut self) {
+        lock(&INTERRUPT_FORWARDING).release(restore_handler);
+    }
+}
+
+#[cfg(not(test))]
+fn install_handler() -> libc::sigaction {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGINT, &action, &mut previous);
+        previous
+    }
+}
+
+#[cfg(not(test))]
+fn restore_handler(previous: libc::sigaction) {
+    unsafe {
+        libc::sigaction(libc::SIGINT, &previous, std::ptr::null_mut());
+    }
+}
+
+// Tests run in parallel in one process, so they leave its SIGINT handler alone.
+#[cfg(test)]
+fn install_handler() -> libc::sigaction {
+    unsafe { std::mem::zeroed() }
+}
+
+#[cfg(test)]
+fn restore_handler(_previous: libc::sigaction) {}
+
+/// Locks the mutex, a panic elsewhere doesn't make what it holds wrong.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_log_line() {
+        let line = "\x1b[00m[\x1b[01;33mwarn\x1b[00m] Retrying pull#1 in 2s";
+
+        match parse_log_line(line) {
+            Some((ClogLevel::Warning, message)) => assert_eq!(message, "Retrying pull#1 in 2s"),
+            other => panic!("expected a warning, got {:?}", other),
+        }
+        assert!(parse_log_line("[x] done").is_none());
+        assert!(parse_log_line("Reading package lists...").is_none());
+        assert_eq!(
+            parse_step_event("[\x1b[01;35mstep\x1b[00m] install-nix"),
+            Some(StepEvent::Started(String::from("install-nix")))
+        );
+        assert_eq!(parse_step_event("[done] install-nix"), Some(StepEvent::Finished(String::from("install-nix"))));
+        assert_eq!(parse_step_event("[pause] deploy"), Some(StepEvent::Paused(String::from("deploy"))));
+        assert!(parse_step_event("[info] install-nix").is_none());
+    }
+
+    #[test]
+    #[ignore = "needs zsh"]
+    fn test_run_script() {
+        let dir = std::env::temp_dir().join(format!("corg-runner-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("run.sh"), "echo '[info] hi'\necho oops >&2\nexit 3\n").unwrap();
+
+        let mut lines = vec![];
+        let status = run_script(Path::new("run.sh"), &dir, &[], |stream, line| lines.push((stream, String::from(line)))).unwrap();
+
+        assert_eq!(exit_code(&status), 3);
+        assert!(lines.contains(&(Stream::Stdout, String::from("[info] hi"))));
+        assert!(lines.contains(&(Stream::Stderr, String::from("oops"))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let mut forwarding = Forwarding::new();
+        let mut installed = vec![];
+        let mut restored = vec![];
+
+        // A host finishing doesn't take the handler away from the others.
+        forwarding.acquire(|| {
+            installed.push("rollout");
+            "previous"
+        });
+        forwarding.acquire(|| {
+            installed.push("host");
+            "forwarding"
+        });
+        forwarding.release(|previous| restored.push(previous));
+        assert_eq!(installed, vec!["rollout"]);
+        assert!(restored.is_empty());
+
+        forwarding.release(|previous| restored.push(previous));
+        assert_eq!(restored, vec!["previous"]);
+    }
+
+    #[test]
+    fn test_watch_child() {
+        // Beyond the highest pid Linux hands out, so no other test can hold it.
+        let pid = i32::MAX - 1;
+        let watching = || CHILD_PIDS.iter().any(|slot| slot.load(Ordering::SeqCst) == pid);
+
+        let slot = watch_child(pid);
+        assert!(watching());
+        drop(slot);
+        assert!(!watching());
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let ssh = Ssh {
+            port: Some(2222),
+            identity: Some(String::from("~/.ssh/faye")),
+            options: vec![String::from("StrictHostKeyChecking=accept-new")],
+            ..Ssh::default()
+        };
+        let command = format!("{:?}", ssh.command("faye"));
+
+        assert_eq!(
+            command,
+            "\"ssh\" \"-T\" \"-p\" \"2222\" \"-i\" \"~/.ssh/faye\" \"-o\" \"StrictHostKeyChecking=accept-new\" \"faye\" \"zsh\" \"-s\""
+        );
+    }
+
+    #[test]
+    fn test_run_remote() {
+        // Stands in for ssh on PATH, writing its arguments to a file and running
+        // the script it's given on stdin with sh.
+        let dir = std::env::temp_dir().join(format!("corg-ssh-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let stub = dir.join("ssh");
+        let argv = dir.join("argv");
+        fs::write(&stub, format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\nexec sh -s\n", argv.display())).unwrap();
+        fs::set_permissions(&stub, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap_or_default());
+
+        let ssh = Ssh {
+            port: Some(2222),
+            identity: Some(String::from("~/.ssh/faye")),
+            options: vec![String::from("StrictHostKeyChecking=accept-new")],
+            ..Ssh::default()
+        };
+        // What `run_remote` runs, with the stub's directory on PATH of ssh only.
+        let transport = CommandTransport::ssh(&ssh, "faye").with_env("PATH", &path);
+        let mut lines = vec![];
+        let status = transport::run_script(&transport, &ssh.shell, "echo '[ok] remote'\nexit 4\n", &mut |stream, line| {
+            lines.push((stream, String::from(line)))
+        })
+        .unwrap();
+
+        assert_eq!(exit_code(&status), 4);
+        assert_eq!(lines, vec![(Stream::Stdout, String::from("[ok] remote"))]);
+        assert_eq!(
+            fs::read_to_string(&argv).unwrap(),
+            "-T\n-p\n2222\n-i\n~/.ssh/faye\n-o\nStrictHostKeyChecking=accept-new\nfaye\nzsh -s\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
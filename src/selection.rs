@@ -0,0 +1,192 @@
+use crate::document::slug;
+
+/// Environment variable with the slugs of the steps a script runs, separated by
+/// spaces. `corg_step` skips the other steps, all of them run when it's empty.
+pub const SECTIONS_ENV_VAR: &str = "CORG_SECTIONS";
+
+/// Steps of a runbook to run, by slug or heading text, e.g. from
+/// `--section install-nix --from configure`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    pub sections: Vec<String>,
+    /// First step of a range of steps to run, the first step of the runbook if
+    /// only `until` is given.
+    pub from: Option<String>,
+    /// Last step of a range of steps to run, the last step of the runbook if only
+    /// `from` is given.
+    pub until: Option<String>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty() && self.from.is_none() && self.until.is_none()
+    }
+
+    /// Returns the slugs of the selected steps in the order they run. Names which
+    /// don't match a step are an error suggesting the closest one.
+    pub fn resolve(&self, steps: &[String]) -> Result<Vec<String>, String> {
+        let mut selected = vec![false; steps.len()];
+
+        for name in self.sections.iter() {
+            selected[find(steps, name)?] = true;
+        }
+
+        if self.from.is_some() || self.until.is_some() {
+            let from = match &self.from {
+                Some(name) => find(steps, name)?,
+                None => 0,
+            };
+            let until = match &self.until {
+                Some(name) => find(steps, name)?,
+                None => steps.len().saturating_sub(1),
+            };
+
+            if from > until {
+                return Err(format!("'{}' comes after '{}'", steps[from], steps[until]));
+            }
+            for selected in selected[from..=until].iter_mut() {
+                *selected = true;
+            }
+        }
+
+        Ok(steps
+            .iter()
+            .zip(selected)
+            .filter(|(_, selected)| *selected)
+            .map(|(step, _)| step.clone())
+            .collect())
+    }
+}
+
+/// Returns the slugs of the steps a generated script runs, in order.
+pub fn script_steps(script: &str) -> Vec<String> {
+    script
+        .lines()
+        .filter_map(|line| line.strip_prefix("corg_step "))
+        .map(|step| String::from(step.trim()))
+        .collect()
+}
+
+/// Returns the function running a step in a generated script, to tell whether
+/// the step changed between two scripts.
+pub fn step_function<'a>(script: &'a str, slug: &str) -> Option<&'a str> {
+    let start = script.find(&format!("\nfunction {} {{\n", slug))? + 1;
+    let end = script[start..].find("\n# - end function").map_or(script.len(), |end| start + end);
+
+    Some(&script[start..end])
+}
+
+/// Sets the selected steps at the top of a script, for scripts which don't run
+/// with corg's environment, e.g. piped to a shell over ssh.
+pub fn export_sections(script: &str, steps: &[String]) -> String {
+    let export = format!("export {}='{}'\n", SECTIONS_ENV_VAR, steps.join(" "));
+
+    // The shebang has to stay on the first line.
+    match script.strip_prefix("#!") {
+        Some(_) => {
+            let end = script.find('\n').map_or(script.len(), |index| index + 1);
+            format!("{}{}{}", &script[..end], export, &script[end..])
+        }
+        None => format!("{}{}", export, script),
+    }
+}
+
+/// Finds a step by its slug or heading text.
+fn find(steps: &[String], name: &str) -> Result<usize, String> {
+    let wanted = slug(name.trim());
+
+    if let Some(index) = steps.iter().position(|step| *step == wanted) {
+        return Ok(index);
+    }
+
+    let closest = steps
+        .iter()
+        .map(|step| (distance(step, &wanted), step))
+        .filter(|(distance, step)| *distance <= (wanted.len() / 3).max(2) || step.contains(&wanted))
+        .min_by_key(|(distance, _)| *distance);
+
+    match closest {
+        Some((_, step)) => Err(format!("unknown section '{}', did you mean '{}'?", name, step)),
+        None => Err(format!("unknown section '{}', the steps are: {}", name, steps.join(", "))),
+    }
+}
+
+/// Levenshtein distance between two names.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + if a == *b { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps() -> Vec<String> {
+        ["install-nix", "configure-daemon", "build", "deploy"].iter().map(|step| String::from(*step)).collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let selection = Selection {
+            sections: names(&["deploy", "Install Nix"]),
+            ..Selection::default()
+        };
+        assert_eq!(selection.resolve(&steps()).unwrap(), names(&["install-nix", "deploy"]));
+
+        let range = Selection {
+            from: Some(String::from("configure-daemon")),
+            ..Selection::default()
+        };
+        assert_eq!(range.resolve(&steps()).unwrap(), names(&["configure-daemon", "build", "deploy"]));
+
+        let until = Selection {
+            until: Some(String::from("configure-daemon")),
+            ..Selection::default()
+        };
+        assert_eq!(until.resolve(&steps()).unwrap(), names(&["install-nix", "configure-daemon"]));
+    }
+
+    #[test]
+    fn test_resolve_suggests() {
+        let selection = Selection {
+            sections: names(&["configure-deamon"]),
+            ..Selection::default()
+        };
+        assert_eq!(
+            selection.resolve(&steps()).unwrap_err(),
+            "unknown section 'configure-deamon', did you mean 'configure-daemon'?"
+        );
+
+        let selection = Selection {
+            sections: names(&["nope"]),
+            ..Selection::default()
+        };
+        assert!(selection.resolve(&steps()).unwrap_err().ends_with("the steps are: install-nix, configure-daemon, build, deploy"));
+    }
+
+    #[test]
+    fn test_script_steps() {
+        let script = "#!/bin/zsh\nset -e\n\n# - run doc: \ncorg_step install-nix\ncorg_step deploy";
+
+        assert_eq!(script_steps(script), names(&["install-nix", "deploy"]));
+        assert_eq!(
+            export_sections(script, &names(&["deploy"])),
+            "#!/bin/zsh\nexport CORG_SECTIONS='deploy'\nset -e\n\n# - run doc: \ncorg_step install-nix\ncorg_step deploy"
+        );
+    }
+}
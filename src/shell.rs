@@ -1,782 +1,354 @@
-use std::collections::HashMap;
-use std::fmt::{Arguments, Write as FmtWrite};
-use std::io::{self, ErrorKind, Write};
+use crate::condition::Condition;
+use crate::document::{CodeBlock, Content, Document, Heading, Prose, ProseKind, Section};
+use std::path::Path;
 
-use pulldown_cmark::Event::*;
-use pulldown_cmark::{Alignment, CowStr, Event, LinkType, Tag};
-use std::fmt;
+/// Ends the heredoc of `{file=...}` blocks.
+const FILE_DELIMITER: &str = "CORG_FILE_EOF";
 
-enum TableState {
-    Head,
-    Body,
-}
-
-trait CorgTaggable {
-    fn start_tag(&self) -> String;
-    fn write_tag(&self, text: String) -> String;
-    fn end_tag(&self) -> String;
-    fn to_string(&self) -> String;
-}
-
-#[derive(Debug, PartialEq, Clone)]
-struct CorgHeader {
-    level: i32,
-    close_before_start: bool,
-    text: Option<String>,
+/// Renders a document as a zsh script, calling the helpers of the logger script.
+///
+/// - the title is announced.
+/// - level-2 sections become functions, which are run in order at the end
+///   through `corg_step`.
+/// - deeper sections are run in place inside the function.
+/// - paragraphs are logged at debug level and quotes at info level.
+struct ShellRenderer<'s> {
+    output: &'s mut String,
+    /// Code blocks rendered so far, wrapped blocks are named after the count.
+    block_count: usize,
+    /// Name of the parallel group whose jobs haven't been waited for yet.
+    parallel_group: Option<String>,
 }
 
-impl CorgHeader {
-    fn new(level: i32, previous_header: Option<CorgHeader>) -> CorgHeader {
-        // If the new heading is level 2, and we have a previous heading,
-        // we need to close the previous function
-        let close_before_start = match (level, previous_header) {
-            (2, Some(CorgHeader { level, .. })) if level == 2 => true,
-            (2, Some(_)) => false,
-            _ => false,
-        };
-
-        CorgHeader {
-            level,
-            close_before_start,
-            text: None,
+impl<'s> ShellRenderer<'s> {
+    fn new(output: &'s mut String) -> ShellRenderer<'s> {
+        ShellRenderer {
+            output,
+            block_count: 0,
+            parallel_group: None,
         }
     }
 
-    /// Returns a string which can be used as a zsh function name
-    fn function_slug(&self) -> String {
-        let text = &self.text;
-
-        match text {
-            Some(slug) => slug.to_lowercase().replace(" ", "-"),
-            _ => String::from(""),
-        }
+    fn write(&mut self, s: &str) {
+        self.output.push_str(s);
     }
 
-    fn update_text(&mut self, text: Option<String>) {
-        self.text = text
-    }
-}
-
-impl CorgTaggable for CorgHeader {
-    /// Returns text to be used for the start of a header. This function will also close
-    /// any previous header that opens a shell function prepending the close to the output.
-    fn start_tag(&self) -> String {
-        let mut output = String::from("");
-
-        // Check if we need to close the previous function
-        if self.close_before_start {
-            // Push the closing of the previous function
-            let closing = String::from("}\n# - end function\n");
-            output.push_str(closing.as_str());
+    fn render(mut self, document: &Document) {
+        if let Some(title) = &document.title {
+            self.write_title(title);
         }
 
-        // Now push the start of the heading
-        match self.level {
-            1 => output.push_str("corg_announce \"Running Document: "),
-            2 => output.push_str("\n# - begin function:\n"),
-            _ => output.push_str("\n# - start section:\n"),
+        self.write_content(document.content());
+
+        for section in document.sections.iter() {
+            self.write_section(section);
         }
 
-        String::from(output)
+        // Label the section in the output and run the functions in order.
+        let steps: Vec<String> = document.steps().iter().map(|step| format!("corg_step {}", step.slug)).collect();
+        self.write("\n# - run doc: \n");
+        self.write(&steps.join("\n"));
     }
 
-    /// Returns the body of a header depending on its level:
-    ///
-    ///     - level-1 headings it closes the announce statement.
-    ///     - level-2 headings it opens a shell function with the heading name.
-    ///     - all other headings it returns an empty string.
-    ///
-    fn write_tag(&self, text: String) -> String {
-        let header = CorgHeader {
-            level: self.level,
-            close_before_start: self.close_before_start,
-            text: Some(text.clone()),
-        };
-
-        match header.level {
-            1 => format!("{}\"\n\n", text.clone()),
-            2 => format!("function {} {{\n", header.function_slug()),
-            _ => String::from(""),
-        }
+    /// Announces the document, if its condition doesn't hold the script exits early.
+    fn write_title(&mut self, title: &Heading) {
+        self.write(&format!("corg_announce \"Running Document: {}\"\n\n", double_quote(&title.text)));
+        self.write_title_guard(title);
     }
 
-    fn end_tag(&self) -> String {
-        match self.level {
-            2 => String::from(""),
-            _ => String::from(""),
+    fn write_title_guard(&mut self, title: &Heading) {
+        if let Some(condition) = &title.when {
+            let skip = skip_command(&title.text, condition);
+            self.write(&format!("if ! {}; then\n\t{}\n\texit 0\nfi\n\n", condition_test(condition), skip));
         }
     }
 
-    fn to_string(&self) -> String {
-        format!(
-            "CorgHeader {{ level: {}, close_before_start: {} }}",
-            self.level, self.close_before_start
-        )
-    }
-}
-
-#[derive(Debug, PartialEq, Clone)]
-struct CorgCode;
+    fn write_section(&mut self, section: &Section) {
+        if section.is_step() {
+            self.write("\n# - begin function:\n");
+            self.write(&format!("function {} {{\n", section.slug));
+            self.write_step_body(section, "return");
+            self.write("}\n# - end function\n");
+            return;
+        }
 
-impl CorgTaggable for CorgCode {
-    fn start_tag(&self) -> String {
-        String::from("")
-    }
+        self.write("\n# - start section:\n");
 
-    fn write_tag(&self, text: String) -> String {
-        let mut code = String::new();
-        let mut lines: Vec<&str> = text.split("\n").collect();
-        lines.reverse();
-        while let Some(line) = lines.pop() {
-            // If the line isn't empty we want to pad it.
-            if line != "" {
-                code.push_str("\t");
-            }
+        if let Some(condition) = &section.heading.when {
+            self.write(&format!("if {}; then\n", condition_test(condition)));
+        }
 
-            // If the line contains a heredoc we want to make sure it is padded too
-            if line.contains("<< \"EOF\"") {
-                println!("FOUND EOF w/ QUOTES");
-                let padded_line = line.replace("<< \"EOF\"", "<< \"\tEOF\"");
-                code.push_str(padded_line.as_str());
-            } else if line.contains("<< 'EOF'") {
-                println!("FOUND EOF w/ SINGLE QUOTES");
-                let padded_line = line.replace("<< 'EOF'", "<< '\tEOF'");
-                code.push_str(padded_line.as_str());
-            } else {
-                // Normal line no coddling needed
-                code.push_str(line);
-            }
+        self.write_content(section.content());
 
-            if lines.len() > 0 {
-                code.push_str("\n");
-            }
+        for child in section.children.iter() {
+            self.write_section(child);
         }
 
-        String::from(code)
-    }
-
-    fn end_tag(&self) -> String {
-        String::from("")
+        if let Some(condition) = &section.heading.when {
+            let skip = skip_command(&section.heading.text, condition);
+            self.write(&format!("else\n\t{}\nfi\n", skip));
+        }
     }
 
-    fn to_string(&self) -> String {
-        String::from("CorgCode {}")
-    }
-}
+    /// Writes what a step runs, a step whose condition doesn't hold ends early
+    /// with `leave` (`return` inside a function, `exit` in a script of its own).
+    fn write_step_body(&mut self, section: &Section, leave: &str) {
+        if let Some(condition) = &section.heading.when {
+            let skip = skip_command(&section.slug, condition);
+            self.write(&format!("if ! {}; then\n\t{}\n\t{} 0\nfi\n", condition_test(condition), skip, leave));
+        }
 
-#[derive(Debug, PartialEq, Clone)]
-struct CorgCodeBlock {
-    lang: String,
-}
+        self.write_content(section.content());
 
-impl CorgTaggable for CorgCodeBlock {
-    fn start_tag(&self) -> String {
-        String::from("# - begin code:\n")
+        for child in section.children.iter() {
+            self.write_section(child);
+        }
     }
 
-    /// Delegates to Corg Code
-    fn write_tag(&self, text: String) -> String {
-        let corg_code = CorgCode {};
-        corg_code.write_tag(text)
-    }
+    /// Writes the prose and code of a section, waiting for background jobs before
+    /// the section ends.
+    fn write_content(&mut self, content: Vec<Content>) {
+        for content in content {
+            match content {
+                Content::Prose(prose) => self.write_prose(prose),
+                Content::Block(block) => self.write_code_block(block),
+            }
+        }
 
-    fn end_tag(&self) -> String {
-        String::from("")
+        self.update_parallel_group(None);
     }
 
-    fn to_string(&self) -> String {
-        format!("CorgCodeBlock {{ lang: {} }}", self.lang)
+    fn write_prose(&mut self, prose: &Prose) {
+        match prose.kind {
+            ProseKind::Paragraph => {
+                self.write(&format!("\n# - paragraph:\ncorg_debug \"{}\"\n\n", double_quote(&prose.text)));
+            }
+            ProseKind::Quote => {
+                self.write(&format!("\n# - quote:\ncorg_info \"{}\"\n\n", double_quote(&prose.text)));
+            }
+            ProseKind::Item => {
+                for line in prose.text.lines() {
+                    self.write(&format!("# - {}\n", line));
+                }
+            }
+        }
     }
-}
 
-#[derive(Debug, PartialEq, Clone)]
-struct CorgParagraph;
+    /// Blocks which are retried or run in the background are written as a function
+    /// and called through the helpers of the logger script, the others in place.
+    fn write_code_block(&mut self, block: &CodeBlock) {
+        if !self.output.is_empty() && !self.output.ends_with('\n') {
+            self.write("\n");
+        }
 
-impl CorgTaggable for CorgParagraph {
-    fn start_tag(&self) -> String {
-        String::from("\n# - paragraph:\ncorg_debug \"")
-    }
+        self.block_count += 1;
+        let function_name = format!("corg_block_{}", self.block_count);
+        let is_wrapped = block.retry.is_some() || block.parallel.is_some();
 
-    fn write_tag(&self, text: String) -> String {
-        format!("{}", text)
-    }
+        // Start or finish a group of background jobs
+        self.update_parallel_group(block.parallel.as_ref());
 
-    fn end_tag(&self) -> String {
-        String::from("\"\n\n")
-    }
+        self.write("# - begin code:\n");
 
-    fn to_string(&self) -> String {
-        String::from("CorgParagraph")
-    }
-}
-
-impl std::fmt::Debug for Box<dyn CorgTaggable> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let farts = self.to_string();
-        write!(f, "{}", farts)
-    }
-}
+        if let Some(condition) = &block.when {
+            self.write(&format!("if {}; then\n", condition_test(condition)));
+        }
 
-impl PartialEq for Box<dyn CorgTaggable> {
-    fn eq(&self, other: &Self) -> bool {
-        self.to_string() == other.to_string()
-    }
-}
+        if let Some(path) = &block.creates {
+            self.write(&format!("if [ ! -e {} ]; then\n", shell_quote(path)));
+        }
 
-/// This is probably a terrible design pattern but hey... I have no idea
-/// what i'm doing.
-#[derive(Debug, PartialEq)]
-struct CorgStateManager {
-    current_function_name: Option<String>,
-    current_heading_level: Option<i32>,
-    inside_codeblock: bool,
-    current_indentation: String,
-    function_names: Vec<String>,
-    corg_tag: Option<Box<dyn CorgTaggable>>,
-    header: Option<CorgHeader>,
-    headers: Vec<CorgHeader>,
-}
+        if is_wrapped {
+            self.write(&format!("function {} {{\n", function_name));
+        }
 
-impl CorgStateManager {
-    /// Returns a new empty instance of CorgStateManager
-    fn new() -> CorgStateManager {
-        CorgStateManager {
-            current_function_name: None,
-            current_heading_level: None,
-            inside_codeblock: false,
-            current_indentation: "".to_string(),
-            function_names: vec![],
-            corg_tag: None,
-            header: None,
-            headers: vec![],
+        let commands = match &block.file {
+            Some(path) => file_commands(path, block.mode.as_deref(), &block.code),
+            // Runs in a shell of its own, the code stays as written.
+            None if block.sudo.is_some() => block.code.clone(),
+            None => indent_code(&block.code),
+        };
+        match &block.sudo {
+            Some(user) => self.write(&sudo_command(user, &commands)),
+            None => self.write(&commands),
         }
-    }
 
-    fn push_function_name(&mut self, function_name: String) {
-        if let Some(mut header) = self.header.clone() {
-            header.update_text(Some(function_name));
-            self.current_function_name = Some(header.function_slug());
-            self.function_names.push(header.function_slug());
-            self.header = Some(header);
+        if is_wrapped {
+            self.write(&format!("}}\n{}\n", block_command(block, &function_name)));
         }
-    }
 
-    fn update_tag(&mut self, new_tag: Box<dyn CorgTaggable>) {
-        self.corg_tag = Some(new_tag);
-    }
+        if let Some(path) = &block.creates {
+            let reason = format!("creates={}", path);
+            self.write(&format!("else\n\t{}\nfi\n", skip_command(&block.name, &reason)));
+        }
 
-    fn update_header(&mut self, level: i32) {
-        // Clone the previous header, this helps setup decisions on how
-        // to close the sections they contain.
-        let previous_header = self.header.clone();
-        // Create a new CorgHeader instance using the current level and
-        // previous header.
-        let corg_header = CorgHeader::new(level, previous_header);
-        // Clear any previous function names, we have to set it later.
-        self.current_function_name = None;
-        // Set the current header.
-        self.header = Some(corg_header.clone());
-        // Update the current tag.
-        self.update_tag(Box::new(corg_header));
+        if let Some(condition) = &block.when {
+            self.write(&format!("else\n\t{}\nfi\n", skip_command(&block.name, condition)));
+        }
     }
 
-    fn needs_to_push_function_name(&self) -> bool {
-        match (&self.header, &self.current_function_name) {
-            (Some(CorgHeader{level, ..}), None) if *level == 2 => true,
-            _ => false
+    /// Switches to the parallel group of the next code block (`None` for a block
+    /// running in the foreground or the end of a section), waiting for the
+    /// previous group and starting the new one.
+    fn update_parallel_group(&mut self, group: Option<&String>) {
+        if self.parallel_group.as_ref() == group {
+            return;
         }
-    }
-}
 
-/// This wrapper exists because we can't have both a blanket implementation
-/// for all types implementing `Write` and types of the for `&mut W` where
-/// `W: StrWrite`. Since we need the latter a lot, we choose to wrap
-/// `Write` types.
-struct WriteWrapper<W>(W);
+        if self.parallel_group.is_some() {
+            self.write("# - end parallel group:\ncorg_parallel_wait\n");
+        }
 
-/// Trait that allows writing string slices. This is basically an extension
-/// of `std::io::Write` in order to include `String`.
-pub(crate) trait StrWrite {
-    fn write_str(&mut self, s: &str) -> io::Result<()>;
+        if let Some(group) = group {
+            self.write("\n# - begin parallel group:\n");
+            self.write(&format!("corg_parallel_start {}\n", shell_quote(group)));
+        }
 
-    fn write_fmt(&mut self, args: Arguments) -> io::Result<()>;
+        self.parallel_group = group.cloned();
+    }
 }
 
-impl<W> StrWrite for WriteWrapper<W>
-where
-    W: Write,
-{
-    #[inline]
-    fn write_str(&mut self, s: &str) -> io::Result<()> {
-        self.0.write_all(s.as_bytes())
-    }
+/// Returns the command running a wrapped block, e.g.
+/// `corg_parallel_job 'pull#1' corg_retry 5 exp 2 'pull#1' corg_block_1`
+fn block_command(block: &CodeBlock, function_name: &str) -> String {
+    let mut command = String::from(function_name);
 
-    #[inline]
-    fn write_fmt(&mut self, args: Arguments) -> io::Result<()> {
-        self.0.write_fmt(args)
+    if let Some(retry) = &block.retry {
+        command = format!(
+            "corg_retry {} {} {} {} {}",
+            retry.attempts,
+            retry.backoff.as_str(),
+            retry.delay,
+            shell_quote(&block.name),
+            command
+        );
     }
-}
 
-impl<'w> StrWrite for String {
-    #[inline]
-    fn write_str(&mut self, s: &str) -> io::Result<()> {
-        self.push_str(s);
-        Ok(())
+    if block.parallel.is_some() {
+        command = format!("corg_parallel_job {} {}", shell_quote(&block.name), command);
     }
 
-    #[inline]
-    fn write_fmt(&mut self, args: Arguments) -> io::Result<()> {
-        // FIXME: translate fmt error to io error?
-        FmtWrite::write_fmt(self, args).map_err(|_| ErrorKind::Other.into())
-    }
+    command
 }
 
-impl<W> StrWrite for &'_ mut W
-where
-    W: StrWrite,
-{
-    #[inline]
-    fn write_str(&mut self, s: &str) -> io::Result<()> {
-        (**self).write_str(s)
-    }
-
-    #[inline]
-    fn write_fmt(&mut self, args: Arguments) -> io::Result<()> {
-        (**self).write_fmt(args)
-    }
+/// Returns the command running the commands of a block as another user through
+/// `corg_sudo`, e.g. `corg_sudo 'postgres' 'psql -f schema.sql'`.
+fn sudo_command(user: &str, commands: &str) -> String {
+    format!("\tcorg_sudo {} {}\n", shell_quote(user), shell_quote(commands.trim_end_matches('\n')))
 }
 
-struct ShellWriter<'a, I, W> {
-    /// Iterator supplying events.
-    iter: I,
+/// Indents the code with a tab, heredocs terminated by `EOF` are indented too so
+/// they still end.
+fn indent_code(code: &str) -> String {
+    let mut output = String::new();
 
-    /// Writer to write to.
-    writer: W,
+    for line in code.lines() {
+        // If the line isn't empty we want to pad it.
+        if !line.is_empty() {
+            output.push('\t');
+        }
 
-    /// Whether or not the last write wrote a newline.
-    end_newline: bool,
+        // If the line contains a heredoc we want to make sure it is padded too
+        if line.contains("<< \"EOF\"") {
+            output.push_str(&line.replace("<< \"EOF\"", "<< \"\tEOF\""));
+        } else if line.contains("<< 'EOF'") {
+            output.push_str(&line.replace("<< 'EOF'", "<< '\tEOF'"));
+        } else {
+            // Normal line no coddling needed
+            output.push_str(line);
+        }
 
-    /// Hacky solution to writing shell files until I understand
-    /// what the fuck this thing truly does.
-    corg_state: CorgStateManager,
+        output.push('\n');
+    }
 
-    table_state: TableState,
-    table_alignments: Vec<Alignment>,
-    table_cell_index: usize,
-    numbers: HashMap<CowStr<'a>, usize>,
+    output
 }
 
-impl<'a, I, W> ShellWriter<'a, I, W>
-where
-    I: Iterator<Item = Event<'a>>,
-    W: StrWrite,
-{
-    fn new(iter: I, writer: W) -> Self {
-        let corg_state = CorgStateManager::new();
-
-        Self {
-            iter,
-            writer,
-            end_newline: true,
-            corg_state: corg_state,
-            table_state: TableState::Head,
-            table_alignments: vec![],
-            table_cell_index: 0,
-            numbers: HashMap::new(),
-        }
-    }
+/// Returns the commands writing a `{file=...}` block, the code goes into a quoted
+/// heredoc so it's written exactly as in the document.
+fn file_commands(path: &str, mode: Option<&str>, code: &str) -> String {
+    let mut commands = String::new();
 
-    /// Writes a new line.
-    fn write_newline(&mut self) -> io::Result<()> {
-        self.end_newline = true;
-        self.writer.write_str("\n")
+    if let Some(parent) = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        commands.push_str(&format!("\tmkdir -p {}\n", shell_quote(&parent.to_string_lossy())));
     }
 
-    /// Writes a buffer, and tracks whether or not a newline was written.
-    #[inline]
-    fn write(&mut self, s: &str) -> io::Result<()> {
-        self.writer.write_str(s)?;
-
-        if !s.is_empty() {
-            self.end_newline = s.ends_with('\n');
-        }
-        Ok(())
+    commands.push_str(&format!("\tcat > {} << '{}'\n", shell_quote(path), FILE_DELIMITER));
+    commands.push_str(code);
+    if !code.is_empty() && !code.ends_with('\n') {
+        commands.push('\n');
     }
+    commands.push_str(&format!("{}\n", FILE_DELIMITER));
 
-    pub fn run(mut self) -> io::Result<()> {
-        while let Some(event) = self.iter.next() {
-            match event {
-                Start(tag) => {
-                    self.start_tag(tag)?;
-                }
-                End(tag) => {
-                    self.end_tag(tag)?;
-                }
-                Text(text) => {
-                    let corg_state = &self.corg_state;
-                    let corg_tag = &corg_state.corg_tag;
-                    let output = match corg_tag {
-                        Some(tag) => tag.write_tag(text.to_string()),
-                        _ => text.to_string(),
-                    };
-
-                    if corg_state.needs_to_push_function_name() {
-                        self.corg_state.push_function_name(text.to_string());
-                    };
-
-                    self.write(output.as_str())?;
-                }
-                Code(text) => {
-                    self.write("\n# IS THIS CALLED\n")?;
-                    self.write(&text)?;
-                    self.write("")?;
-                }
-                Html(html) | InlineHtml(html) => {
-                    self.write(&html)?;
-                }
-                SoftBreak => {
-                    self.write_newline()?;
-                }
-                HardBreak => self.write("\n\n")?,
-                FootnoteReference(name) => {
-                    let len = self.numbers.len() + 1;
-                    self.write("# -- note:\n# ")?;
-                    self.write(&name)?;
-                    let number = *self.numbers.entry(name).or_insert(len);
-                    write!(&mut self.writer, " #{}", number)?;
-                }
-                TaskListMarker(true) => {
-                    self.write("")?;
-                }
-                TaskListMarker(false) => {
-                    self.write("")?;
-                }
-            }
-        }
-        // Close last function body
-        self.write("\n}\n")?;
-        // Grab all the function names we've created as a string.
-        let function_names = self.corg_state.function_names.join("\n");
-        let mut run_script_block = String::new();
-        // Label the section in the output.
-        run_script_block.push_str("\n# - run doc: \n");
-        // Insert the function names in the block
-        run_script_block.push_str(&function_names);
-        // Write the function names to execute 'em
-        self.write(&run_script_block)?;
-        // Done
-        Ok(())
+    if let Some(mode) = mode {
+        commands.push_str(&format!("\tchmod {} {}\n", mode, shell_quote(path)));
     }
 
-    /// Writes the start of an HTML tag.
-    fn start_tag(&mut self, tag: Tag<'a>) -> io::Result<()> {
-        match tag {
-            Tag::Paragraph => {
-                // Update our current corg tag.
-                self.corg_state.update_tag(Box::new(CorgParagraph {}));
-                // Now grab the output string.
-                let maybe_paragraph = &self.corg_state.corg_tag;
-
-                let output = match maybe_paragraph {
-                    Some(corg_tag) => corg_tag.start_tag(),
-                    _ => String::from(""),
-                };
-                // Don't write a newline
-                self.end_newline = false;
-                // Write the paragraph start to disk
-                self.write(output.as_str())
-            }
-            Tag::Rule => {
-                let rule_str = "# **************************************************************************** #";
-                if self.end_newline {
-                    self.write(&rule_str)
-                } else {
-                    self.write(&rule_str)?;
-                    self.write("\n")
-                }
-            }
-            Tag::Header(level) => {
-                // Update the header
-                self.corg_state.update_header(level);
-                // Now get the output for the header.
-                let maybe_header = &self.corg_state.header;
-                let output = match maybe_header {
-                    Some(header) => header.start_tag(),
-                    _ => String::from(""),
-                };
-                // Make sure a newline is appended to the output
-                self.end_newline = true;
-                // Write it to disk
-                self.write(output.as_str())
-            }
-            Tag::Table(alignments) => {
-                self.table_alignments = alignments;
-                self.write("")
-            }
-            Tag::TableHead => {
-                self.table_state = TableState::Head;
-                self.table_cell_index = 0;
-                self.write("")
-            }
-            Tag::TableRow => {
-                self.table_cell_index = 0;
-                self.write("")
-            }
-            Tag::TableCell => {
-                match self.table_state {
-                    TableState::Head => {
-                        self.write("")?;
-                        // self.write("<th")?;
-                    }
-                    TableState::Body => {
-                        self.write("")?;
-                        // self.write("<td")?;
-                    }
-                }
-                match self.table_alignments.get(self.table_cell_index) {
-                    // Some(&Alignment::Left) => self.write(" align=\"left\">"),
-                    // Some(&Alignment::Center) => self.write(" align=\"center\">"),
-                    // Some(&Alignment::Right) => self.write(" align=\"right\">"),
-                    _ => self.write(""),
-                }
-            }
-            Tag::BlockQuote => {
-                if self.end_newline {
-                    self.write("# block quote")?;
-                    self.write("corg_info \n")
-                } else {
-                    self.write("# block quote")?;
-                    self.write("\ncorg_info \n")
-                }
-            }
-            Tag::CodeBlock(info) => {
-                if !self.end_newline {
-                    self.write_newline()?;
-                }
+    commands
+}
 
-                // Extract the language
-                let lang = info.split(' ').next().unwrap();
-                let code_lang = lang.to_string();
-                // Update the current tag
-                self.corg_state
-                    .update_tag(Box::new(CorgCodeBlock { lang: code_lang }));
-                // Grab the new tag
-                let maybe_code_block = &self.corg_state.corg_tag;
-                let output = match maybe_code_block {
-                    Some(code_block) => code_block.start_tag(),
-                    _ => String::from(""),
-                };
-
-                // Write the code to disk
-                self.write(output.as_str())
-            }
-            Tag::List(Some(1)) => {
-                if self.end_newline {
-                    self.write("# List \n")
-                } else {
-                    self.write("\n# List\n")
-                }
-            }
-            Tag::List(Some(_start)) => self.write("#"),
-            Tag::List(None) => {
-                if self.end_newline {
-                    self.write("# List (None)\n")
-                } else {
-                    self.write("\n# List (None)\n")
-                }
-            }
-            Tag::Item => {
-                if self.end_newline {
-                    self.write("# -")
-                } else {
-                    self.write("\n# -")
-                }
-            }
-            Tag::Emphasis => self.write(""),
-            Tag::Strong => self.write(""),
-            Tag::Strikethrough => self.write(""),
-            Tag::Link(LinkType::Email, _dest, _title) => self.write(""),
-            Tag::Link(_link_type, _dest, _title) => self.write(""),
-            Tag::Image(_link_type, _dest, _title) => self.write(""),
-            Tag::FootnoteDefinition(name) => {
-                if self.end_newline {
-                    self.write("# ")?;
-                } else {
-                    self.write("\n# ")?;
-                }
-                self.write(&name)?;
-                self.write(" - ")?;
-                let len = self.numbers.len() + 1;
-                let number = *self.numbers.entry(name).or_insert(len);
-                write!(&mut self.writer, "{}", number)?;
-                self.write("\n")
-            }
-            Tag::HtmlBlock => Ok(()),
-        }
-    }
+/// Wraps the text in single quotes so the shell takes it literally.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
 
-    fn end_tag(&mut self, tag: Tag) -> io::Result<()> {
-        match tag {
-            Tag::Paragraph => {
-                let output = match &self.corg_state.corg_tag {
-                    Some(paragraph) => paragraph.end_tag(),
-                    _ => String::from(""),
-                };
+/// Escapes the text for use inside double quotes.
+fn double_quote(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
 
-                self.write(output.as_str())?;
-            }
-            Tag::Rule => (),
-            Tag::Header(_level) => {
-                let output = match &self.corg_state.corg_tag {
-                    Some(header) => header.end_tag(),
-                    _ => String::from(""),
-                };
-
-                self.write(output.as_str())?;
-            }
-            Tag::Table(_) => {
-                self.write("")?;
-            }
-            Tag::TableHead => {
-                self.write("")?;
-                self.table_state = TableState::Body;
-            }
-            Tag::TableRow => {
-                self.write("")?;
-            }
-            Tag::TableCell => {
-                match self.table_state {
-                    TableState::Head => {
-                        self.write("")?;
-                    }
-                    TableState::Body => {
-                        self.write("")?;
-                    }
-                }
+    for c in text.chars() {
+        if c == '"' || c == '\\' || c == '$' || c == '`' {
+            output.push('\\');
+        }
+        output.push(c);
+    }
 
-                self.table_cell_index += 1;
-            }
-            Tag::BlockQuote => {
-                self.write("")?;
-            }
-            Tag::CodeBlock(_code_lang) => {
-                let output = match &self.corg_state.corg_tag {
-                    Some(code_block) => code_block.end_tag(),
-                    _ => String::from(""),
-                };
+    output
+}
 
-                self.write(output.as_str())?;
-            }
-            Tag::List(Some(_)) => {
-                self.write("")?;
-            }
-            Tag::List(None) => {
-                self.write("")?;
-            }
-            Tag::Item => {
-                self.write("")?;
-            }
-            Tag::Emphasis => {
-                self.write("")?;
-            }
-            Tag::Strong => {
-                self.write("")?;
-            }
-            Tag::Strikethrough => {
-                self.write("")?;
-            }
-            Tag::Link(_, _, _) => {
-                self.write("")?;
-            }
-            Tag::Image(_, _, _) => (), // shouldn't happen, handled in start
-            Tag::FootnoteDefinition(_) => {
-                self.write("")?;
-            }
-            Tag::HtmlBlock => {}
-        }
-        Ok(())
+/// Returns the shell test checking the condition, `os` and `host` comparisons
+/// go through `corg_when` in the logger script. Conditions were validated when
+/// the document was parsed, see `Condition::parse`.
+fn condition_test(condition: &Condition) -> String {
+    match condition {
+        Condition::Compare { key, operator, value } => format!(
+            "corg_when {} {} {}",
+            key.as_str(),
+            shell_quote(operator.as_str()),
+            shell_quote(value)
+        ),
+        Condition::Shell(test) => test.clone(),
     }
 }
 
-/// Iterate over an `Iterator` of `Event`s, generate HTML for each `Event`, and
-/// push it to a `String`.
-///
-/// # Examples
-///
-/// ```
-/// use pulldown_cmark::{html, Parser};
-///
-/// let markdown_str = r#"
-/// hello
-/// =====
-///
-/// * alpha
-/// * beta
-/// "#;
-/// let parser = Parser::new(markdown_str);
-///
-/// let mut html_buf = String::new();
-/// html::push_html(&mut html_buf, parser);
-///
-/// assert_eq!(html_buf, r#"<h1>hello</h1>
-/// <ul>
-/// <li>alpha</li>
-/// <li>beta</li>
-/// </ul>
-/// "#);
-/// ```
+/// Returns the command logging that a section or block was skipped, `reason` is
+/// the condition which didn't hold.
+fn skip_command(name: &str, reason: &dyn std::fmt::Display) -> String {
+    format!("corg_skip {} {}", shell_quote(name), shell_quote(&reason.to_string()))
+}
 
+/// Renders the document as a zsh script and pushes it to a `String`.
+///
 /// CORG-NOTE:
 /// push_shell is probably useful if you need to build up a buffer from multiple
 /// markdown files.
 ///
-pub fn push_shell<'a, I>(s: &mut String, iter: I)
-where
-    I: Iterator<Item = Event<'a>>,
-{
-    ShellWriter::new(iter, s).run().unwrap();
+pub fn push_shell(s: &mut String, document: &Document) {
+    ShellRenderer::new(s).render(document)
 }
-/// :CORG-NOTE
 
-/// Iterate over an `Iterator` of `Event`s, generate HTML for each `Event`, and
-/// write it out to a writable stream.
-///
-/// **Note**: using this function with an unbuffered writer like a file or socket
-/// will result in poor performance. Wrap these in a
-/// [`BufWriter`](https://doc.rust-lang.org/std/io/struct.BufWriter.html) to
-/// prevent unnecessary slowdowns.
-///
-/// # Examples
-///
-/// ```
-/// use pulldown_cmark::{html, Parser};
-/// use std::io::Cursor;
-///
-/// let markdown_str = r#"
-/// hello
-/// =====
-///
-/// * alpha
-/// * beta
-/// "#;
-/// let mut bytes = Vec::new();
-/// let parser = Parser::new(markdown_str);
-///
-/// html::write_html(Cursor::new(&mut bytes), parser);
-///
-/// assert_eq!(&String::from_utf8_lossy(&bytes)[..], r#"<h1>hello</h1>
-/// <ul>
-/// <li>alpha</li>
-/// <li>beta</li>
-/// </ul>
-/// "#);
-/// ```
-pub fn write_html<'a, I, W>(writer: W, iter: I) -> io::Result<()>
-where
-    I: Iterator<Item = Event<'a>>,
-    W: Write,
-{
-    ShellWriter::new(iter, WriteWrapper(writer)).run()
+/// Renders one step as a script of its own, for back ends running every step in
+/// a separate shell (e.g. make). The title's condition and the code before the
+/// first section run first, the logger has to be sourced by the caller.
+pub fn push_step_script(s: &mut String, document: &Document, step: &Section) {
+    let mut renderer = ShellRenderer::new(s);
+
+    if let Some(title) = &document.title {
+        renderer.write_title_guard(title);
+    }
+
+    renderer.write_content(document.content());
+    renderer.write_step_body(step, "exit");
 }
 
 #[cfg(test)]
@@ -784,54 +356,30 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_corg_new() {
-        let expected = CorgStateManager {
-            current_function_name: None,
-            current_heading_level: None,
-            inside_codeblock: false,
-            current_indentation: "".to_string(),
-            function_names: vec![],
-            corg_tag: None,
-            header: None,
-            headers: vec![]
-        };
-
-        let actual = CorgStateManager::new();
+    fn test_indent_code() {
+        let code = "cat > /tmp/x << \"EOF\"\nhi\n\nEOF\n";
 
-        assert_eq!(actual, expected);
+        assert_eq!(indent_code(code), "\tcat > /tmp/x << \"\tEOF\"\n\thi\n\n\tEOF\n");
     }
 
     #[test]
-    fn test_corg_header_function_slug() {
-
-        let header_text = String::from("Holla Cheese Burgers");
-        let corg_header = CorgHeader { level: 2, close_before_start: false, text: Some(header_text) };
-
-        let actual = corg_header.function_slug();
-
-        assert_eq!(actual, "holla-cheese-burgers".to_string());
+    fn test_file_commands() {
+        assert_eq!(
+            file_commands("/etc/motd", Some("0644"), "Welcome to $HOST\n"),
+            "\tmkdir -p '/etc'\n\tcat > '/etc/motd' << 'CORG_FILE_EOF'\nWelcome to $HOST\nCORG_FILE_EOF\n\tchmod 0644 '/etc/motd'\n"
+        );
     }
 
     #[test]
-    fn test_corg_needs_to_push_function_name() {
-        let mut ksm = CorgStateManager::new();
-
-        ksm.header = Some(CorgHeader { level: 2, close_before_start: false, text: None });
-        ksm.current_heading_level = Some(2);
-
+    fn test_sudo_command() {
         assert_eq!(
-            ksm.needs_to_push_function_name(),
-            true
-        )
+            sudo_command("postgres", "psql -c 'select 1'\n"),
+            "\tcorg_sudo 'postgres' 'psql -c '\\''select 1'\\'''\n"
+        );
     }
 
     #[test]
-    fn test_corg_push_function_name() {
-        let mut ksm = CorgStateManager::new();
-        ksm.header = Some(CorgHeader{ level: 2, close_before_start: false, text: None});
-
-        ksm.push_function_name("shit".to_string());
-
-        assert_eq!(ksm.current_function_name, Some("shit".to_string()))
+    fn test_double_quote() {
+        assert_eq!(double_quote("run `make` in \"$HOME\""), "run \\`make\\` in \\\"\\$HOME\\\"");
     }
 }
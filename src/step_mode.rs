@@ -0,0 +1,71 @@
+use crate::document::{Content, ProseKind, Section};
+
+/// Environment variable turning on step mode, `corg_step` then asks whether to
+/// run, skip or edit each step before running it.
+pub const STEP_MODE_ENV_VAR: &str = "CORG_STEP_MODE";
+
+/// Describes a step for the operator before it runs: its heading, then its prose
+/// and code in document order, deeper sections included.
+pub fn describe(section: &Section) -> String {
+    let mut output = String::new();
+    push_section(&mut output, section);
+
+    output.trim_end().to_string() + "\n"
+}
+
+fn push_section(output: &mut String, section: &Section) {
+    output.push_str(&format!("{} {}\n\n", "#".repeat(section.heading.level.max(1) as usize), section.heading.text));
+
+    if let Some(condition) = &section.heading.when {
+        output.push_str(&format!("Runs when {}\n\n", condition));
+    }
+
+    for content in section.content() {
+        match content {
+            Content::Prose(prose) => {
+                let prefix = match prose.kind {
+                    ProseKind::Paragraph => "",
+                    ProseKind::Item => "- ",
+                    ProseKind::Quote => "> ",
+                };
+                for line in prose.text.lines() {
+                    output.push_str(&format!("{}{}\n", prefix, line));
+                }
+                output.push('\n');
+            }
+            Content::Block(block) => {
+                output.push_str(&format!("```{}\n{}", block.lang, block.code));
+                if !block.code.ends_with('\n') {
+                    output.push('\n');
+                }
+                output.push_str("```\n\n");
+            }
+        }
+    }
+
+    for child in section.children.iter() {
+        push_section(output, child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::vars::CorgVars;
+
+    #[test]
+    fn test_describe() {
+        let source = "# Faye\n\n## Install Nix\n\nInstalls Nix for all users.\n\n\
+                      ```shell\ncurl -L https://nixos.org/nix/install | sh\n```\n\n\
+                      ### Check\n\n> Takes a minute\n\n```shell\nnix --version\n```\n\n## Deploy\n";
+        let document = Document::parse(source, &CorgVars::new(), None).unwrap();
+
+        assert_eq!(
+            describe(document.step("install-nix").unwrap()),
+            "## Install Nix\n\nInstalls Nix for all users.\n\n\
+             ```shell\ncurl -L https://nixos.org/nix/install | sh\n```\n\n\
+             ### Check\n\n> Takes a minute\n\n```shell\nnix --version\n```\n"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use crate::corg_error::CorgError;
+use crate::run_env::RunEnv;
+use crate::runner;
+use crate::transport::{self, Transport};
+
+/// Environment variable with the password `corg_sudo` gives sudo, for hosts where
+/// sudo needs one and there's no terminal to ask on.
+pub const BECOME_PASSWORD_ENV_VAR: &str = "CORG_BECOME_PASSWORD";
+
+/// Whether the code of a script can run as the users it needs on a host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SudoAccess {
+    /// Sudo lets the user in without a password, e.g. `NOPASSWD`, or with the one
+    /// given.
+    Ready,
+    /// Sudo wants a password, or didn't take the one given.
+    NeedsPassword,
+    /// Sudo isn't installed.
+    Missing,
+}
+
+/// Returns the users a generated script runs code as through `corg_sudo`, in
+/// the order they first come up.
+pub fn script_users(script: &str) -> Vec<String> {
+    let mut users: Vec<String> = vec![];
+
+    for line in script.lines() {
+        let user = line
+            .trim_start()
+            .strip_prefix("corg_sudo '")
+            .and_then(|rest| rest.split('\'').next())
+            .map(String::from);
+
+        if let Some(user) = user {
+            if !users.contains(&user) {
+                users.push(user);
+            }
+        }
+    }
+
+    users
+}
+
+/// Returns the script checking that sudo lets the host's user run code as the
+/// users without asking for a password on a terminal. It exits with 127 when sudo
+/// isn't installed and 1 when it wants a password.
+pub fn check_script(users: &[String]) -> String {
+    let users: Vec<String> = users.iter().map(|user| format!("'{}'", user)).collect();
+
+    format!(
+        "me=$(id -un)\n\
+         for user in {}; do\n\
+         \t[ \"$me\" = \"$user\" ] && continue\n\
+         \tcommand -v sudo > /dev/null 2>&1 || exit 127\n\
+         \tif [ -n \"${{{password}}}\" ]; then\n\
+         \t\tprintf '%s\\n' \"${{{password}}}\" | sudo -S -p '' -u \"$user\" true 2> /dev/null || exit 1\n\
+         \telse\n\
+         \t\tsudo -n -u \"$user\" true 2> /dev/null || exit 1\n\
+         \tfi\n\
+         done\n",
+        users.join(" "),
+        password = BECOME_PASSWORD_ENV_VAR
+    )
+}
+
+/// Checks whether the code of a script can run as `users` on the target named
+/// `name`, with the password of `run_env` when it has one.
+pub fn check(transport: &dyn Transport, name: &str, users: &[String], run_env: &RunEnv) -> Result<SudoAccess, CorgError> {
+    let status = transport::run_script(transport, "sh", &run_env.export(&check_script(users)), &mut |_, _| {})?;
+
+    if transport.unreachable(&status) {
+        return Err(CorgError::Become(format!("{} to {} failed while checking sudo", transport.kind(), name)));
+    }
+
+    match status.code() {
+        Some(0) => Ok(SudoAccess::Ready),
+        Some(1) => Ok(SudoAccess::NeedsPassword),
+        Some(127) => Ok(SudoAccess::Missing),
+        _ => Err(CorgError::Become(format!(
+            "checking sudo on {} failed with exit code {}",
+            name,
+            runner::exit_code(&status)
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use std::process::Command;
+
+    #[test]
+    fn test_script_users() {
+        let script = "function database {\n\tcorg_sudo 'postgres' 'psql'\n\tcorg_sudo 'root' 'apt-get update'\n\tcorg_sudo 'postgres' 'createdb faye'\n}\n";
+
+        assert_eq!(script_users(script), vec!["postgres", "root"]);
+    }
+
+    #[test]
+    fn test_check_script() {
+        // The host's own user never needs sudo.
+        let me = Command::new("id").arg("-un").output().unwrap();
+        let me = String::from_utf8_lossy(&me.stdout).trim().to_string();
+        let status = Command::new("sh").arg("-c").arg(check_script(&[me])).status().unwrap();
+
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    fn test_check() {
+        let users = vec![String::from("postgres")];
+        let mut run_env = RunEnv::new();
+        run_env.insert(BECOME_PASSWORD_ENV_VAR, "hunter22").unwrap();
+
+        let transport = MockTransport::new().with_exit_code(1);
+        assert_eq!(check(&transport, "faye", &users, &run_env).unwrap(), SudoAccess::NeedsPassword);
+        let (command, input) = transport.commands.lock().unwrap()[0].clone();
+        assert_eq!(command, vec!["sh", "-s"]);
+        assert!(input.unwrap().starts_with("export CORG_BECOME_PASSWORD='hunter22'\n"));
+
+        let transport = MockTransport::new().with_exit_code(127);
+        assert_eq!(check(&transport, "faye", &users, &run_env).unwrap(), SudoAccess::Missing);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::attributes::parse_duration;
+use crate::front_matter::scalar_to_string;
+use crate::CORG_VERSION;
+use serde_yaml::{Mapping, Value};
+
+/// Front matter key holding the settings of scheduled runs.
+pub const SCHEDULE_FRONT_MATTER_KEY: &str = "schedule";
+
+/// Host running the script on the machine with the units rather than over ssh.
+pub const LOCAL_HOST: &str = "local";
+
+/// How a scheduled runbook runs, from the `schedule` front matter:
+///
+/// ```yaml
+/// schedule:
+///   on_calendar: daily
+///   user: certbot
+///   environment_file: /etc/corg/certs.env
+///   timeout: 10m
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    /// When the timer fires, see `systemd.time(7)`, e.g. `daily` or `Mon *-*-* 04:00`.
+    pub on_calendar: Option<String>,
+    pub user: Option<String>,
+    /// File with `KEY=value` lines the script gets as environment variables.
+    pub environment_file: Option<String>,
+    /// Seconds a run may take before it's stopped.
+    pub timeout: Option<u64>,
+}
+
+impl Schedule {
+    pub fn from_meta(meta: &Mapping) -> Result<Schedule, String> {
+        let values = match meta.get(&Value::from(SCHEDULE_FRONT_MATTER_KEY)) {
+            Some(Value::Mapping(values)) => values,
+            Some(Value::Null) | None => return Ok(Schedule::default()),
+            Some(_) => return Err(format!("`{}` must be a mapping", SCHEDULE_FRONT_MATTER_KEY)),
+        };
+
+        let get = |key: &str| -> Result<Option<String>, String> {
+            match values.get(&Value::from(key)) {
+                Some(value) => match scalar_to_string(value) {
+                    Some(value) => Ok(Some(value)),
+                    None => Err(format!("`{}.{}` must be a string", SCHEDULE_FRONT_MATTER_KEY, key)),
+                },
+                None => Ok(None),
+            }
+        };
+
+        let timeout = match get("timeout")? {
+            Some(timeout) => {
+                // Plain numbers are seconds.
+                let seconds = match timeout.parse::<f64>() {
+                    Ok(seconds) => seconds,
+                    Err(_) => parse_duration(&timeout)
+                        .map_err(|err| format!("`{}.timeout`: {}", SCHEDULE_FRONT_MATTER_KEY, err))?,
+                };
+                Some(seconds.ceil() as u64)
+            }
+            None => None,
+        };
+
+        Ok(Schedule {
+            on_calendar: get("on_calendar")?,
+            user: get("user")?,
+            environment_file: get("environment_file")?,
+            timeout,
+        })
+    }
+}
+
+/// Renders a oneshot service running the script, on the local machine or on
+/// `host` over ssh. The output of the script goes to the journal, tagged with the
+/// name of the unit.
+pub fn push_service(s: &mut String, unit_name: &str, description: &str, schedule: &Schedule, host: &str, script: &str) {
+    push_header(s);
+
+    s.push_str("[Unit]\n");
+    s.push_str(&format!("Description=Corg runbook: {}\n", escape_specifiers(description)));
+    s.push_str("Wants=network-online.target\n");
+    s.push_str("After=network-online.target\n\n");
+
+    s.push_str("[Service]\n");
+    s.push_str("Type=oneshot\n");
+
+    if host == LOCAL_HOST {
+        s.push_str(&format!("ExecStart=/usr/bin/env zsh {}\n", exec_word(script)));
+    } else {
+        // The script is standalone, so it can be piped to a shell on the host.
+        s.push_str(&format!("ExecStart=/usr/bin/env ssh -T {} zsh -s\n", exec_word(host)));
+        s.push_str(&format!("StandardInput=file:{}\n", escape_specifiers(script)));
+    }
+
+    if let Some(user) = &schedule.user {
+        s.push_str(&format!("User={}\n", escape_specifiers(user)));
+    }
+    if let Some(environment_file) = &schedule.environment_file {
+        s.push_str(&format!("EnvironmentFile={}\n", escape_specifiers(environment_file)));
+    }
+    if let Some(timeout) = schedule.timeout {
+        s.push_str(&format!("TimeoutStartSec={}\n", timeout));
+    }
+
+    s.push_str("StandardOutput=journal\n");
+    s.push_str("StandardError=journal\n");
+    s.push_str(&format!("SyslogIdentifier={}\n", escape_specifiers(unit_name)));
+}
+
+/// Renders the timer starting the service of the same name. Runs missed while
+/// the machine was off are caught up on the next boot.
+pub fn push_timer(s: &mut String, unit_name: &str, description: &str, on_calendar: &str) {
+    push_header(s);
+
+    s.push_str("[Unit]\n");
+    s.push_str(&format!("Description=Schedule of corg runbook: {}\n\n", escape_specifiers(description)));
+
+    s.push_str("[Timer]\n");
+    s.push_str(&format!("OnCalendar={}\n", on_calendar));
+    s.push_str("Persistent=true\n");
+    s.push_str(&format!("Unit={}.service\n\n", unit_name));
+
+    s.push_str("[Install]\n");
+    s.push_str("WantedBy=timers.target\n");
+}
+
+fn push_header(s: &mut String) {
+    s.push_str(&format!("# -- GENERATED BY CORG v{} --\n", CORG_VERSION));
+}
+
+/// Escapes the `%` specifiers systemd expands in unit settings, e.g. `%h`.
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// Quotes a word of `ExecStart=` the way systemd splits the command line, with
+/// `$` escaped so it isn't taken for an environment variable.
+fn exec_word(word: &str) -> String {
+    let word = escape_specifiers(word);
+    let plain = !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "_-./=:@%+,".contains(c));
+
+    if plain {
+        word
+    } else {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(yaml: &str) -> Mapping {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_from_meta() {
+        let schedule = Schedule::from_meta(&meta("schedule:\n  user: certbot\n  timeout: 10m\n")).unwrap();
+
+        assert_eq!(schedule.user.as_deref(), Some("certbot"));
+        assert_eq!(schedule.timeout, Some(600));
+        assert_eq!(schedule.on_calendar, None);
+        assert_eq!(Schedule::from_meta(&Mapping::new()).unwrap(), Schedule::default());
+        assert!(Schedule::from_meta(&meta("schedule:\n  timeout: soon\n")).is_err());
+    }
+
+    #[test]
+    fn test_push_service() {
+        let schedule = Schedule {
+            user: Some(String::from("certbot")),
+            timeout: Some(600),
+            ..Schedule::default()
+        };
+        let mut local = String::new();
+        push_service(&mut local, "corg-certs", "Renew certs", &schedule, LOCAL_HOST, "/srv/units/corg-certs.sh");
+
+        assert!(local.contains("Description=Corg runbook: Renew certs\n"));
+        assert!(local.contains("Type=oneshot\nExecStart=/usr/bin/env zsh /srv/units/corg-certs.sh\nUser=certbot\n\
+                                TimeoutStartSec=600\nStandardOutput=journal\n"));
+        assert!(local.ends_with("SyslogIdentifier=corg-certs\n"));
+
+        let mut remote = String::new();
+        push_service(&mut remote, "corg-certs", "Renew certs", &schedule, "faye", "/srv/units/corg-certs.sh");
+
+        assert!(remote.contains("ExecStart=/usr/bin/env ssh -T faye zsh -s\nStandardInput=file:/srv/units/corg-certs.sh\n"));
+    }
+
+    #[test]
+    fn test_push_service_escapes() {
+        let schedule = Schedule {
+            environment_file: Some(String::from("/etc/corg/%i.env")),
+            ..Schedule::default()
+        };
+        let mut local = String::new();
+        push_service(&mut local, "corg-clean", "Clean up at 90%", &schedule, LOCAL_HOST, "/srv/my units/say \"$hi\".sh");
+
+        assert!(local.contains("Description=Corg runbook: Clean up at 90%%\n"));
+        assert!(local.contains("ExecStart=/usr/bin/env zsh \"/srv/my units/say \\\"$$hi\\\".sh\"\n"));
+        assert!(local.contains("EnvironmentFile=/etc/corg/%%i.env\n"));
+
+        let mut remote = String::new();
+        push_service(&mut remote, "corg-clean", "Clean up", &schedule, "admin@faye host", "/srv/my units/clean.sh");
+
+        assert!(remote.contains("ExecStart=/usr/bin/env ssh -T \"admin@faye host\" zsh -s\nStandardInput=file:/srv/my units/clean.sh\n"));
+    }
+
+    #[test]
+    fn test_push_timer() {
+        let mut timer = String::new();
+        push_timer(&mut timer, "corg-certs", "Renew certs", "daily");
+
+        assert!(timer.contains("[Timer]\nOnCalendar=daily\nPersistent=true\nUnit=corg-certs.service\n\n\
+                                [Install]\nWantedBy=timers.target\n"));
+    }
+}
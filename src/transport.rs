@@ -0,0 +1,632 @@
+use crate::corg_error::CorgError;
+use crate::events::{self, LogEvent};
+use crate::run_env;
+use crate::runner::{self, Ssh, Stream};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Gets scripts and files to the machine a run targets and runs commands there.
+/// The run itself only goes through these, so a new kind of target only needs a
+/// transport of its own.
+pub trait Transport: Sync {
+    /// What the transport goes through, e.g. `ssh`, for messages.
+    fn kind(&self) -> &str;
+
+    /// Writes `contents` to `path` on the target, creating the directories
+    /// leading to it. `cleanup` removes it again.
+    fn upload(&self, path: &str, contents: &[u8]) -> Result<(), CorgError>;
+
+    /// Creates an empty file on the target which only the user there can read
+    /// and write, under a name nobody can guess, and returns its path. `cleanup`
+    /// removes it again.
+    fn temp_file(&self) -> Result<String, CorgError>;
+
+    /// Runs the command on the target, writing `input` to its stdin, and calls
+    /// `on_line` with every line it writes to stdout or stderr as it comes in.
+    fn exec_streaming(
+        &self,
+        command: &[&str],
+        input: Option<&str>,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<ExitStatus, CorgError>;
+
+    /// Reads the file at `path` on the target.
+    fn download(&self, path: &str) -> Result<Vec<u8>, CorgError>;
+
+    /// Removes everything uploaded so far.
+    fn cleanup(&self) -> Result<(), CorgError>;
+
+    /// Whether the status is the transport's own failure to reach the target
+    /// rather than the command's, e.g. ssh's 255.
+    fn unreachable(&self, _status: &ExitStatus) -> bool {
+        false
+    }
+}
+
+/// Pipes a standalone script to `shell -s` on the target and cleans up after it,
+/// the output is passed to `on_line` like for `runner::run_script`.
+pub fn run_script(
+    transport: &dyn Transport,
+    shell: &str,
+    script: &str,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<ExitStatus, CorgError> {
+    let result = transport.exec_streaming(&[shell, "-s"], Some(script), on_line);
+    let cleaned = transport.cleanup();
+
+    let status = result?;
+    cleaned?;
+    Ok(status)
+}
+
+/// Runs a script like `run_script` with the logger writing its events to a
+/// `temp_file` on the target (see `events::LOG_FILE_ENV_VAR`), and returns them
+/// along with the status. The file is read back before cleaning up.
+pub fn run_script_with_events(
+    transport: &dyn Transport,
+    shell: &str,
+    script: &str,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<(ExitStatus, Vec<LogEvent>), CorgError> {
+    let events_path = transport.temp_file()?;
+
+    let script = run_env::export_vars(script, &[(events::LOG_FILE_ENV_VAR, &events_path)]);
+    let result = transport.exec_streaming(&[shell, "-s"], Some(&script), on_line);
+    // The events are a side channel, the run doesn't fail without them.
+    let events = transport.download(&events_path).unwrap_or_default();
+    let cleaned = transport.cleanup();
+
+    let status = result?;
+    cleaned?;
+    Ok((status, events::parse_events(&String::from_utf8_lossy(&events))))
+}
+
+/// Runs commands on this machine from a directory, with extra environment
+/// variables. Paths of files are relative to the directory.
+#[derive(Debug)]
+pub struct LocalTransport {
+    dir: PathBuf,
+    env: Vec<(String, String)>,
+    uploaded: Mutex<Vec<PathBuf>>,
+}
+
+impl LocalTransport {
+    pub fn new(dir: &Path, env: &[(&str, &str)]) -> LocalTransport {
+        LocalTransport {
+            dir: dir.to_path_buf(),
+            env: env.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect(),
+            uploaded: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Transport for LocalTransport {
+    fn kind(&self) -> &str {
+        "local"
+    }
+
+    fn upload(&self, path: &str, contents: &[u8]) -> Result<(), CorgError> {
+        let path = self.dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, contents)?;
+
+        lock(&self.uploaded).push(path);
+        Ok(())
+    }
+
+    /// Created with `mkstemp` in the temporary directory of this machine.
+    fn temp_file(&self) -> Result<String, CorgError> {
+        let template = env::temp_dir().join("corg-XXXXXX");
+        let mut template = CString::new(template.as_os_str().as_bytes())
+            .map_err(|_| CorgError::Transport(String::from("the temporary directory contains a NUL byte")))?
+            .into_bytes_with_nul();
+
+        let fd = unsafe { libc::mkstemp(template.as_mut_ptr() as *mut libc::c_char) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        unsafe {
+            libc::close(fd);
+        }
+
+        template.pop();
+        let path = PathBuf::from(OsString::from_vec(template));
+        lock(&self.uploaded).push(path.clone());
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// Commands without input share corg's stdin, see `runner::run_script`.
+    fn exec_streaming(
+        &self,
+        command: &[&str],
+        input: Option<&str>,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<ExitStatus, CorgError> {
+        let (program, args) = split_command(command)?;
+        let mut command = Command::new(program);
+        command.args(args).current_dir(&self.dir).envs(self.env.iter().cloned());
+
+        runner::run_command(command, input.map(String::from), on_line)
+    }
+
+    fn download(&self, path: &str) -> Result<Vec<u8>, CorgError> {
+        Ok(fs::read(self.dir.join(path))?)
+    }
+
+    fn cleanup(&self) -> Result<(), CorgError> {
+        for path in lock(&self.uploaded).drain(..) {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A target reached through a local command which runs its arguments there, e.g.
+/// `ssh faye` or `podman exec -i web`. Files are moved with `cat` over the
+/// command's stdin and stdout, so the target only needs a POSIX shell.
+#[derive(Debug)]
+pub struct CommandTransport {
+    kind: String,
+    /// The command and the arguments leading up to what runs on the target.
+    prefix: Vec<String>,
+    /// ssh joins its arguments with spaces for the remote shell, so they're
+    /// quoted for it.
+    joins_arguments: bool,
+    /// Extra environment variables of the command, e.g. a `PATH` with a stub.
+    env: Vec<(String, String)>,
+    uploaded: Mutex<Vec<String>>,
+}
+
+impl CommandTransport {
+    /// Runs commands on `host` with the system ssh client.
+    pub fn ssh(ssh: &Ssh, host: &str) -> CommandTransport {
+        CommandTransport::new("ssh", ssh.args(host), true)
+    }
+
+    /// Runs commands in a container, chroot or the namespaces of a process.
+    /// Fails when the tool it needs isn't installed here.
+    pub fn target(target: &Target) -> Result<CommandTransport, CorgError> {
+        let program = target.program();
+        if !is_installed(program) {
+            return Err(CorgError::Transport(format!("{} isn't installed, it's needed to run in {}", program, target)));
+        }
+
+        let pid;
+        let prefix = match target {
+            Target::Podman(container) => vec!["podman", "exec", "-i", container],
+            Target::Chroot(dir) => vec!["chroot", dir],
+            Target::Nsenter(id) => {
+                pid = id.to_string();
+                vec!["nsenter", "--target", &pid, "--mount", "--uts", "--ipc", "--net", "--pid"]
+            }
+        };
+
+        Ok(CommandTransport::new(program, prefix.into_iter().map(String::from).collect(), false))
+    }
+
+    fn new(kind: &str, prefix: Vec<String>, joins_arguments: bool) -> CommandTransport {
+        CommandTransport {
+            kind: String::from(kind),
+            prefix,
+            joins_arguments,
+            env: vec![],
+            uploaded: Mutex::new(vec![]),
+        }
+    }
+
+    /// Sets an environment variable of the command here, not of what it runs on
+    /// the target. `PATH` also changes where the command is looked up.
+    pub fn with_env(mut self, name: &str, value: &str) -> CommandTransport {
+        self.env.push((String::from(name), String::from(value)));
+        self
+    }
+
+    fn command(&self, command: &[&str]) -> Result<Command, CorgError> {
+        let (program, prefix) = split_command(&self.prefix)?;
+        let mut output = Command::new(program);
+        output.args(prefix).envs(self.env.iter().cloned());
+
+        if self.joins_arguments {
+            let words: Vec<String> = command.iter().map(|word| shell_word(word)).collect();
+            output.arg(words.join(" "));
+        } else {
+            output.args(command);
+        }
+
+        Ok(output)
+    }
+
+    /// Runs a `sh -c` script on the target with the arguments, failing with the
+    /// error output of the command when it doesn't succeed.
+    fn sh(&self, action: &str, script: &str, args: &[&str], input: Option<&[u8]>) -> Result<Output, CorgError> {
+        let mut command = vec!["sh", "-c", script, "sh"];
+        command.extend(args);
+
+        let output = run_captured(self.command(&command)?, input)?;
+        if !output.status.success() {
+            return Err(CorgError::Transport(format!(
+                "{} over {} failed: {}",
+                action,
+                self.kind,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(output)
+    }
+}
+
+impl Transport for CommandTransport {
+    fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    fn upload(&self, path: &str, contents: &[u8]) -> Result<(), CorgError> {
+        let script = "mkdir -p \"$(dirname \"$1\")\" && cat > \"$1\"";
+        self.sh(&format!("uploading {}", path), script, &[path], Some(contents))?;
+
+        lock(&self.uploaded).push(String::from(path));
+        Ok(())
+    }
+
+    /// Created with `mktemp`, which makes it private, in `$TMPDIR` of the target.
+    fn temp_file(&self) -> Result<String, CorgError> {
+        let output = self.sh("creating a temporary file", "mktemp \"${TMPDIR:-/tmp}/corg-XXXXXX\"", &[], None)?;
+        let path = String::from_utf8_lossy(&output.stdout).trim_end().to_string();
+        if path.is_empty() {
+            return Err(CorgError::Transport(format!("mktemp over {} didn't print a path", self.kind)));
+        }
+
+        lock(&self.uploaded).push(path.clone());
+        Ok(path)
+    }
+
+    fn exec_streaming(
+        &self,
+        command: &[&str],
+        input: Option<&str>,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<ExitStatus, CorgError> {
+        runner::run_command(self.command(command)?, input.map(String::from), on_line)
+    }
+
+    fn download(&self, path: &str) -> Result<Vec<u8>, CorgError> {
+        Ok(self.sh(&format!("downloading {}", path), "cat -- \"$1\"", &[path], None)?.stdout)
+    }
+
+    fn cleanup(&self) -> Result<(), CorgError> {
+        let mut uploaded = lock(&self.uploaded);
+        if uploaded.is_empty() {
+            return Ok(());
+        }
+
+        let paths: Vec<&str> = uploaded.iter().map(String::as_str).collect();
+        self.sh("cleaning up", "rm -f -- \"$@\"", &paths, None)?;
+        uploaded.clear();
+
+        Ok(())
+    }
+
+    fn unreachable(&self, status: &ExitStatus) -> bool {
+        self.joins_arguments && status.code() == Some(255)
+    }
+}
+
+/// Where `--target` runs a script on this machine, e.g. `podman:web`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// A running podman container, by name or id.
+    Podman(String),
+    /// A directory holding another root file system.
+    Chroot(String),
+    /// The namespaces of a running process, e.g. a container started by another tool.
+    Nsenter(u32),
+}
+
+impl Target {
+    /// The tool the target is entered with.
+    pub fn program(&self) -> &'static str {
+        match self {
+            Target::Podman(_) => "podman",
+            Target::Chroot(_) => "chroot",
+            Target::Nsenter(_) => "nsenter",
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Target, String> {
+        let (kind, place) = match text.split_once(':') {
+            Some((kind, place)) if !place.is_empty() => (kind, place),
+            _ => return Err(format!("'{}' isn't a target, use podman:CONTAINER, chroot:DIR or nsenter:PID", text)),
+        };
+
+        match kind {
+            "podman" => Ok(Target::Podman(String::from(place))),
+            "chroot" => Ok(Target::Chroot(String::from(place))),
+            "nsenter" => place
+                .parse()
+                .map(Target::Nsenter)
+                .map_err(|_| format!("nsenter needs the id of a process, not '{}'", place)),
+            _ => Err(format!("unknown target '{}', use podman, chroot or nsenter", kind)),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Podman(container) => write!(f, "podman:{}", container),
+            Target::Chroot(dir) => write!(f, "chroot:{}", dir),
+            Target::Nsenter(pid) => write!(f, "nsenter:{}", pid),
+        }
+    }
+}
+
+/// A target which only exists in memory, for tests. Every command gets the same
+/// output and exit code, and what ran is recorded.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    /// Files on the target by path.
+    pub files: Mutex<BTreeMap<String, Vec<u8>>>,
+    /// Commands run so far, with what was written to their stdin.
+    pub commands: Mutex<Vec<(Vec<String>, Option<String>)>>,
+    output: Vec<(Stream, String)>,
+    exit_code: i32,
+    uploaded: Mutex<Vec<String>>,
+    temp_files: AtomicUsize,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Adds a line to the output of every command.
+    pub fn with_line(mut self, stream: Stream, line: &str) -> MockTransport {
+        self.output.push((stream, String::from(line)));
+        self
+    }
+
+    /// Sets the exit code of every command.
+    pub fn with_exit_code(mut self, exit_code: i32) -> MockTransport {
+        self.exit_code = exit_code;
+        self
+    }
+}
+
+impl Transport for MockTransport {
+    fn kind(&self) -> &str {
+        "mock"
+    }
+
+    fn upload(&self, path: &str, contents: &[u8]) -> Result<(), CorgError> {
+        lock(&self.files).insert(String::from(path), contents.to_vec());
+        lock(&self.uploaded).push(String::from(path));
+        Ok(())
+    }
+
+    /// Named `/tmp/corg-mock-<n>`, counting up from 1.
+    fn temp_file(&self) -> Result<String, CorgError> {
+        let path = format!("/tmp/corg-mock-{}", self.temp_files.fetch_add(1, Ordering::SeqCst) + 1);
+        self.upload(&path, b"")?;
+        Ok(path)
+    }
+
+    fn exec_streaming(
+        &self,
+        command: &[&str],
+        input: Option<&str>,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<ExitStatus, CorgError> {
+        let command = command.iter().map(|word| String::from(*word)).collect();
+        lock(&self.commands).push((command, input.map(String::from)));
+
+        for (stream, line) in self.output.iter() {
+            on_line(*stream, line);
+        }
+
+        Ok(ExitStatus::from_raw(self.exit_code << 8))
+    }
+
+    fn download(&self, path: &str) -> Result<Vec<u8>, CorgError> {
+        lock(&self.files)
+            .get(path)
+            .cloned()
+            .ok_or_else(|| CorgError::Transport(format!("{} doesn't exist", path)))
+    }
+
+    fn cleanup(&self) -> Result<(), CorgError> {
+        let mut files = lock(&self.files);
+        for path in lock(&self.uploaded).drain(..) {
+            files.remove(&path);
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the program is an executable on `PATH`.
+pub fn is_installed(program: &str) -> bool {
+    let path = env::var_os("PATH").unwrap_or_default();
+
+    env::split_paths(&path).any(|dir| {
+        fs::metadata(dir.join(program)).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    })
+}
+
+fn split_command<S: AsRef<str>>(command: &[S]) -> Result<(&str, &[S]), CorgError> {
+    match command.split_first() {
+        Some((program, args)) => Ok((program.as_ref(), args)),
+        None => Err(CorgError::Transport(String::from("no command to run"))),
+    }
+}
+
+/// Runs the command to completion, writing `input` to its stdin.
+fn run_captured(mut command: Command, input: Option<&[u8]>) -> Result<Output, CorgError> {
+    let mut child = command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_vec();
+            Some(thread::spawn(move || {
+                // Fails when the command exits early, which its status reports.
+                let _ = stdin.write_all(&input);
+            }))
+        }
+        _ => None,
+    };
+
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+
+    Ok(output)
+}
+
+/// Quotes a word for a shell unless it's made of characters the shell takes
+/// literally anyway.
+fn shell_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || "_-./=:@%+,".contains(c));
+
+    if plain {
+        String::from(word)
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Locks the mutex, a panic elsewhere doesn't make what it holds wrong.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_script() {
+        let transport = MockTransport::new().with_line(Stream::Stdout, "[ok] done").with_exit_code(2);
+        transport.upload("/tmp/corg/vars", b"DEBUG=1\n").unwrap();
+        assert_eq!(transport.download("/tmp/corg/vars").unwrap(), b"DEBUG=1\n");
+
+        let mut lines = vec![];
+        let status = run_script(&transport, "zsh", "corg_step deploy\n", &mut |stream, line| {
+            lines.push((stream, String::from(line)))
+        })
+        .unwrap();
+
+        assert_eq!(runner::exit_code(&status), 2);
+        assert_eq!(lines, vec![(Stream::Stdout, String::from("[ok] done"))]);
+        assert_eq!(
+            *transport.commands.lock().unwrap(),
+            vec![(vec![String::from("zsh"), String::from("-s")], Some(String::from("corg_step deploy\n")))]
+        );
+        // Cleaned up after the run.
+        assert!(transport.download("/tmp/corg/vars").is_err());
+    }
+
+    #[test]
+    fn test_run_script_with_events() {
+        let transport = MockTransport::new();
+        let (status, events) = run_script_with_events(&transport, "zsh", "#!/bin/zsh\ncorg_info hi\n", &mut |_, _| {}).unwrap();
+
+        assert!(status.success());
+        assert!(events.is_empty());
+        assert_eq!(
+            transport.commands.lock().unwrap()[0].1.as_deref(),
+            Some("#!/bin/zsh\nexport CORG_LOG_FILE='/tmp/corg-mock-1'\ncorg_info hi\n")
+        );
+        assert!(transport.files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_local_temp_file() {
+        let transport = LocalTransport::new(&env::temp_dir(), &[]);
+        let first = transport.temp_file().unwrap();
+        let second = transport.temp_file().unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(transport.download(&first).unwrap(), b"");
+
+        transport.cleanup().unwrap();
+        assert!(!Path::new(&first).exists());
+        assert!(!Path::new(&second).exists());
+    }
+
+    #[test]
+    fn test_command_transport() {
+        // Stands in for ssh, running the command after `-T faye` with sh.
+        let dir = env::temp_dir().join(format!("corg-transport-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let stub = dir.join("ssh");
+        fs::write(&stub, "#!/bin/sh\nshift 2\nexec sh -c \"$1\"\n").unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let ssh = Ssh {
+            program: stub.to_string_lossy().to_string(),
+            ..Ssh::default()
+        };
+        let transport = CommandTransport::ssh(&ssh, "faye");
+        let path = dir.join("uploads/it's here.txt");
+        let path = path.to_str().unwrap();
+
+        transport.upload(path, b"El Psy Kongroo\n").unwrap();
+        assert_eq!(transport.download(path).unwrap(), b"El Psy Kongroo\n");
+
+        let mut lines = vec![];
+        let status = transport
+            .exec_streaming(&["cat", path], None, &mut |_, line| lines.push(String::from(line)))
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(lines, vec!["El Psy Kongroo"]);
+
+        let temp_file = transport.temp_file().unwrap();
+        assert_eq!(fs::metadata(&temp_file).unwrap().permissions().mode() & 0o777, 0o600);
+
+        transport.cleanup().unwrap();
+        assert!(!Path::new(path).exists());
+        assert!(!Path::new(&temp_file).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!("podman:web".parse(), Ok(Target::Podman(String::from("web"))));
+        assert_eq!("chroot:/srv/faye".parse(), Ok(Target::Chroot(String::from("/srv/faye"))));
+        assert_eq!("nsenter:1234".parse(), Ok(Target::Nsenter(1234)));
+        assert!("nsenter:web".parse::<Target>().is_err());
+        assert!("docker:web".parse::<Target>().is_err());
+        assert_eq!(Target::Podman(String::from("web")).to_string(), "podman:web");
+    }
+}
@@ -0,0 +1,284 @@
+use crate::corg_error::CorgError;
+use crate::front_matter::{scalar_to_string, FrontMatter};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fs;
+
+/// Front matter key holding the default variables of a document.
+pub const VARS_FRONT_MATTER_KEY: &str = "vars";
+/// Front matter key holding per environment overlays of the variables.
+pub const ENVIRONMENTS_FRONT_MATTER_KEY: &str = "environments";
+
+/// Matches `{{ name }}` placeholders. A placeholder directly after a `$` is left
+/// alone so it can't collide with shell (or GitHub Actions) syntax, and one
+/// preceded by a backslash is written out literally without the backslash.
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(\\|\$)?\{\{\s*([A-Za-z_][A-Za-z0-9_.\-]*)\s*\}\}").unwrap()
+});
+
+/// A placeholder without a value.
+#[derive(Debug, PartialEq)]
+pub struct UndefinedVariable {
+    pub name: String,
+    /// The placeholder as written, e.g. `{{ name }}`.
+    pub placeholder: String,
+}
+
+/// Values for the `{{ var }}` placeholders of a document.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorgVars {
+    values: HashMap<String, String>,
+}
+
+impl CorgVars {
+    pub fn new() -> CorgVars {
+        CorgVars {
+            values: HashMap::new(),
+        }
+    }
+
+    /// Reads the `vars` of the front matter, overlaid with the vars of the given
+    /// environment (from `environments.<name>`) when there is one.
+    pub fn from_front_matter(front_matter: Option<&FrontMatter>, environment: Option<&str>) -> Result<CorgVars, CorgError> {
+        let mut vars = CorgVars::new();
+
+        if let Some(values) = front_matter.and_then(|front_matter| front_matter.get(VARS_FRONT_MATTER_KEY)) {
+            vars.insert_value("", values)?;
+        }
+
+        if let Some(environment) = environment {
+            let environments = front_matter.and_then(|front_matter| front_matter.get(ENVIRONMENTS_FRONT_MATTER_KEY));
+            let overlay = match environments {
+                Some(Value::Mapping(environments)) => environments.get(&Value::String(String::from(environment))),
+                _ => None,
+            };
+
+            match overlay {
+                Some(values) => vars.insert_value("", values)?,
+                None => {
+                    return Err(CorgError::Vars(format!(
+                        "unknown environment '{}', add it to `{}` in the front matter",
+                        environment, ENVIRONMENTS_FRONT_MATTER_KEY
+                    )))
+                }
+            }
+        }
+
+        Ok(vars)
+    }
+
+    /// Reads a vars file with one `key=value` per line, `#` starts a comment.
+    pub fn read_file(path: &str) -> Result<CorgVars, CorgError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| CorgError::Vars(format!("{}: {}", path, err)))?;
+        let mut vars = CorgVars::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line = line.trim_start_matches("export ");
+            let (key, value) = CorgVars::parse_assignment(line)
+                .map_err(|err| CorgError::Vars(format!("{}:{}: {}", path, index + 1, err)))?;
+            vars.insert(&key, &value);
+        }
+
+        Ok(vars)
+    }
+
+    /// Parses a `key=value` assignment as given to `--var`.
+    pub fn parse_assignment(assignment: &str) -> Result<(String, String), String> {
+        let mut parts = assignment.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) => unquote(value.trim()),
+            None => return Err(format!("expected key=value, got '{}'", assignment)),
+        };
+
+        if !is_valid_name(key) {
+            return Err(format!("'{}' is not a valid variable name", key));
+        }
+
+        Ok((String::from(key), String::from(value)))
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.values.insert(String::from(key), String::from(value));
+    }
+
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.values.get(key)
+    }
+
+    /// Returns the variables sorted by name.
+    pub fn entries(&self) -> Vec<(&str, &str)> {
+        let mut entries: Vec<(&str, &str)> = self
+            .values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Adds all the values of `other`, replacing existing ones.
+    pub fn extend(&mut self, other: &CorgVars) {
+        for (key, value) in other.values.iter() {
+            self.insert(key, value);
+        }
+    }
+
+    /// Nested mappings are flattened into dotted names, e.g. `db.host`.
+    fn insert_value(&mut self, prefix: &str, value: &Value) -> Result<(), CorgError> {
+        match value {
+            Value::Mapping(values) => {
+                for (key, value) in values.iter() {
+                    let key = match scalar_to_string(key) {
+                        Some(key) if prefix.is_empty() => key,
+                        Some(key) => format!("{}.{}", prefix, key),
+                        None => return Err(CorgError::Vars(format!("invalid variable name {:?}", key))),
+                    };
+
+                    self.insert_value(&key, value)?;
+                }
+
+                Ok(())
+            }
+            Value::Null => Ok(()),
+            value => match scalar_to_string(value) {
+                Some(value) if is_valid_name(prefix) => {
+                    self.insert(prefix, &value);
+                    Ok(())
+                }
+                Some(_) => Err(CorgError::Vars(format!("'{}' is not a valid variable name", prefix))),
+                None => Err(CorgError::Vars(format!("variable '{}' must be a string, number or boolean", prefix))),
+            },
+        }
+    }
+
+    /// Replaces the placeholders in the text with their values.
+    pub fn substitute(&self, text: &str) -> Result<String, UndefinedVariable> {
+        let mut undefined: Option<UndefinedVariable> = None;
+
+        let output = PLACEHOLDER.replace_all(text, |captures: &Captures| {
+            let placeholder = captures.get(0).unwrap().as_str();
+            let name = &captures[2];
+
+            match captures.get(1).map(|prefix| prefix.as_str()) {
+                Some("\\") => String::from(&placeholder[1..]),
+                Some(_) => String::from(placeholder),
+                None => match self.get(name) {
+                    Some(value) => value.clone(),
+                    None => {
+                        if undefined.is_none() {
+                            undefined = Some(UndefinedVariable {
+                                name: String::from(name),
+                                placeholder: String::from(placeholder),
+                            });
+                        }
+                        String::from(placeholder)
+                    }
+                },
+            }
+        });
+
+        match undefined {
+            Some(undefined) => Err(undefined),
+            None => Ok(output.into_owned()),
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+        }
+        _ => false,
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    let quoted = value.len() >= 2
+        && ((value.starts_with('"') && value.ends_with('"'))
+            || (value.starts_with('\'') && value.ends_with('\'')));
+
+    if quoted {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> CorgVars {
+        let mut vars = CorgVars::new();
+        vars.insert("name", "faye");
+        vars.insert("db.host", "10.0.0.2");
+        vars
+    }
+
+    #[test]
+    fn test_substitute() {
+        let actual = vars().substitute("ssh {{name}} -- ping {{ db.host }}");
+
+        assert_eq!(actual, Ok(String::from("ssh faye -- ping 10.0.0.2")));
+    }
+
+    #[test]
+    fn test_substitute_leaves_shell_syntax_alone() {
+        let text = "echo ${name} ${{ name }} \\{{ name }} {{print $1}} {{.State.Running}}";
+        let actual = vars().substitute(text);
+
+        assert_eq!(
+            actual,
+            Ok(String::from("echo ${name} ${{ name }} {{ name }} {{print $1}} {{.State.Running}}"))
+        );
+    }
+
+    #[test]
+    fn test_substitute_undefined() {
+        let actual = vars().substitute("echo {{ name }} {{ nope }}");
+
+        assert_eq!(
+            actual,
+            Err(UndefinedVariable {
+                name: String::from("nope"),
+                placeholder: String::from("{{ nope }}"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_front_matter() {
+        let source = "---\nvars:\n  name: faye\n  db:\n    host: 10.0.0.1\nenvironments:\n  prod:\n    db:\n      host: 10.0.0.2\n---\n";
+        let (front_matter, _) = FrontMatter::split(source).unwrap();
+
+        let defaults = CorgVars::from_front_matter(front_matter.as_ref(), None).unwrap();
+        let prod = CorgVars::from_front_matter(front_matter.as_ref(), Some("prod")).unwrap();
+
+        assert_eq!(defaults.get("db.host"), Some(&String::from("10.0.0.1")));
+        assert_eq!(prod.get("db.host"), Some(&String::from("10.0.0.2")));
+        assert_eq!(prod.get("name"), Some(&String::from("faye")));
+        assert!(CorgVars::from_front_matter(front_matter.as_ref(), Some("staging")).is_err());
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            CorgVars::parse_assignment("name=\"faye v2\""),
+            Ok((String::from("name"), String::from("faye v2")))
+        );
+        assert!(CorgVars::parse_assignment("name").is_err());
+        assert!(CorgVars::parse_assignment("1name=x").is_err());
+    }
+}
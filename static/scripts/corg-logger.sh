@@ -137,6 +137,8 @@ log_level_colors+=(["error"]="${fg_bold[$error_color]}")
 log_level_colors+=(["ok"]="${fg_bold[$ok_color]}")
 log_level_colors+=(["warn"]="${fg_bold[$warn_color]}")
 log_level_colors+=(["announce"]="${fg_bold[$announce_color]}")
+log_level_colors+=(["step"]="${fg_bold[$announce_color]}")
+log_level_colors+=(["done"]="${fg_bold[$ok_color]}")
 
 # Logs messages, conumsed by the other logger shortcuts
 function corg_log() {
@@ -144,6 +146,47 @@ function corg_log() {
 	status_level=$1
 	status_message="${reset_color}[${log_level_colors[$status_level]}$status_level${reset_color}]"
 	echo "${status_message} $2"
+	corg_log_event "$status_level" "$2"
+}
+
+# Appends a logged line as JSON to CORG_LOG_FILE when it's set, the side channel
+# runners and log shipping read instead of the colored output:
+#
+#   {"timestamp":"2026-10-18T11:30:00.250+0200","level":"info","section":"deploy","host":"faye","message":"..."}
+function corg_log_event() {
+	[ -n "${CORG_LOG_FILE}" ] || return 0
+	zmodload zsh/datetime
+	local now=$EPOCHREALTIME timestamp offset level section host message
+
+	strftime -s timestamp '%Y-%m-%dT%H:%M:%S' ${now%.*}
+	strftime -s offset '%z' ${now%.*}
+	corg_json_string "$1"; level=$REPLY
+	corg_json_string "${corg_current_step}"; section=$REPLY
+	corg_json_string "${HOST:-$(hostname)}"; host=$REPLY
+	corg_json_string "$2"; message=$REPLY
+
+	# Another user (see corg_sudo) may not be allowed to write the file.
+	print -r -- "{\"timestamp\":\"${timestamp}.${${now#*.}[1,3]}${offset}\",\"level\":\"${level}\",\"section\":\"${section}\",\"host\":\"${host}\",\"message\":\"${message}\"}" 2> /dev/null >> "${CORG_LOG_FILE}" || true
+}
+
+# Escapes text for a JSON string into REPLY, without the colors of the logger.
+# Control characters without a short escape become \u00XX.
+function corg_json_string() {
+	setopt localoptions extendedglob
+	local code escaped
+	REPLY=${1//$'\e'\[[0-9;]#m/}
+	REPLY=${REPLY//\\/\\\\}
+	REPLY=${REPLY//\"/\\\"}
+	REPLY=${REPLY//$'\n'/\\n}
+	REPLY=${REPLY//$'\r'/\\r}
+	REPLY=${REPLY//$'\t'/\\t}
+
+	if [[ $REPLY == *[$'\x01'-$'\x1f']* ]]; then
+		for code in {1..31}; do
+			printf -v escaped '\\u%04x' $code
+			REPLY=${REPLY//${(#)code}/$escaped}
+		done
+	fi
 }
 
 function corg_announce() {
@@ -177,4 +220,228 @@ function corg_msg() {
     echo $1
 }
 
+# Runs a command until it succeeds, used for code blocks with a retry attribute:
+#
+#   corg_retry <attempts> <fixed|linear|exp> <delay-seconds> <name> <command...>
+#
+# Each attempt runs in a subshell with errexit so any failing line fails the
+# attempt. The status of the last attempt is returned so it survives `set -e`.
+function corg_retry() {
+	setopt localoptions noerrexit
+	local attempts=$1 backoff=$2 delay=$3 name=$4
+	shift 4
+	local wait=$delay attempt=1 corg_status=0
+
+	while true; do
+		( setopt errexit; "$@" )
+		corg_status=$?
+
+		if [ $corg_status -eq 0 ]; then
+			return 0
+		fi
+
+		if [ $attempt -ge $attempts ]; then
+			corg_error "${name} failed after ${attempts} attempts (exit ${corg_status})"
+			return $corg_status
+		fi
+
+		corg_warn "${name} failed attempt ${attempt}/${attempts} (exit ${corg_status}), retrying in ${wait}s"
+		sleep $wait
+
+		case $backoff in
+			linear) wait=$(( wait + delay )) ;;
+			exp) wait=$(( wait * 2 )) ;;
+		esac
+		attempt=$(( attempt + 1 ))
+	done
+}
+
+# Starts a group of code blocks running as background jobs:
+#
+#   corg_parallel_start <group>
+#   corg_parallel_job <name> <command...>
+#   corg_parallel_wait
+#
+# The output of each job is buffered and printed prefixed with its name once the
+# group is waited for, which fails if any of the jobs failed.
+function corg_parallel_start() {
+	corg_parallel_group=$1
+	corg_parallel_dir=$(mktemp -d)
+	corg_parallel_pids=()
+	corg_parallel_names=()
+	corg_info "Starting parallel group ${corg_parallel_group}"
+}
+
+function corg_parallel_job() {
+	local name=$1
+	shift
+	local job_file="${corg_parallel_dir}/$(( ${#corg_parallel_pids} + 1 ))"
+
+	{
+		setopt noerrexit
+		( setopt errexit; "$@" ) > "${job_file}.log" 2>&1
+		echo $? > "${job_file}.status"
+	} &
+
+	corg_parallel_pids+=($!)
+	corg_parallel_names+=("$name")
+}
+
+function corg_parallel_wait() {
+	setopt localoptions noerrexit
+	local index name line job_status failed=0
+
+	for (( index = 1; index <= ${#corg_parallel_pids}; index++ )); do
+		wait ${corg_parallel_pids[$index]}
+		name=${corg_parallel_names[$index]}
+		job_status=$(cat "${corg_parallel_dir}/${index}.status" 2> /dev/null || echo 1)
+
+		while IFS= read -r line || [ -n "$line" ]; do
+			echo "[${name}] ${line}"
+		done < "${corg_parallel_dir}/${index}.log"
+
+		if [ "$job_status" -eq 0 ]; then
+			corg_ok "${name} finished"
+		else
+			corg_error "${name} failed (exit ${job_status})"
+			if [ $failed -eq 0 ]; then
+				failed=$job_status
+			fi
+		fi
+	done
+
+	rm -rf "$corg_parallel_dir"
+
+	if [ $failed -ne 0 ]; then
+		corg_error "Parallel group ${corg_parallel_group} failed"
+	fi
+	return $failed
+}
+
+# Checks the os or hostname of the machine, used for sections and code blocks
+# with a when attribute:
+#
+#   corg_when <os|host> <=|!=|=~|!~> <value>
+#
+# The os is the ID of /etc/os-release (e.g. debian or nixos), falling back to
+# the lowercased kernel name.
+function corg_when() {
+	local key=$1 operator=$2 expected=$3 actual
+
+	case $key in
+		os) actual=$( . /etc/os-release 2> /dev/null; echo ${ID:-${(L)$(uname -s)}} ) ;;
+		host) actual=${HOST:-$(hostname)} ;;
+		*) corg_error "Unknown condition ${key}"; return 2 ;;
+	esac
+
+	case $operator in
+		'=') [ "$actual" = "$expected" ] ;;
+		'!=') [ "$actual" != "$expected" ] ;;
+		'=~') [[ $actual =~ $expected ]] ;;
+		'!~') ! [[ $actual =~ $expected ]] ;;
+		*) corg_error "Unknown operator ${operator}"; return 2 ;;
+	esac
+}
+
+# Logs the step before running its function and again once it succeeded, so a
+# runner can tell which step a script failed in. When CORG_SECTIONS holds the
+# slugs of some steps the others are skipped, when CORG_STEP_MODE is set the
+# operator is asked what to do before every step. corg_current_step names the
+# step in the events of CORG_LOG_FILE.
+function corg_step() {
+	if [ -n "${CORG_SECTIONS}" ] && [[ " ${CORG_SECTIONS} " != *" $1 "* ]]; then
+		corg_debug "Skipping $1, not selected"
+		return 0
+	fi
+	corg_current_step=$1
+
+	if [ -n "${CORG_STEP_MODE}" ]; then
+		corg_log "pause" $1
+		corg_prompt_step $1 || return 0
+	fi
+
+	corg_log "step" $1
+	$1
+	local step_status=$?
+	if [ $step_status -eq 0 ]; then
+		corg_log "done" $1
+	fi
+	corg_current_step=""
+	return $step_status
+}
+
+# Asks the operator on the terminal what to do with a step, returns 1 when it's
+# skipped. Edits only change the function for this run, the document stays as
+# it is.
+function corg_prompt_step() {
+	local choice
+	local edit_file
+	while true; do
+		echo "[r]un, [s]kip, [e]dit once, s[h]ell or [a]bort $1?"
+		if ! read -r choice < /dev/tty; then
+			choice="a"
+		fi
+
+		case "$choice" in
+			r|run|"") return 0 ;;
+			s|skip)
+				corg_info "Skipping $1, skipped by the operator"
+				return 1
+				;;
+			e|edit)
+				edit_file=$(mktemp "${TMPDIR:-/tmp}/corg-$1.XXXXXX")
+				functions $1 > $edit_file
+				${VISUAL:-${EDITOR:-vi}} $edit_file < /dev/tty > /dev/tty
+				source $edit_file
+				rm -f $edit_file
+				return 0
+				;;
+			h|shell)
+				corg_info "Starting ${SHELL:-/bin/sh}, exit it to get back to $1"
+				${SHELL:-/bin/sh} -i < /dev/tty > /dev/tty 2>&1
+				;;
+			a|abort)
+				corg_error "Aborted before $1"
+				exit 130
+				;;
+			*) corg_warn "Unknown choice '$choice'" ;;
+		esac
+	done
+}
+
+# Runs code as another user through sudo, `corg_sudo <user> <code>`. The code
+# runs in a zsh of its own with errexit like the script, along with the helpers
+# of the logger. sudo keeps CORG_LOG_FILE and the variables corg exported to the
+# run, which CORG_ENV_VARS names. The password comes from CORG_BECOME_PASSWORD
+# when it's set, e.g. for remote runs which have no terminal for sudo to ask on.
+function corg_sudo() {
+	local code="$(functions -m 'corg_*')
+$(typeset -p log_level_colors)
+reset_color=${(q)reset_color}
+corg_current_step=${(q)corg_current_step}
+$2"
+
+	if [ "$(id -un)" = "$1" ]; then
+		zsh -e -c "$code"
+		return $?
+	fi
+
+	if ! command -v sudo > /dev/null 2>&1; then
+		corg_error "sudo isn't installed, it's needed to run as $1"
+		return 127
+	fi
+
+	local preserve="CORG_LOG_FILE,CORG_LOG_LEVEL${CORG_ENV_VARS:+,${CORG_ENV_VARS}}"
+	if [ -n "${CORG_BECOME_PASSWORD}" ]; then
+		printf '%s\n' "${CORG_BECOME_PASSWORD}" | sudo -S -p '' --preserve-env="$preserve" -u "$1" -- zsh -e -c "$code"
+	else
+		sudo --preserve-env="$preserve" -u "$1" -- zsh -e -c "$code"
+	fi
+}
+
+# Logs that a section or code block was skipped because its condition is false.
+function corg_skip() {
+	corg_info "Skipping $1, condition not met: $2"
+}
+
 # - end logger: